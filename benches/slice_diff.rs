@@ -0,0 +1,80 @@
+//! Compares diffing data read through a window with scanning the same data as a slice.
+//!
+//! Run with `cargo bench --bench slice_diff`.  The data size in MiB and the block size can be given as arguments.
+
+extern crate rdiff;
+
+use rdiff::BlockHashes;
+use std::env;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+/// The number of times each way of diffing is timed, of which the fastest is reported
+const RUNS: usize = 5;
+
+/// Data which doesn't repeat, so that every block is different
+fn random_data(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+    (0..len).map(|_| {
+        state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+        (state >> 56) as u8
+    }).collect()
+}
+
+/// Changes the old data in the ways a file usually changes: a few small edits spread
+/// throughout, some data moved to the end, and something new added to the end.
+fn edit(old: &[u8]) -> Vec<u8> {
+    let step = old.len() / 16;
+    let mut new = Vec::with_capacity(old.len());
+    for (index, piece) in old.chunks(step).enumerate() {
+        match index % 4 {
+            0 => new.extend_from_slice(piece),
+            1 => {
+                new.extend_from_slice(&piece[..piece.len() / 2]);
+                new.extend_from_slice(b"some inserted text");
+                new.extend_from_slice(&piece[piece.len() / 2..]);
+            }
+            2 => new.extend_from_slice(&piece[100..]),
+            _ => new.extend_from_slice(&piece[..piece.len() - 1000])
+        }
+    }
+    new.extend_from_slice(&old[step..2 * step]);
+    new.extend_from_slice(&random_data(step / 4, 2));
+    new
+}
+
+/// Times `run` on what `setup` gives, without timing `setup`, and gives the fastest of several runs
+fn fastest<T, S: FnMut() -> T, F: FnMut(T)>(mut setup: S, mut run: F) -> Duration {
+    (0..RUNS).map(|_| {
+        let input = setup();
+        let start = Instant::now();
+        run(input);
+        start.elapsed()
+    }).min().unwrap()
+}
+
+fn report(name: &str, len: usize, time: Duration) {
+    let rate = len as f64 / (1024.0 * 1024.0) / time.as_secs_f64();
+    println!("{:<24} {:>10.2?} {:>10.1} MiB/s", name, time, rate);
+}
+
+fn main() {
+    // `cargo bench` passes `--bench`, which isn't one of ours
+    let args: Vec<usize> = env::args().skip(1).filter_map(|arg| arg.parse().ok()).collect();
+    let size = args.first().cloned().unwrap_or(64) * 1024 * 1024;
+    let block_size = args.get(1).cloned().unwrap_or(4096);
+    let old = random_data(size, 1);
+    let new = edit(&old);
+    let hashes = BlockHashes::new(Cursor::new(&old), block_size).unwrap();
+    println!("Diffing {} MiB with {} byte blocks, fastest of {} runs", size / (1024 * 1024), block_size, RUNS);
+
+    let read_diff = hashes.diff_against(Cursor::new(&new)).unwrap();
+    assert_eq!(hashes.diff_against_slice(&new).unwrap(), read_diff);
+    report("diff_against", new.len(), fastest(|| (), |_| { hashes.diff_against(Cursor::new(&new)).unwrap(); }));
+    report("diff_against_slice", new.len(), fastest(|| (), |_| { hashes.diff_against_slice(&new).unwrap(); }));
+
+    // The hashes are updated by each run, so they are hashed again before it
+    let old_hashes = || BlockHashes::new(Cursor::new(&old), block_size).unwrap();
+    report("diff_and_update", new.len(), fastest(old_hashes, |mut hashes| { hashes.diff_and_update(Cursor::new(&new)).unwrap(); }));
+    report("diff_and_update_slice", new.len(), fastest(old_hashes, |mut hashes| { hashes.diff_and_update_slice(&new).unwrap(); }));
+}
@@ -4,26 +4,25 @@ extern crate rdiff;
 use notify::{RecommendedWatcher, Watcher, op};
 use std::sync::mpsc::channel;
 use std::fs;
-use std::io;
 use rdiff::BlockHashes;
 
 macro_rules! try_io {
     ($e: expr) => ({
         match $e {
             Ok(v) => v,
-            Err(e) => return Err(notify::Error::Io(e))
+            Err(e) => return Err(notify::Error::Io(e.into()))
         }
     });
 }
 
-fn create_hashes(file: &str) -> io::Result<rdiff::BlockHashes> {
-    let file = try!(fs::File::open(file));
+fn create_hashes(file: &str) -> rdiff::Result<rdiff::BlockHashes> {
+    let file = fs::File::open(file)?;
     BlockHashes::new(file, 8)
 }
 
-fn update_hashes(hashes: &mut BlockHashes, file: &str) -> io::Result<()> {
-    let file = try!(fs::File::open(file));
-    let diffs = try!(hashes.diff_and_update(file));
+fn update_hashes(hashes: &mut BlockHashes, file: &str) -> rdiff::Result<()> {
+    let file = fs::File::open(file)?;
+    let diffs = hashes.diff_and_update(file)?;
     if diffs.inserts().len() != 0 || diffs.deletes().len() != 0 {
         println!("{:?}", diffs);
     }
@@ -38,20 +37,18 @@ fn watch(file_name: &str) -> notify::Result<()> {
 
   // Automatically select the best implementation for your platform.
   // You can also access each implementation directly e.g. INotifyWatcher.
-  let mut watcher: RecommendedWatcher = try!(Watcher::new(tx));
+  let mut watcher: RecommendedWatcher = Watcher::new(tx)?;
 
   // Add a path to be watched. All files and directories at that path and
   // below will be monitored for changes.
-  try!(watcher.watch(file_name));
+  watcher.watch(file_name)?;
 
   // This is a simple loop, but you may want to use more complex logic here,
   // for example to handle I/O.
   loop {
       match rx.recv() {
-        Ok(notify::Event{ path: Some(_),op:Ok(operation) }) => {
-            if operation == op::WRITE {
-                try_io!(update_hashes(&mut hashes, file_name));
-            }
+        Ok(notify::Event{ path: Some(_),op:Ok(operation) }) if operation == op::WRITE => {
+            try_io!(update_hashes(&mut hashes, file_name));
         },
         Err(e) => println!("watch error {}", e),
         _ => ()
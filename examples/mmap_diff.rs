@@ -0,0 +1,46 @@
+extern crate memmap2;
+extern crate rdiff;
+
+use memmap2::Mmap;
+use std::env;
+use std::fs;
+use std::time::Instant;
+use rdiff::BlockHashes;
+
+fn map(file_name: &str) -> rdiff::Result<Mmap> {
+    let file = fs::File::open(file_name)?;
+    // The files must not be changed while they are mapped
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+/// Diffs two files, first reading the new one through a window and then scanning a memory map of it directly
+fn compare(old_name: &str, new_name: &str, block_size: usize) -> rdiff::Result<()> {
+    let mut read_hashes = BlockHashes::new(fs::File::open(old_name)?, block_size)?;
+    let mut slice_hashes = BlockHashes::new(fs::File::open(old_name)?, block_size)?;
+
+    let start = Instant::now();
+    let read_diff = read_hashes.diff_and_update(fs::File::open(new_name)?)?;
+    println!("Reading the file took {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let new_data = map(new_name)?;
+    let slice_diff = slice_hashes.diff_and_update_slice(&new_data)?;
+    println!("Scanning the memory map took {:?}", start.elapsed());
+
+    assert_eq!(read_diff, slice_diff);
+    assert_eq!(read_hashes, slice_hashes);
+    println!("{} inserts and {} deletes", slice_diff.inserts().len(), slice_diff.deletes().len());
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        println!("usage: mmap_diff <old file> <new file> [block size]");
+        return;
+    }
+    let block_size = args.get(3).map_or(4096, |size| size.parse().expect("the block size must be a number"));
+    if let Err(e) = compare(&args[1], &args[2], block_size) {
+        println!("Error: {}", e);
+    }
+}
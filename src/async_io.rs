@@ -0,0 +1,365 @@
+//! Versions of the signature, diff and apply functions which read and write with tokio's
+//! `AsyncRead` and `AsyncWrite`, enabled by the `async` feature.
+use super::{Error, Result, BlockHashes, Diff, Op, PayloadKind, WeakHash, StrongHashAlgorithm, CONTAINER_MAGIC, CONTAINER_VERSION};
+use crate::container;
+use crate::hashing::{DiffScanner, StrongHasher};
+use std::cmp::max;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::marker::PhantomData;
+use byteorder::{NetworkEndian, ByteOrder};
+use crc32fast::Hasher;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The amount of new data read at once while finding a diff
+const ASYNC_BUFFER_SIZE: usize = 64 * 1024;
+
+impl BlockHashes {
+    /// Create a new BlockHash based on the data read asynchronously from `data_source`, in the same way as
+    /// [`new()`](#method.new).
+    ///
+    /// This method returns an error when there is a problem reading from `data_source`, or when `block_size` is 0.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::BlockHashes;
+    /// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+    /// let mut hashes = BlockHashes::new_async(&b"It was the best of times"[..], 6).await.unwrap();
+    /// let diff = hashes.diff_and_update_async(&b"It was not the best of things"[..]).await.unwrap();
+    /// assert_eq!("It was not the best of things",
+    ///             diff.apply_to_string("It was the best of times").unwrap());
+    /// # });
+    /// ```
+    pub async fn new_async<R: AsyncRead + Unpin>(data_source: R, block_size: usize) -> Result<BlockHashes> {
+        BlockHashes::with_hashes_async(data_source, block_size, StrongHashAlgorithm::Md5, 16).await
+    }
+}
+
+impl<H: WeakHash> BlockHashes<H> {
+    /// Create a new BlockHash based on the data read asynchronously from `data_source`, choosing both the weak and
+    /// the strong hash in the same way as [`with_hashes()`](#method.with_hashes).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::{BlockHashes, Buzhash, StrongHashAlgorithm};
+    /// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+    /// let data = &b"It was the best of times"[..];
+    /// let hashes = BlockHashes::<Buzhash>::with_hashes_async(data, 6, StrongHashAlgorithm::Blake3, 16).await.unwrap();
+    /// let diff = hashes.diff_against_async(&b"It was not the best of things"[..]).await.unwrap();
+    /// assert_eq!("It was not the best of things", diff.apply_to_string("It was the best of times").unwrap());
+    /// # });
+    /// ```
+    pub async fn with_hashes_async<R: AsyncRead + Unpin>(mut data_source: R, block_size: usize, strong_hash: StrongHashAlgorithm, strong_len: usize) -> Result<BlockHashes<H>> {
+        if block_size == 0 {
+            return Err(Error::InvalidParameter("the block size must be at least 1".to_string()));
+        }
+        if strong_len == 0 || strong_len > strong_hash.output_len() {
+            return Err(Error::InvalidParameter(format!("strong hashes must be between 1 and {} bytes", strong_hash.output_len())));
+        }
+        let mut hashes = BlockHashes {
+            hashes: HashMap::new(),
+            block_size,
+            file_size: 0,
+            weak_hash: PhantomData,
+            strong_hash,
+            strong_len
+        };
+        let mut strong_hasher = StrongHasher::new(hashes.strong_hash, hashes.strong_len);
+        let mut block = vec![0; block_size];
+        let mut read_size = read_block(&mut data_source, &mut block).await?;
+        while read_size > 0 {
+            hashes.add_block(&mut strong_hasher, &block[..read_size]);
+            read_size = read_block(&mut data_source, &mut block).await?;
+        }
+        Ok(hashes)
+    }
+
+    /// Compare the data read asynchronously from `new_data` with these hashes, and then update the hashes to match it,
+    /// in the same way as [`diff_and_update()`](#method.diff_and_update).
+    pub async fn diff_and_update_async<R: AsyncRead + Unpin>(&mut self, new_data: R) -> Result<Diff> {
+        let (diff, new_hashes) = self.find_diff_async(new_data, true).await?;
+        *self = new_hashes;
+        Ok(diff)
+    }
+
+    /// Compare the data read asynchronously from `new_data` with these hashes, without updating them,
+    /// in the same way as [`diff_against()`](#method.diff_against).
+    pub async fn diff_against_async<R: AsyncRead + Unpin>(&self, new_data: R) -> Result<Diff> {
+        let (diff, _) = self.find_diff_async(new_data, false).await?;
+        Ok(diff)
+    }
+
+    /// Finds the difference between the data in `new_data` and these hashes, scanning the data a buffer at a time as it
+    /// is read.  Also gives the hashes of `new_data`, which are only filled in if `record_hashes` is set.
+    async fn find_diff_async<R: AsyncRead + Unpin>(&self, mut new_data: R, record_hashes: bool) -> Result<(Diff, BlockHashes<H>)> {
+        // The scanner needs two whole blocks to move forward
+        let buffer_size = max(ASYNC_BUFFER_SIZE, 2 * self.block_size);
+        let mut buffer = Vec::with_capacity(buffer_size);
+        let mut scanner = DiffScanner::new(self, record_hashes);
+        loop {
+            let start = buffer.len();
+            buffer.resize(buffer_size, 0);
+            let read_size = read_block(&mut new_data, &mut buffer[start..]).await?;
+            buffer.truncate(start + read_size);
+            let finished = buffer.len() < buffer_size;
+            let scanned = scanner.scan(&buffer, finished);
+            buffer.drain(..scanned);
+            if finished {
+                break;
+            }
+        }
+        Ok(scanner.finish())
+    }
+}
+
+impl Diff {
+    /// Apply the operations in this diff to the data read asynchronously from `base`, writing the result to `out`,
+    /// in the same way as [`apply_stream()`](#method.apply_stream).
+    ///
+    /// Gives an error if the operations refer to positions past the end of `base`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::BlockHashes;
+    /// use std::io::Cursor;
+    /// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+    /// let hashes = BlockHashes::new(Cursor::new("It was the best of times"), 6).unwrap();
+    /// let diff = hashes.diff_against(Cursor::new("It was not the best of things")).unwrap();
+    /// let mut result = Vec::new();
+    /// diff.apply_async(&b"It was the best of times"[..], &mut result).await.unwrap();
+    /// assert_eq!(result, b"It was not the best of things");
+    /// # });
+    /// ```
+    pub async fn apply_async<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(&self, mut base: R, mut out: W) -> Result<()> {
+        self.check_order()?;
+        let mut position = 0;
+        for op in self.to_ops() {
+            match op {
+                Op::Retain(len) => {
+                    let copied = io::copy(&mut (&mut base).take(len as u64), &mut out).await?;
+                    if copied < len as u64 {
+                        return Err(Error::PositionOutOfRange(position + copied as usize));
+                    }
+                    position += len;
+                }
+                Op::Insert(data) => out.write_all(&data).await?,
+                Op::Delete(len) => {
+                    let skipped = io::copy(&mut (&mut base).take(len as u64), &mut io::sink()).await?;
+                    if skipped < len as u64 {
+                        return Err(Error::PositionOutOfRange(position + skipped as usize));
+                    }
+                    position += len;
+                }
+            }
+        }
+        io::copy(&mut base, &mut out).await?;
+        out.flush().await?;
+        Ok(())
+    }
+
+    /// Compress this diff and write it asynchronously to `writer`, in the same format as [`compress_to()`](#method.compress_to).
+    /// Each part of the diff is written as it is compressed, so the whole diff is never held in memory twice.
+    pub async fn compress_to_async<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        let mut writer = AsyncChecksumWriter {
+            writer,
+            hasher: Hasher::new()
+        };
+        writer.write(&container::header(PayloadKind::Diff, (None, None))).await?;
+        writer.write_u64(self.inserts.len() as u64).await?;
+        for insert in self.inserts.iter() {
+            writer.write_u64(insert.position as u64).await?;
+            writer.write_u64(insert.data.len() as u64).await?;
+            writer.write(&insert.data).await?;
+        }
+        writer.write_u64(self.deletes.len() as u64).await?;
+        for delete in self.deletes.iter() {
+            writer.write_u64(delete.position as u64).await?;
+            writer.write_u64(delete.len as u64).await?;
+        }
+        writer.finish().await?;
+        Ok(())
+    }
+
+    /// Expand a diff from compressed data read asynchronously from `reader`, in the same way as
+    /// [`expand_from()`](#method.expand_from).  Nothing past the end of the diff is read.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::{BlockHashes, Diff};
+    /// use std::io::Cursor;
+    /// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+    /// let hashes = BlockHashes::new(Cursor::new("It was the best of times"), 6).unwrap();
+    /// let diff = hashes.diff_against(Cursor::new("It was not the best of things")).unwrap();
+    /// let mut compressed = Vec::new();
+    /// diff.compress_to_async(&mut compressed).await.unwrap();
+    /// assert_eq!(Diff::expand_from_async(&mut &compressed[..]).await.unwrap(), diff);
+    /// # });
+    /// ```
+    pub async fn expand_from_async<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Diff> {
+        // The compressed diff is read into memory by following its layout, and then expanded from there
+        let mut compressed = Vec::new();
+        let header = read_more(reader, &mut compressed, 8).await?;
+        if header[..4] != CONTAINER_MAGIC {
+            return Err(Error::Corrupt("not an rdiff file".to_string()));
+        }
+        if header[4] != CONTAINER_VERSION {
+            return Err(Error::UnsupportedVersion(header[4]));
+        }
+        let insert_count = read_field(reader, &mut compressed).await?;
+        for _ in 0..insert_count {
+            read_field(reader, &mut compressed).await?;
+            let len = read_field(reader, &mut compressed).await?;
+            read_more(reader, &mut compressed, len).await?;
+        }
+        let delete_count = read_field(reader, &mut compressed).await?;
+        for _ in 0..delete_count {
+            read_field(reader, &mut compressed).await?;
+            read_field(reader, &mut compressed).await?;
+        }
+        // The checksum
+        read_more(reader, &mut compressed, 4).await?;
+        Diff::expand_from(&mut Cursor::new(compressed))
+    }
+}
+
+/// Writes a container asynchronously, keeping the checksum of everything written in the same way as `ChecksumWriter`
+struct AsyncChecksumWriter<'a, W> {
+    writer: &'a mut W,
+    hasher: Hasher
+}
+
+impl<'a, W: AsyncWrite + Unpin> AsyncChecksumWriter<'a, W> {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.hasher.update(data);
+        self.writer.write_all(data).await
+    }
+
+    async fn write_u64(&mut self, value: u64) -> io::Result<()> {
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u64(&mut long_buf, value);
+        self.write(&long_buf).await
+    }
+
+    /// Ends the container by writing the checksum of everything written into it
+    async fn finish(self) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        NetworkEndian::write_u32(&mut int_buf, self.hasher.finalize());
+        self.writer.write_all(&int_buf).await?;
+        self.writer.flush().await
+    }
+}
+
+/// Reads from `reader` until `buffer` is full or there is no more data, like `read_block()`.
+///
+/// Returns the number of bytes read, which is only less than the size of `buffer` at the end of the data
+async fn read_block<R: AsyncRead + Unpin>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read_size = 0;
+    while read_size < buffer.len() {
+        match reader.read(&mut buffer[read_size..]).await {
+            Ok(0) => break,
+            Ok(size) => read_size += size,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(read_size)
+}
+
+/// Reads exactly `len` more bytes from `reader` onto the end of `buffer`, and returns them.
+/// The buffer only grows as data arrives, so a corrupted length can't cause a huge allocation.
+async fn read_more<'a, R: AsyncRead + Unpin>(reader: &mut R, buffer: &'a mut Vec<u8>, len: u64) -> Result<&'a [u8]> {
+    let start = buffer.len();
+    if (&mut *reader).take(len).read_to_end(buffer).await? as u64 != len {
+        return Err(Error::Truncated);
+    }
+    Ok(&buffer[start..])
+}
+
+/// Reads a 64 bit field of a compressed diff onto the end of `buffer`, and returns its value
+async fn read_field<R: AsyncRead + Unpin>(reader: &mut R, buffer: &mut Vec<u8>) -> Result<u64> {
+    Ok(NetworkEndian::read_u64(read_more(reader, buffer, 8).await?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{BlockHashes, Diff, Error};
+    use std::future::Future;
+    use std::io::Cursor;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(future)
+    }
+
+    fn assert_send<F: Future + Send>(future: F) -> F {
+        future
+    }
+
+    #[test]
+    fn async_diffs() {
+        let old: Vec<u8> = (0..200_000u64).map(|i| (i * i / 7) as u8).collect();
+        let mut new = old[..1000].to_vec();
+        new.extend_from_slice(b"something new");
+        new.extend_from_slice(&old[1000..90_000]);
+        new.extend_from_slice(&old[100_000..]);
+        for &block_size in [7, 4096, 100_000].iter() {
+            let mut hashes = block_on(assert_send(BlockHashes::new_async(&old[..], block_size))).unwrap();
+            assert_eq!(hashes, BlockHashes::new(Cursor::new(&old), block_size).unwrap());
+            let diff = block_on(assert_send(hashes.diff_against_async(&new[..]))).unwrap();
+            assert_eq!(diff, hashes.diff_against(Cursor::new(&new)).unwrap());
+            assert_eq!(block_on(hashes.diff_and_update_async(&new[..])).unwrap(), diff);
+            assert_eq!(hashes, BlockHashes::new(Cursor::new(&new), block_size).unwrap());
+
+            let mut result = Vec::new();
+            block_on(assert_send(diff.apply_async(&old[..], &mut result))).unwrap();
+            assert_eq!(result, new);
+        }
+        match block_on(BlockHashes::new_async(&old[..], 0)) {
+            Err(Error::InvalidParameter(_)) => (),
+            result => panic!("expected an invalid parameter, got {:?}", result)
+        }
+        let hashes = BlockHashes::new(Cursor::new(&old), 16).unwrap();
+        let diff = hashes.diff_against(Cursor::new(&new)).unwrap();
+        match block_on(diff.apply_async(&old[..50_000], &mut Vec::new())) {
+            Err(Error::PositionOutOfRange(_)) => (),
+            result => panic!("expected a position out of range, got {:?}", result)
+        }
+    }
+
+    #[test]
+    fn async_compression() {
+        let mut diff = Diff::new();
+        diff.add_insert(2, b"ab".to_vec());
+        diff.add_insert(10, b"cde".to_vec());
+        diff.add_delete(3, 1);
+        let mut compressed = Vec::new();
+        block_on(diff.compress_to_async(&mut compressed)).unwrap();
+        let mut sync_compressed = Vec::new();
+        diff.compress_to(&mut sync_compressed).unwrap();
+        assert_eq!(compressed, sync_compressed);
+
+        // Anything after the diff is left unread
+        compressed.extend_from_slice(b"trailing");
+        let mut reader = &compressed[..];
+        assert_eq!(block_on(assert_send(Diff::expand_from_async(&mut reader))).unwrap(), diff);
+        assert_eq!(reader, b"trailing");
+
+        // The layout used before containers is only read by `expand_from_legacy()`
+        let narrow = vec![0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, b'a', b'b', 0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0, 1];
+        match block_on(Diff::expand_from_async(&mut &narrow[..])) {
+            Err(Error::Corrupt(ref message)) if message == "not an rdiff file" => (),
+            result => panic!("expected data which isn't an rdiff file, got {:?}", result)
+        }
+
+        match block_on(Diff::expand_from_async(&mut &sync_compressed[..sync_compressed.len() - 2])) {
+            Err(Error::Truncated) => (),
+            result => panic!("expected truncated data, got {:?}", result)
+        }
+        let mut corrupted = sync_compressed.clone();
+        corrupted[20] ^= 1;
+        assert!(block_on(Diff::expand_from_async(&mut &corrupted[..])).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+use super::{Error, Result, Diff};
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+impl Diff {
+    /// Apply the operations in this sequence to the file at `path`, without ever leaving a partially
+    /// written file behind.
+    ///
+    /// The new version is written to a temporary file in the same directory and synced to disk.  It is then
+    /// read back and compared with what was written, by its length and MD5 digest, and checked to have the
+    /// length the diff should produce, before it is renamed over the original file.  If anything goes wrong
+    /// (including a crash part way through) the original file is left untouched.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rdiff::BlockHashes;
+    /// use std::fs::File;
+    /// let hashes = BlockHashes::new(File::open("notes.txt").unwrap(), 8).unwrap();
+    /// let diff = hashes.diff_against(File::open("notes-edited.txt").unwrap()).unwrap();
+    /// diff.apply_atomic("notes.txt").unwrap();
+    /// ```
+    pub fn apply_atomic<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.replace_file(path.as_ref(), None)
+    }
+
+    /// Apply the operations in this sequence to the file at `path` in the same way as
+    /// [`apply_atomic()`](#method.apply_atomic), keeping the previous version of the file at `backup`.
+    ///
+    /// Any existing file at `backup` is replaced.
+    pub fn apply_atomic_with_backup<P: AsRef<Path>, Q: AsRef<Path>>(&self, path: P, backup: Q) -> Result<()> {
+        self.replace_file(path.as_ref(), Some(backup.as_ref()))
+    }
+
+    /// Apply the operations in this sequence to `base`, writing the result to the file at `path` in the same way as
+    /// [`apply_atomic()`](#method.apply_atomic), so that `path` is only replaced once the whole result has been written.
+    ///
+    /// `base` may be read from the file at `path` itself, as long as it was opened before calling this.
+    /// If there is already a file at `path`, its permissions are kept.  The temporary file is read back and
+    /// checked against what was written in the same way, and against the length the diff should produce
+    /// from the data read from `base`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rdiff::BlockHashes;
+    /// use std::fs::File;
+    /// let hashes = BlockHashes::new(File::open("notes.txt").unwrap(), 8).unwrap();
+    /// let diff = hashes.diff_against(File::open("notes-edited.txt").unwrap()).unwrap();
+    /// diff.apply_stream_atomic(File::open("notes.txt").unwrap(), "notes-patched.txt").unwrap();
+    /// ```
+    pub fn apply_stream_atomic<R: Read, P: AsRef<Path>>(&self, base: R, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let permissions = match fs::metadata(path) {
+            Ok(metadata) => Some(metadata.permissions()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into())
+        };
+        write_atomically(path, permissions, None, |temp| {
+            let mut base = CountingReader {
+                reader: base,
+                count: 0
+            };
+            let written = self.write_temp(&mut base, temp)?;
+            self.check_len(&written, base.count)?;
+            Ok(written)
+        })
+    }
+
+    fn replace_file(&self, path: &Path, backup: Option<&Path>) -> Result<()> {
+        let base = File::open(path)?;
+        let metadata = base.metadata()?;
+        write_atomically(path, Some(metadata.permissions()), backup, |temp| {
+            let written = self.write_temp(BufReader::new(base), temp)?;
+            self.check_len(&written, metadata.len())?;
+            Ok(written)
+        })
+    }
+
+    /// Writes the new version of `base` into `temp`, keeping track of exactly what was written
+    fn write_temp<R: Read>(&self, base: R, temp: &File) -> Result<Written> {
+        let mut out = DigestWriter {
+            writer: BufWriter::new(temp),
+            digest: Md5::new(),
+            len: 0
+        };
+        self.apply_stream(base, &mut out)?;
+        out.writer.flush()?;
+        Ok(out.finish())
+    }
+
+    /// Checks that the new version of a file of `base_len` bytes has as many bytes as this diff should produce
+    fn check_len(&self, written: &Written, base_len: u64) -> Result<()> {
+        if written.len != self.new_len(base_len)? {
+            return Err(Error::Corrupt("patched file does not have the expected length".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Calculates the length of the file this diff produces from a file of `base_len` bytes
+    fn new_len(&self, base_len: u64) -> Result<u64> {
+        let inserted: u64 = self.inserts.iter().map(|insert| insert.data.len() as u64).sum();
+        let deleted: u64 = self.deletes.iter().map(|delete| delete.len as u64).sum();
+        (base_len + inserted).checked_sub(deleted)
+            .ok_or(Error::PositionOutOfRange(base_len as usize))
+    }
+}
+
+/// The length and MD5 digest of the data written to a file
+#[derive(PartialEq)]
+struct Written {
+    len: u64,
+    digest: [u8; 16]
+}
+
+/// Passes data on to a writer, keeping track of its length and digest
+struct DigestWriter<W: Write> {
+    writer: W,
+    digest: Md5,
+    len: u64
+}
+
+impl<W: Write> DigestWriter<W> {
+    fn finish(mut self) -> Written {
+        let mut digest = [0; 16];
+        self.digest.result(&mut digest);
+        Written {
+            len: self.len,
+            digest
+        }
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.digest.input(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Counts the bytes read through it
+struct CountingReader<R: Read> {
+    reader: R,
+    count: u64
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// Reads back the file at `path` and checks that it holds exactly what was written
+fn verify(path: &Path, written: &Written) -> Result<()> {
+    let mut out = DigestWriter {
+        writer: io::sink(),
+        digest: Md5::new(),
+        len: 0
+    };
+    io::copy(&mut File::open(path)?, &mut out)?;
+    if out.finish() != *written {
+        return Err(Error::Corrupt("patched file does not contain what was written".to_string()));
+    }
+    Ok(())
+}
+
+/// Distinguishes the temporary files of calls made at the same time by this process
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Replaces the file at `path` with one written by `write`.  The new file is written to a temporary file in
+/// the same directory, synced to disk, read back to check that it holds what `write` says it wrote, given
+/// `permissions` and then renamed over `path`, after keeping the old file at `backup` if there is one.
+/// The temporary file is removed if anything fails.
+fn write_atomically<F: FnOnce(&File) -> Result<Written>>(path: &Path, permissions: Option<Permissions>, backup: Option<&Path>, write: F) -> Result<()> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from(".")
+    };
+    let file_name = path.file_name().ok_or_else(|| Error::InvalidParameter("path does not name a file".to_string()))?;
+    let mut temp_name = ".".to_string();
+    temp_name.push_str(&file_name.to_string_lossy());
+    temp_name.push_str(&format!(".rdiff-{}-{}.tmp", process::id(), TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let temp_path = directory.join(temp_name);
+
+    let temp = OpenOptions::new().write(true).create_new(true).open(&temp_path)?;
+    let result = write(&temp).and_then(|written| {
+        temp.sync_all()?;
+        verify(&temp_path, &written)?;
+        if let Some(permissions) = permissions {
+            fs::set_permissions(&temp_path, permissions)?;
+        }
+        if let Some(backup) = backup {
+            keep_backup(path, backup)?;
+        }
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+        return result;
+    }
+    sync_directory(&directory)?;
+    Ok(())
+}
+
+/// Keeps the current version of the file at `path` as `backup`.  A hard link is used where possible,
+/// since the original is about to be replaced anyway.
+fn keep_backup(path: &Path, backup: &Path) -> io::Result<()> {
+    if let Err(e) = fs::remove_file(backup) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    if fs::hard_link(path, backup).is_err() {
+        fs::copy(path, backup)?;
+        File::open(backup)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Makes sure the rename of the new file has reached the disk
+#[cfg(unix)]
+fn sync_directory(directory: &Path) -> io::Result<()> {
+    File::open(directory)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_directory(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Diff;
+    use super::{verify, DigestWriter, Written};
+    use crypto::md5::Md5;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{self, Cursor, Write};
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rdiff-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn applying_atomically() {
+        let dir = test_dir("atomic");
+        let path = dir.join("file.txt");
+        let backup = dir.join("file.txt.orig");
+        File::create(&path).unwrap().write_all(b"The quick brown fox").unwrap();
+
+        let mut diff = Diff::new();
+        diff.add_insert(10, b"red ".to_vec());
+        diff.add_delete(14, 6);
+        diff.apply_atomic_with_backup(&path, &backup).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"The quick red fox");
+        assert_eq!(fs::read(&backup).unwrap(), b"The quick brown fox");
+
+        // A diff that doesn't fit the file leaves it untouched
+        let mut diff = Diff::new();
+        diff.add_delete(10, 20);
+        assert!(diff.apply_atomic(&path).is_err());
+        let mut diff = Diff::new();
+        diff.add_insert(30, b"!".to_vec());
+        assert!(diff.apply_atomic(&path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"The quick red fox");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn applying_streams_atomically() {
+        let dir = test_dir("atomic-stream");
+        let path = dir.join("file.txt");
+        File::create(&path).unwrap().write_all(b"The quick brown fox").unwrap();
+        let mut diff = Diff::new();
+        diff.add_insert(10, b"red ".to_vec());
+        diff.add_delete(14, 6);
+
+        // The base can be read from the file being replaced
+        diff.apply_stream_atomic(File::open(&path).unwrap(), &path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"The quick red fox");
+
+        // Calls made at the same time for the same file each get their own temporary file
+        let diff = Arc::new(diff);
+        let threads: Vec<_> = (0..8).map(|_| {
+            let (diff, path) = (diff.clone(), path.clone());
+            thread::spawn(move || diff.apply_stream_atomic(Cursor::new("The quick brown fox"), &path))
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+        assert_eq!(fs::read(&path).unwrap(), b"The quick red fox");
+
+        // A failed patch leaves nothing behind
+        let mut diff = Diff::new();
+        diff.add_delete(10, 20);
+        assert!(diff.apply_stream_atomic(Cursor::new("short"), dir.join("other.txt")).is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn written(data: &[u8]) -> Written {
+        let mut out = DigestWriter {
+            writer: io::sink(),
+            digest: Md5::new(),
+            len: 0
+        };
+        out.write_all(data).unwrap();
+        out.finish()
+    }
+
+    #[test]
+    fn verifying_written_files() {
+        let dir = test_dir("atomic-verify");
+        let path = dir.join("file.txt");
+        fs::write(&path, "The quick brown fox").unwrap();
+        assert!(verify(&path, &written(b"The quick brown fox")).is_ok());
+        // Data with the right length, but different contents
+        assert!(verify(&path, &written(b"The quick brown cat")).is_err());
+        assert!(verify(&path, &written(b"The quick brown fox!")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
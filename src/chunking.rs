@@ -0,0 +1,404 @@
+use super::{Error, Result, ChunkHashes, ChunkSizes, Chunker, Delta, StrongHash, StrongHashAlgorithm, ChecksumReader, ChecksumWriter, PayloadKind};
+use crate::hashing::read_block;
+use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::cmp::{min, max};
+use std::mem;
+use byteorder::{NetworkEndian, ByteOrder};
+
+/// Generates the random value the gear hash adds for each byte, using splitmix64 from a fixed seed.
+/// Chunk boundaries depend on this table, so it must never change.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x6765_6172_6364_6321;
+    let mut index = 0;
+    while index < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[index] = z ^ (z >> 31);
+        index += 1;
+    }
+    table
+}
+
+/// The value the gear hash adds for each byte
+const GEAR_TABLE: [u64; 256] = gear_table();
+
+impl ChunkSizes {
+    /// Creates a new set of chunk sizes, in bytes.
+    ///
+    /// Gives an error unless `0 < min <= avg <= max`.
+    pub fn new(min: usize, avg: usize, max: usize) -> Result<ChunkSizes> {
+        if min == 0 || min > avg || avg > max {
+            return Err(Error::InvalidParameter("chunk sizes must have 0 < min <= avg <= max".to_string()));
+        }
+        Ok(ChunkSizes {
+            min,
+            avg,
+            max
+        })
+    }
+
+    /// Gets the smallest size a chunk can be, other than at the end of the file
+    #[inline]
+    pub fn get_min(&self) -> usize {
+        self.min
+    }
+
+    /// Gets the size most chunks will be close to
+    #[inline]
+    pub fn get_avg(&self) -> usize {
+        self.avg
+    }
+
+    /// Gets the largest size a chunk can be
+    #[inline]
+    pub fn get_max(&self) -> usize {
+        self.max
+    }
+}
+
+impl Default for ChunkSizes {
+    /// Chunks of between 2KiB and 64KiB, averaging 8KiB
+    fn default() -> ChunkSizes {
+        ChunkSizes {
+            min: 2 * 1024,
+            avg: 8 * 1024,
+            max: 64 * 1024
+        }
+    }
+}
+
+/// Makes a mask with the highest `bits` bits set.  The gear hash is shifted left as each byte is added,
+/// so the highest bits depend on the most data.
+fn high_bits(bits: u32) -> u64 {
+    !(u64::MAX >> bits)
+}
+
+impl<R: Read> Chunker<R> {
+    fn new(reader: R, sizes: ChunkSizes) -> Chunker<R> {
+        // A boundary is found on average once every 2^bits bytes
+        let bits = usize::BITS - 1 - sizes.avg.leading_zeros();
+        Chunker {
+            reader,
+            buffer: Vec::with_capacity(sizes.max),
+            sizes,
+            small_mask: high_bits(min(bits + 1, 63)),
+            large_mask: high_bits(max(bits, 2) - 1),
+            finished: false
+        }
+    }
+
+    /// Reads the next chunk, or `None` once all of the data has been read
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        if !self.finished && self.buffer.len() < self.sizes.max {
+            let start = self.buffer.len();
+            self.buffer.resize(self.sizes.max, 0);
+            let read = read_block(&mut self.reader, &mut self.buffer[start..])?;
+            self.buffer.truncate(start + read);
+            self.finished = self.buffer.len() < self.sizes.max;
+        }
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        let rest = self.buffer.split_off(self.find_boundary());
+        Ok(Some(mem::replace(&mut self.buffer, rest)))
+    }
+
+    /// Finds the length of the chunk at the start of the buffer.  The buffer always holds at least the
+    /// maximum chunk size, unless the end of the data has been reached.
+    fn find_boundary(&self) -> usize {
+        let data = &self.buffer;
+        if data.len() <= self.sizes.min {
+            return data.len();
+        }
+        let end = min(data.len(), self.sizes.max);
+        let normal = min(self.sizes.avg, end);
+        let mut hash: u64 = 0;
+        // Boundaries are harder to find before the average size, and easier after it,
+        // which keeps the chunk sizes close to the average
+        for (index, &byte) in data.iter().enumerate().take(end).skip(self.sizes.min) {
+            hash = (hash << 1).wrapping_add(GEAR_TABLE[byte as usize]);
+            let mask = if index < normal { self.small_mask } else { self.large_mask };
+            if hash & mask == 0 {
+                return index + 1;
+            }
+        }
+        end
+    }
+}
+
+/// Calculates the strong hash of a chunk, truncated to `strong_len` bytes
+fn hash_chunk(hasher: &mut dyn StrongHash, chunk: &[u8], strong_len: usize) -> Vec<u8> {
+    let mut result = vec![0; hasher.output_len()];
+    hasher.update(chunk);
+    hasher.finish_reset(&mut result);
+    result.truncate(strong_len);
+    result
+}
+
+impl ChunkHashes {
+    /// Split the data in `data_source` into chunks, and hash each one with BLAKE3.
+    ///
+    /// This method returns an error when there is a problem reading from `data_source`.
+    pub fn new<R: Read>(data_source: R, sizes: ChunkSizes) -> Result<ChunkHashes> {
+        ChunkHashes::with_strong_hash(data_source, sizes, StrongHashAlgorithm::Blake3, 32)
+    }
+
+    /// Split the data in `data_source` into chunks, and hash each one with `strong_hash`, truncated to `strong_len` bytes.
+    ///
+    /// Chunks are only ever matched by their strong hash, so it shouldn't be truncated much.
+    /// Gives an error if `strong_len` is 0 or longer than the algorithm's digest.
+    pub fn with_strong_hash<R: Read>(data_source: R, sizes: ChunkSizes, strong_hash: StrongHashAlgorithm, strong_len: usize) -> Result<ChunkHashes> {
+        if strong_len == 0 || strong_len > strong_hash.output_len() {
+            return Err(Error::InvalidParameter(format!("strong hashes must be between 1 and {} bytes", strong_hash.output_len())));
+        }
+        let mut chunker = Chunker::new(data_source, sizes);
+        let mut hasher = strong_hash.hasher();
+        let mut chunks = Vec::new();
+        while let Some(chunk) = chunker.next_chunk()? {
+            chunks.push((chunk.len(), hash_chunk(&mut *hasher, &chunk, strong_len)));
+        }
+        Ok(ChunkHashes::from_chunks(chunks, sizes, strong_hash, strong_len))
+    }
+
+    /// Builds the hashes from the length and strong hash of every chunk
+    fn from_chunks(chunks: Vec<(usize, Vec<u8>)>, sizes: ChunkSizes, strong_hash: StrongHashAlgorithm, strong_len: usize) -> ChunkHashes {
+        let mut positions = HashMap::with_capacity(chunks.len());
+        let mut file_size = 0;
+        for &(len, ref hash) in chunks.iter() {
+            positions.entry(hash.clone()).or_insert((file_size, len));
+            file_size += len;
+        }
+        ChunkHashes {
+            chunks,
+            positions,
+            sizes,
+            file_size,
+            strong_hash,
+            strong_len
+        }
+    }
+
+    /// Gets the sizes the file was split into chunks with
+    #[inline]
+    pub fn get_sizes(&self) -> ChunkSizes {
+        self.sizes
+    }
+
+    /// Gets the number of chunks the file was split into
+    #[inline]
+    pub fn get_chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Gets the length in bytes of the file these hashes were calculated over
+    #[inline]
+    pub fn get_file_size(&self) -> usize {
+        self.file_size
+    }
+
+    /// Compare the data in `new_data` with these hashes, and produce a `Delta` which rebuilds `new_data`
+    /// from copies of the chunks of the old version of the file, plus the data in any new chunks.
+    pub fn delta_against<R: Read>(&self, new_data: R) -> Result<Delta> {
+        self.find_delta(new_data, None)
+    }
+
+    /// Compare the data in `new_data` with these hashes in the same way as [`delta_against()`](#method.delta_against),
+    /// and then update the hashes to match `new_data`.
+    pub fn delta_and_update<R: Read>(&mut self, new_data: R) -> Result<Delta> {
+        let mut new_chunks = Vec::new();
+        let delta = self.find_delta(new_data, Some(&mut new_chunks))?;
+        *self = ChunkHashes::from_chunks(new_chunks, self.sizes, self.strong_hash, self.strong_len);
+        Ok(delta)
+    }
+
+    /// Finds the delta which rebuilds `new_data`.  If `new_chunks` is given, the length and hash of
+    /// every chunk of `new_data` is recorded into it as well.
+    fn find_delta<R: Read>(&self, new_data: R, mut new_chunks: Option<&mut Vec<(usize, Vec<u8>)>>) -> Result<Delta> {
+        let mut chunker = Chunker::new(new_data, self.sizes);
+        let mut hasher = self.strong_hash.hasher();
+        let mut delta = Delta::new();
+        while let Some(chunk) = chunker.next_chunk()? {
+            let hash = hash_chunk(&mut *hasher, &chunk, self.strong_len);
+            match self.positions.get(&hash) {
+                Some(&(position, len)) if len == chunk.len() => delta.add_copy(position, len),
+                _ => delta.add_literal(chunk.clone())
+            }
+            if let Some(ref mut new_chunks) = new_chunks {
+                new_chunks.push((chunk.len(), hash));
+            }
+        }
+        Ok(delta)
+    }
+
+    /// Compress these hashes and write to `writer`.  The output can then be expanded
+    /// back into an equivilent set of hashes using `expand_from()`
+    pub fn compress_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut writer = ChecksumWriter::new(writer, PayloadKind::ChunkHashes, (None, Some(self.strong_hash)))?;
+        let mut long_buf = [0;8];
+        for size in [self.sizes.min, self.sizes.avg, self.sizes.max, self.chunks.len()].iter() {
+            NetworkEndian::write_u64(&mut long_buf, *size as u64);
+            writer.write_all(&long_buf)?;
+        }
+        writer.write_all(&[self.strong_len as u8])?;
+        for &(len, ref hash) in self.chunks.iter() {
+            NetworkEndian::write_u64(&mut long_buf, len as u64);
+            writer.write_all(&long_buf)?;
+            writer.write_all(hash)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Expand these hashes from previously compressed data in `reader`.  The data in reader
+    /// should have been written using `compress_to()`
+    ///
+    /// Gives an error if the data is not a set of chunk hashes, or has been corrupted.
+    pub fn expand_from<R: Read>(reader: &mut R) -> Result<ChunkHashes> {
+        let (mut reader, (_, strong_hash)) = ChecksumReader::new(reader, PayloadKind::ChunkHashes)?;
+        let strong_hash = strong_hash.ok_or_else(|| Error::Corrupt("no hash algorithm given".to_string()))?;
+        let mut sizes = [0; 4];
+        for size in sizes.iter_mut() {
+            *size = read_size(&mut reader)?;
+        }
+        let [min, avg, max, chunk_count] = sizes;
+        let sizes = ChunkSizes::new(min, avg, max).map_err(|_| Error::Corrupt("invalid chunk sizes".to_string()))?;
+        let mut strong_len = [0];
+        reader.read_exact(&mut strong_len)?;
+        let strong_len = strong_len[0] as usize;
+        if strong_len == 0 || strong_len > strong_hash.output_len() {
+            return Err(Error::Corrupt("invalid strong hash length".to_string()));
+        }
+        // The count hasn't been checked against the amount of data yet, so don't use it to reserve space
+        let mut chunks = Vec::new();
+        let mut file_size: usize = 0;
+        for _ in 0..chunk_count {
+            let len = read_size(&mut reader)?;
+            if len == 0 || len > sizes.max {
+                return Err(Error::Corrupt("invalid chunk length".to_string()));
+            }
+            file_size = file_size.checked_add(len).ok_or_else(|| Error::Corrupt("file size is too large for this platform".to_string()))?;
+            let mut hash = vec![0; strong_len];
+            reader.read_exact(&mut hash)?;
+            chunks.push((len, hash));
+        }
+        reader.finish()?;
+        Ok(ChunkHashes::from_chunks(chunks, sizes, strong_hash, strong_len))
+    }
+}
+
+/// Reads a 64 bit size from compressed hashes
+fn read_size<R: Read>(reader: &mut R) -> Result<usize> {
+    let mut long_buf = [0;8];
+    reader.read_exact(&mut long_buf)?;
+    let size = NetworkEndian::read_u64(&long_buf);
+    if size > usize::MAX as u64 {
+        return Err(Error::Corrupt("size is too large for this platform".to_string()));
+    }
+    Ok(size as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{ChunkHashes, ChunkSizes, Chunker, DeltaOp, Error, StrongHashAlgorithm};
+    use std::io::Cursor;
+
+    /// Data which doesn't repeat, so that every chunk is different
+    fn random_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            (state >> 56) as u8
+        }).collect()
+    }
+
+    fn chunk_lengths(data: &[u8], sizes: ChunkSizes) -> Vec<usize> {
+        let mut chunker = Chunker::new(data, sizes);
+        let mut lengths = Vec::new();
+        while let Some(chunk) = chunker.next_chunk().unwrap() {
+            lengths.push(chunk.len());
+        }
+        lengths
+    }
+
+    fn copied_bytes(hashes: &ChunkHashes, new: &[u8]) -> usize {
+        let delta = hashes.delta_against(new).unwrap();
+        let mut result = Vec::new();
+        delta.apply(&mut Cursor::new(&random_data(hashes.get_file_size(), 1)), &mut result).unwrap();
+        assert_eq!(result, new);
+        delta.ops().map(|op| match *op {
+            DeltaOp::Copy{len, ..} => len,
+            DeltaOp::Literal(_) => 0
+        }).sum()
+    }
+
+    #[test]
+    fn chunking() {
+        let sizes = ChunkSizes::new(256, 1024, 4096).unwrap();
+        let data = random_data(100_000, 1);
+        let lengths = chunk_lengths(&data, sizes);
+        assert_eq!(lengths.iter().sum::<usize>(), data.len());
+        assert!(lengths[..lengths.len() - 1].iter().all(|len| (256..=4096).contains(len)));
+        let average = data.len() / lengths.len();
+        assert!(average > 512 && average < 2048, "average chunk size was {}", average);
+
+        // Chunks after an insert are the same as before it
+        let mut shifted = b"some new data".to_vec();
+        shifted.extend_from_slice(&data);
+        let shifted_lengths = chunk_lengths(&shifted, sizes);
+        assert_eq!(shifted_lengths[shifted_lengths.len() - 50..], lengths[lengths.len() - 50..]);
+
+        assert_eq!(chunk_lengths(&[], sizes), Vec::<usize>::new());
+        assert_eq!(chunk_lengths(&data[..100], sizes), vec![100]);
+        match ChunkSizes::new(1024, 256, 4096) {
+            Err(Error::InvalidParameter(_)) => (),
+            result => panic!("expected an invalid parameter, got {:?}", result)
+        }
+    }
+
+    #[test]
+    fn chunk_deltas() {
+        let sizes = ChunkSizes::new(256, 1024, 4096).unwrap();
+        let old = random_data(100_000, 1);
+        let mut hashes = ChunkHashes::new(&old[..], sizes).unwrap();
+        assert_eq!(copied_bytes(&hashes, &old), old.len());
+
+        // Inserting near the start only changes the chunk around the insert
+        let mut new = old[..1000].to_vec();
+        new.extend_from_slice(b"an insert near the start");
+        new.extend_from_slice(&old[1000..50_000]);
+        new.extend_from_slice(&old[60_000..]);
+        assert!(copied_bytes(&hashes, &new) > 80_000);
+
+        hashes.delta_and_update(&new[..]).unwrap();
+        assert_eq!(hashes, ChunkHashes::new(&new[..], sizes).unwrap());
+    }
+
+    #[test]
+    fn compressing_chunk_hashes() {
+        let hashes = ChunkHashes::with_strong_hash(&random_data(10_000, 1)[..], ChunkSizes::new(64, 256, 1024).unwrap(), StrongHashAlgorithm::Sha256, 16).unwrap();
+        let mut compressed = Vec::new();
+        hashes.compress_to(&mut compressed).unwrap();
+        assert_eq!(compressed.len(), 8 + 32 + 1 + hashes.get_chunk_count() * 24 + 4);
+        assert_eq!(ChunkHashes::expand_from(&mut Cursor::new(compressed.clone())).unwrap(), hashes);
+        assert!(ChunkHashes::expand_from(&mut Cursor::new(&compressed[..compressed.len() - 1])).is_err());
+
+        // Chunks which are empty or longer than the maximum can't have been written by the chunker
+        // and chunks which add up to more than can be addressed can't be expanded
+        for &(max, lengths) in [(1024, [0, 256]), (1024, [1025, 256]), (usize::MAX, [usize::MAX, usize::MAX])].iter() {
+            let mut corrupt = ChunkHashes::with_strong_hash(&random_data(10_000, 1)[..], ChunkSizes::new(64, 256, 1024).unwrap(), StrongHashAlgorithm::Sha256, 16).unwrap();
+            corrupt.sizes.max = max;
+            corrupt.chunks[0].0 = lengths[0];
+            corrupt.chunks[1].0 = lengths[1];
+            let mut compressed = Vec::new();
+            corrupt.compress_to(&mut compressed).unwrap();
+            match ChunkHashes::expand_from(&mut Cursor::new(compressed)) {
+                Err(Error::Corrupt(_)) => (),
+                result => panic!("expected corrupt data, got {:?}", result)
+            }
+        }
+    }
+}
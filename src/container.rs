@@ -0,0 +1,233 @@
+//! The container written around compressed diffs and hashes, so that they can be recognised
+//! and checked when they are read back.
+//!
+//! A container is laid out as:
+//!
+//! | Field          | Size    |                                                           |
+//! |----------------|---------|-----------------------------------------------------------|
+//! | Magic          | 4 bytes | `rdif`                                                    |
+//! | Format version | 1 byte  | Currently 1                                               |
+//! | Payload kind   | 1 byte  | 1 for a `Diff`, 2 for `BlockHashes`, 3 for `ChunkHashes`  |
+//! | Weak hash      | 1 byte  | The rolling hash used by `BlockHashes`, or 0 otherwise    |
+//! | Strong hash    | 1 byte  | The strong hash used by the hashes, or 0 for a `Diff`     |
+//! | Payload        |         |                                                           |
+//! | Checksum       | 4 bytes | CRC-32 of everything before it                            |
+//!
+//! All integers are big endian.  Weak hashes numbered from 128 up are implemented outside of this crate.
+use super::{Error, Result, ChecksumReader, ChecksumWriter, PayloadKind, WeakHashAlgorithm, StrongHashAlgorithm, CONTAINER_MAGIC, CONTAINER_VERSION};
+use std::io::{self, Read, Write};
+use byteorder::{NetworkEndian, ByteOrder};
+use crc32fast::Hasher;
+
+/// The weak and strong hash algorithms recorded in a container, if the data it holds uses them
+type HashAlgorithms = (Option<WeakHashAlgorithm>, Option<StrongHashAlgorithm>);
+
+impl PayloadKind {
+    fn id(self) -> u8 {
+        match self {
+            PayloadKind::Diff => 1,
+            PayloadKind::BlockHashes => 2,
+            PayloadKind::ChunkHashes => 3
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            PayloadKind::Diff => "a diff",
+            PayloadKind::BlockHashes => "block hashes",
+            PayloadKind::ChunkHashes => "chunk hashes"
+        }
+    }
+}
+
+impl WeakHashAlgorithm {
+    fn id(self) -> u8 {
+        match self {
+            WeakHashAlgorithm::Rdiff => 1,
+            WeakHashAlgorithm::RollSum => 2,
+            WeakHashAlgorithm::RabinKarp => 3,
+            WeakHashAlgorithm::Adler32 => 4,
+            WeakHashAlgorithm::Buzhash => 5,
+            WeakHashAlgorithm::Custom(id) => id
+        }
+    }
+
+    fn from_id(id: u8) -> Option<WeakHashAlgorithm> {
+        match id {
+            1 => Some(WeakHashAlgorithm::Rdiff),
+            2 => Some(WeakHashAlgorithm::RollSum),
+            3 => Some(WeakHashAlgorithm::RabinKarp),
+            4 => Some(WeakHashAlgorithm::Adler32),
+            5 => Some(WeakHashAlgorithm::Buzhash),
+            128..=255 => Some(WeakHashAlgorithm::Custom(id)),
+            _ => None
+        }
+    }
+}
+
+impl StrongHashAlgorithm {
+    fn id(self) -> u8 {
+        match self {
+            StrongHashAlgorithm::Md5 => 1,
+            StrongHashAlgorithm::Blake2 => 2,
+            StrongHashAlgorithm::Blake3 => 3,
+            StrongHashAlgorithm::Sha256 => 4
+        }
+    }
+
+    fn from_id(id: u8) -> Option<StrongHashAlgorithm> {
+        match id {
+            1 => Some(StrongHashAlgorithm::Md5),
+            2 => Some(StrongHashAlgorithm::Blake2),
+            3 => Some(StrongHashAlgorithm::Blake3),
+            4 => Some(StrongHashAlgorithm::Sha256),
+            _ => None
+        }
+    }
+}
+
+/// The header which starts a container holding `kind` of data, which uses the given hash algorithms
+pub(crate) fn header(kind: PayloadKind, hashes: HashAlgorithms) -> [u8; 8] {
+    let (weak_id, strong_id) = (hashes.0.map_or(0, |weak| weak.id()), hashes.1.map_or(0, |strong| strong.id()));
+    let mut header = [0; 8];
+    header[..4].copy_from_slice(&CONTAINER_MAGIC);
+    header[4..].copy_from_slice(&[CONTAINER_VERSION, kind.id(), weak_id, strong_id]);
+    header
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    /// Starts a container by writing its header into `writer`
+    pub(crate) fn new(writer: W, kind: PayloadKind, hashes: HashAlgorithms) -> io::Result<ChecksumWriter<W>> {
+        let mut container = ChecksumWriter {
+            writer,
+            hasher: Hasher::new()
+        };
+        container.write_all(&header(kind, hashes))?;
+        Ok(container)
+    }
+
+    /// Ends the container by writing the checksum of everything written into it
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        NetworkEndian::write_u32(&mut int_buf, self.hasher.finalize());
+        self.writer.write_all(&int_buf)
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<R: Read> ChecksumReader<R> {
+    /// Starts reading a container from `reader`.  Gives an error if the data isn't in a container, or
+    /// the container doesn't hold the expected kind of data, and otherwise gives the hash algorithms
+    /// recorded in the container.
+    pub(crate) fn new(mut reader: R, kind: PayloadKind) -> Result<(ChecksumReader<R>, HashAlgorithms)> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != CONTAINER_MAGIC {
+            return Err(Error::Corrupt("not an rdiff file".to_string()));
+        }
+        let mut hasher = Hasher::new();
+        hasher.update(&magic);
+        let mut container = ChecksumReader {
+            reader,
+            hasher
+        };
+        let mut header = [0; 4];
+        container.read_exact(&mut header)?;
+        let [version, kind_id, weak_id, strong_id] = header;
+        if version != CONTAINER_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        if kind_id != kind.id() {
+            let found = [PayloadKind::Diff, PayloadKind::BlockHashes, PayloadKind::ChunkHashes].iter().find(|kind| kind.id() == kind_id).map_or("unknown data", |kind| kind.name());
+            return Err(Error::Corrupt(format!("expected {}, but found {}", kind.name(), found)));
+        }
+        let weak = match weak_id {
+            0 => None,
+            _ => Some(WeakHashAlgorithm::from_id(weak_id).ok_or_else(|| Error::Unsupported("unknown hash algorithm".to_string()))?)
+        };
+        let strong = match strong_id {
+            0 => None,
+            _ => Some(StrongHashAlgorithm::from_id(strong_id).ok_or_else(|| Error::Unsupported("unknown hash algorithm".to_string()))?)
+        };
+        let hashes = (weak, strong);
+        Ok((container, hashes))
+    }
+
+    /// Reads the checksum at the end of the container, and checks it against the data that was read
+    pub(crate) fn finish(mut self) -> Result<()> {
+        let mut int_buf = [0;4];
+        self.reader.read_exact(&mut int_buf)?;
+        if NetworkEndian::read_u32(&int_buf) != self.hasher.finalize() {
+            return Err(Error::Corrupt("checksum does not match, the data is corrupted".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{BlockHashes, Diff, Error, RollingHash};
+    use std::io::Cursor;
+
+    fn compressed_diff() -> Vec<u8> {
+        let mut diff = Diff::new();
+        diff.add_insert(2, b"ab".to_vec());
+        diff.add_delete(3, 1);
+        let mut compressed = Vec::new();
+        diff.compress_to(&mut compressed).unwrap();
+        compressed
+    }
+
+    fn error_message(data: Vec<u8>) -> String {
+        Diff::expand_from(&mut Cursor::new(data)).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn validating_containers() {
+        let compressed = compressed_diff();
+        assert_eq!(&compressed[..8], b"rdif\x01\x01\x00\x00");
+        assert!(Diff::expand_from(&mut Cursor::new(compressed.clone())).is_ok());
+
+        let mut corrupted = compressed.clone();
+        corrupted[20] ^= 1;
+        assert_eq!(error_message(corrupted), "checksum does not match, the data is corrupted");
+
+        let mut future = compressed.clone();
+        future[4] = 2;
+        assert_eq!(error_message(future.clone()), "unsupported format version 2");
+        match Diff::expand_from(&mut Cursor::new(future)) {
+            Err(Error::UnsupportedVersion(2)) => (),
+            result => panic!("expected an unsupported version, got {:?}", result)
+        }
+
+        match Diff::expand_from(&mut Cursor::new(&compressed[..compressed.len() - 2])) {
+            Err(Error::Truncated) => (),
+            result => panic!("expected truncated data, got {:?}", result)
+        }
+
+        let mut hashes = Vec::new();
+        BlockHashes::new(Cursor::new("It was the best of times"), 8).unwrap().compress_to(&mut hashes).unwrap();
+        assert_eq!(error_message(hashes.clone()), "expected a diff, but found block hashes");
+        assert_eq!(BlockHashes::<RollingHash>::expand_from(&mut Cursor::new(compressed)).unwrap_err().to_string(), "expected block hashes, but found a diff");
+        assert_eq!(error_message(b"some other data".to_vec()), "not an rdiff file");
+    }
+}
@@ -0,0 +1,256 @@
+use super::{Error, Result, Delta, DeltaOp, Move};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::slice::Iter;
+
+impl Delta {
+    /// Creates a new, empty `Delta`
+    #[inline]
+    pub fn new() -> Delta {
+        Delta {
+            ops: Vec::new()
+        }
+    }
+
+    /// Adds an operation to copy `len` bytes from `base_offset` in the old version of the file.
+    /// If the copy continues on from the previous copy, then the two are merged, as long as the
+    /// merged length still fits in a `usize`.
+    pub(crate) fn add_copy(&mut self, base_offset: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        if let Some(&mut DeltaOp::Copy{base_offset: tail_offset, len: ref mut tail_len}) = self.ops.last_mut() {
+            if tail_offset.checked_add(*tail_len) == Some(base_offset) {
+                if let Some(merged_len) = tail_len.checked_add(len) {
+                    *tail_len = merged_len;
+                    return;
+                }
+            }
+        }
+        self.ops.push(DeltaOp::Copy{base_offset, len});
+    }
+
+    /// Adds an operation to write `data` into the new version of the file.
+    /// If the previous operation was also a literal, then the two are merged.
+    ///
+    /// Consumes the data that is passed in
+    pub(crate) fn add_literal(&mut self, mut data: Vec<u8>) {
+        if data.is_empty() {
+            return;
+        }
+        if let Some(&mut DeltaOp::Literal(ref mut tail)) = self.ops.last_mut() {
+            tail.append(&mut data);
+            return;
+        }
+        self.ops.push(DeltaOp::Literal(data));
+    }
+
+    /// Gets an iterator over all of the operations, in the order of the new file
+    pub fn ops(&self) -> Iter<'_, DeltaOp> {
+        self.ops.iter()
+    }
+
+    /// Checks if this delta has any operations at all
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Gets the length in bytes of the file this delta will produce
+    ///
+    /// Gives an error if the length is too large to fit in a `usize`, which can only happen for a corrupt delta.
+    pub fn new_len(&self) -> Result<usize> {
+        self.ops.iter().try_fold(0usize, |total, op| {
+            let len = match *op {
+                DeltaOp::Copy{len, ..} => len,
+                DeltaOp::Literal(ref data) => data.len()
+            };
+            total.checked_add(len).ok_or_else(|| Error::Corrupt("the delta produces more data than can be addressed".to_string()))
+        })
+    }
+
+    /// Finds the copies in this delta which were moved from elsewhere in the old version of the file.
+    ///
+    /// The largest set of copies which appear in the same order in both versions are considered to have
+    /// stayed in place, and every other copy is reported as a move.  This means that if two blocks are
+    /// swapped, only one of them is reported as moved.  The moves are given in the order of the new file.
+    pub fn moves(&self) -> Vec<Move> {
+        // Find each copy, along with where it ends up in the new file
+        let mut copies = Vec::new();
+        let mut new_offset = 0;
+        for op in self.ops.iter() {
+            match *op {
+                DeltaOp::Copy{base_offset, len} => {
+                    copies.push(Move::new(base_offset, new_offset, len));
+                    new_offset += len;
+                }
+                DeltaOp::Literal(ref data) => new_offset += data.len()
+            }
+        }
+
+        // Then find the longest run of copies whose base offsets are increasing.
+        // `tails[i]` is the index of the copy ending the best run of length `i + 1` found so far
+        // and `previous` links each copy to the one before it in its run.
+        let mut tails: Vec<usize> = Vec::new();
+        let mut previous = vec![None; copies.len()];
+        for (index, copy) in copies.iter().enumerate() {
+            let run_len = tails.partition_point(|&tail| copies[tail].base_offset < copy.base_offset);
+            if run_len > 0 {
+                previous[index] = Some(tails[run_len - 1]);
+            }
+            if run_len == tails.len() {
+                tails.push(index);
+            } else {
+                tails[run_len] = index;
+            }
+        }
+        let mut in_place = vec![false; copies.len()];
+        let mut current = tails.last().cloned();
+        while let Some(index) = current {
+            in_place[index] = true;
+            current = previous[index];
+        }
+
+        copies.into_iter().zip(in_place).filter(|&(_, in_place)| !in_place).map(|(copy, _)| copy).collect()
+    }
+
+    /// Rebuilds the new version of the file by reading copied data from `base`, and writing
+    /// the result into `out`.  `base` should be the old version of the file that the delta was created against.
+    ///
+    /// Gives an error if any copy refers to data past the end of `base`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::BlockHashes;
+    /// use std::io::Cursor;
+    /// let hashes = BlockHashes::new(Cursor::new("first block second block"), 6).unwrap();
+    /// let delta = hashes.delta_against(Cursor::new("second block first block")).unwrap();
+    /// let mut result = Vec::new();
+    /// delta.apply(&mut Cursor::new("first block second block"), &mut result).unwrap();
+    /// assert_eq!(result, b"second block first block");
+    /// ```
+    pub fn apply<B: Read + Seek, W: Write>(&self, base: &mut B, out: &mut W) -> Result<()> {
+        for op in self.ops.iter() {
+            match *op {
+                DeltaOp::Copy{base_offset, len} => {
+                    base.seek(SeekFrom::Start(base_offset as u64))?;
+                    let copied = io::copy(&mut base.take(len as u64), out)?;
+                    if copied < len as u64 {
+                        return Err(Error::PositionOutOfRange(base_offset + copied as usize));
+                    }
+                }
+                DeltaOp::Literal(ref data) => out.write_all(data)?
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Move {
+    /// Builds a new `Move` of `len` bytes from `base_offset` in the old version of a file
+    /// to `new_offset` in the new version
+    #[inline]
+    pub fn new(base_offset: usize, new_offset: usize, len: usize) -> Move {
+        Move {
+            base_offset,
+            new_offset,
+            len
+        }
+    }
+
+    /// Gets the byte position of the moved data in the old version of the file
+    #[inline]
+    pub fn get_base_offset(&self) -> usize {
+        self.base_offset
+    }
+
+    /// Gets the byte position of the moved data in the new version of the file
+    #[inline]
+    pub fn get_new_offset(&self) -> usize {
+        self.new_offset
+    }
+
+    /// Gets the length in bytes of the moved data
+    #[inline]
+    pub fn get_length(&self) -> usize {
+        self.len
+    }
+}
+
+impl Default for Delta {
+    fn default() -> Delta {
+        Delta::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Delta, DeltaOp, Move};
+    use std::io::Cursor;
+
+    #[test]
+    fn merging_ops() {
+        let mut delta = Delta::new();
+        delta.add_copy(8, 8);
+        delta.add_copy(16, 4);
+        delta.add_literal(b"new".to_vec());
+        delta.add_literal(b" data".to_vec());
+        delta.add_copy(0, 8);
+        delta.add_copy(12, 0);
+        delta.add_literal(Vec::new());
+        assert_eq!(delta.ops().cloned().collect::<Vec<_>>(), vec![
+            DeltaOp::Copy{base_offset: 8, len: 12},
+            DeltaOp::Literal(b"new data".to_vec()),
+            DeltaOp::Copy{base_offset: 0, len: 8},
+        ]);
+        assert_eq!(delta.new_len().unwrap(), 28);
+
+        // Copies are only merged while their length fits
+        let mut delta = Delta::new();
+        delta.add_copy(1, usize::MAX - 1);
+        delta.add_copy(usize::MAX, 5);
+        assert_eq!(delta.ops().count(), 2);
+        assert!(delta.new_len().is_err());
+    }
+
+    #[test]
+    fn applying_delta() {
+        let base = "The quick brown fox jumps over the lazy dog";
+        let mut delta = Delta::new();
+        delta.add_copy(40, 3);
+        delta.add_literal(b" and ".to_vec());
+        delta.add_copy(16, 3);
+        delta.add_copy(3, 1);
+        delta.add_copy(20, 5);
+        let mut result = Vec::new();
+        delta.apply(&mut Cursor::new(base), &mut result).unwrap();
+        assert_eq!(String::from_utf8(result).unwrap(), "dog and fox jumps");
+
+        let mut delta = Delta::new();
+        delta.add_copy(40, 4);
+        assert!(delta.apply(&mut Cursor::new(base), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn finding_moves() {
+        let mut delta = Delta::new();
+        delta.add_copy(0, 8);
+        delta.add_literal(b"new".to_vec());
+        delta.add_copy(16, 8);
+        assert_eq!(delta.moves(), vec![]);
+
+        // Two swapped blocks: only one is considered moved
+        let mut delta = Delta::new();
+        delta.add_copy(8, 8);
+        delta.add_copy(0, 8);
+        assert_eq!(delta.moves(), vec![Move::new(8, 0, 8)]);
+
+        // A block moved from the end to the start of the file
+        let mut delta = Delta::new();
+        delta.add_copy(24, 8);
+        delta.add_literal(b"ab".to_vec());
+        delta.add_copy(0, 8);
+        delta.add_copy(10, 4);
+        delta.add_copy(16, 8);
+        assert_eq!(delta.moves(), vec![Move::new(24, 0, 8)]);
+    }
+}
@@ -0,0 +1,125 @@
+use super::{Diff, Engine, Op};
+use crate::ops::{push_op, front_len, take_op};
+use std::cmp::min;
+use std::collections::VecDeque;
+
+impl Engine {
+    /// Creates the engine for the site with the given id
+    #[inline]
+    pub fn new(site_id: u32) -> Engine {
+        Engine {
+            site_id
+        }
+    }
+
+    /// Gets the id of the site this engine belongs to
+    #[inline]
+    pub fn get_site_id(&self) -> u32 {
+        self.site_id
+    }
+
+    /// Transforms `remote`, a diff made by the site with id `remote_site`, so that it can be applied after `local`.
+    /// Both diffs must have been made against the same version of the file.
+    ///
+    /// The other site can call this with the two diffs swapped, and after each site applies its own diff followed by the
+    /// transformed diff, both will have the same result.  If both diffs insert data at the same place, the data from the site with the
+    /// lower id is placed first.  If both diffs delete the same data, it is only deleted once.
+    pub fn integrate_remote(&self, local: &Diff, remote: &Diff, remote_site: u32) -> Diff {
+        Diff::from_ops(&transform(remote.to_ops(), local.to_ops(), remote_site < self.site_id))
+    }
+}
+
+/// Transforms the operations in `ops` so that they can be performed after `other`, where both
+/// started from the same version of the file.  `ops_first` decides whether data inserted by
+/// `ops` goes before data inserted by `other` at the same place.
+fn transform(ops: Vec<Op>, other: Vec<Op>, ops_first: bool) -> Vec<Op> {
+    let mut ops = VecDeque::from(ops);
+    let mut other = VecDeque::from(other);
+    let mut transformed = Vec::new();
+    while !ops.is_empty() || !other.is_empty() {
+        match (ops.front(), other.front()) {
+            (Some(&Op::Insert(_)), Some(&Op::Insert(_))) if !ops_first => {
+                let len = front_len(&other);
+                other.pop_front();
+                push_op(&mut transformed, Op::Retain(len));
+            }
+            (Some(&Op::Insert(_)), _) => push_op(&mut transformed, ops.pop_front().unwrap()),
+            // Data inserted by the other operations is kept as is
+            (_, Some(&Op::Insert(_))) => {
+                let len = front_len(&other);
+                other.pop_front();
+                push_op(&mut transformed, Op::Retain(len));
+            }
+            _ => {
+                let len = min(front_len(&ops), front_len(&other));
+                match (take_op(&mut ops, len), take_op(&mut other, len)) {
+                    (Op::Retain(_), Op::Retain(_)) => push_op(&mut transformed, Op::Retain(len)),
+                    (Op::Delete(_), Op::Retain(_)) => push_op(&mut transformed, Op::Delete(len)),
+                    // The data has already been deleted by the other operations
+                    (Op::Retain(_), Op::Delete(_)) | (Op::Delete(_), Op::Delete(_)) => (),
+                    _ => unreachable!()
+                }
+            }
+        }
+    }
+    transformed
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Diff, Engine};
+
+    fn converge(base: &str, diff1: &Diff, diff2: &Diff) -> String {
+        let at_site1 = Engine::new(1).integrate_remote(diff1, diff2, 2).apply_to_string(&diff1.apply_to_string(base).unwrap()).unwrap();
+        let at_site2 = Engine::new(2).integrate_remote(diff2, diff1, 1).apply_to_string(&diff2.apply_to_string(base).unwrap()).unwrap();
+        assert_eq!(at_site1, at_site2);
+        at_site1
+    }
+
+    #[test]
+    fn inserts_at_same_position() {
+        let base = "The fox";
+        let mut diff1 = Diff::new();
+        diff1.add_insert(4, b"quick ".to_vec());
+        let mut diff2 = Diff::new();
+        diff2.add_insert(4, b"brown ".to_vec());
+        assert_eq!(converge(base, &diff1, &diff2), "The quick brown fox");
+        assert_eq!(converge(base, &diff2, &diff1), "The brown quick fox");
+    }
+
+    #[test]
+    fn overlapping_deletes() {
+        let base = "The quick brown fox";
+        // Deletes "quick " and adds " jumped"
+        let mut diff1 = Diff::new();
+        diff1.add_insert(19, b" jumped".to_vec());
+        diff1.add_delete(4, 6);
+        // Replaces "ck brown" with "et"
+        let mut diff2 = Diff::new();
+        diff2.add_insert(7, b"et".to_vec());
+        diff2.add_delete(9, 8);
+        assert_eq!(diff2.apply_to_string(base).unwrap(), "The quiet fox");
+        assert_eq!(converge(base, &diff1, &diff2), "The et fox jumped");
+        assert_eq!(converge(base, &diff2, &diff1), "The et fox jumped");
+
+        // The same diff made at both sites deletes "quick " once, but both inserts are kept
+        assert_eq!(converge(base, &diff1, &diff1), "The brown fox jumped jumped");
+    }
+
+    #[test]
+    fn identical_deletes() {
+        let base = "The quick brown fox";
+        // Both sites delete "quick "
+        let mut diff1 = Diff::new();
+        diff1.add_delete(4, 6);
+        let mut diff2 = Diff::new();
+        diff2.add_delete(4, 6);
+        assert_eq!(converge(base, &diff1, &diff2), "The brown fox");
+
+        // One site deletes "quick brown ", which includes the range deleted by the other
+        let mut diff3 = Diff::new();
+        diff3.add_delete(4, 12);
+        assert_eq!(converge(base, &diff1, &diff3), "The fox");
+        assert_eq!(converge(base, &diff3, &diff1), "The fox");
+    }
+}
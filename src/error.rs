@@ -0,0 +1,81 @@
+use super::Error;
+use std::error;
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Truncated => write!(f, "the data ended unexpectedly"),
+            Error::Corrupt(ref message) => write!(f, "{}", message),
+            Error::PositionOutOfRange(position) => write!(f, "position {} is past the end of the data", position),
+            Error::InvalidUtf8(ref e) => write!(f, "{}", e),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported format version {}", version),
+            Error::Unsupported(ref message) => write!(f, "{}", message),
+            Error::InvalidParameter(ref message) => write!(f, "{}", message),
+            Error::ContextMismatch(line) => write!(f, "the patch doesn't match the text at line {}", line)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::InvalidUtf8(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    /// Running out of data part way through reading something is reported as `Truncated`,
+    /// and any other error as `Io`
+    fn from(e: io::Error) -> Error {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            Error::Truncated
+        } else {
+            Error::Io(e)
+        }
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(e: FromUtf8Error) -> Error {
+        Error::InvalidUtf8(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            Error::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, Error::Truncated),
+            Error::InvalidParameter(_) => io::Error::new(io::ErrorKind::InvalidInput, e),
+            e => io::Error::new(io::ErrorKind::InvalidData, e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Error;
+    use std::io;
+
+    #[test]
+    fn converting_io_errors() {
+        match Error::from(io::Error::new(io::ErrorKind::UnexpectedEof, "eof")) {
+            Error::Truncated => (),
+            e => panic!("expected Truncated, got {:?}", e)
+        }
+        match Error::from(io::Error::new(io::ErrorKind::PermissionDenied, "denied")) {
+            Error::Io(ref e) if e.kind() == io::ErrorKind::PermissionDenied => (),
+            e => panic!("expected Io, got {:?}", e)
+        }
+        let e: io::Error = Error::PositionOutOfRange(12).into();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "position 12 is past the end of the data");
+    }
+}
@@ -1,25 +1,23 @@
-use super::{BlockHashes, Diff, Window};
-use std::io::{Read, Write, Result};
+use super::{Error, Result, BlockHashes, Delta, Diff, Window, WeakHash, WeakHashAlgorithm, RollingHash, RollSum, RabinKarp, Adler32, Buzhash, StrongHashAlgorithm, StrongHash, ChecksumReader, ChecksumWriter, PayloadKind};
+use std::io::{self, Read, Write};
 use std::collections::HashMap;
-use crypto::md5::Md5;
-use crypto::digest::Digest;
+use std::cmp::{min, max};
+use std::thread;
+use std::mem;
+use std::fmt;
+use std::marker::PhantomData;
 use byteorder::{NetworkEndian, ByteOrder};
 
-/// Implements a weak, but easy to calculate hash for a block of bytes
-///
-/// The hash is comprised of two bytes.  The first is the sum of the bytes
-// in the block, the second is the sum of the sum of the bytes in the block
-struct RollingHash {
-    a: u16,
-    b: u16,
-    block_size: u16
-}
+/// The hashes of each block, keyed by their weak hash
+type BlockHashMap = HashMap<u32, Vec<(usize, Vec<u8>)>>;
+
+/// The amount of data read at once when hashing blocks in parallel, which is split between the threads
+const PARALLEL_BATCH_SIZE: usize = 8 * 1024 * 1024;
 
-impl RollingHash {
+impl WeakHash for RollingHash {
+    const ALGORITHM: WeakHashAlgorithm = WeakHashAlgorithm::Rdiff;
 
-    /// Creates a new rolling hash over the bytes in `initial_data`.
-    /// It will be assumed that the size of blocks will be the size of the initial data.
-    pub fn new<'a, I: Iterator<Item=&'a u8>>(initial_data: I) -> RollingHash {
+    fn new<'a, I: Iterator<Item=&'a u8>>(initial_data: I) -> RollingHash {
 
         let mut a:u16 = 0;
         let mut b:u16 = 0;
@@ -27,36 +25,31 @@ impl RollingHash {
         for byte in initial_data {
             a = a.wrapping_add(*byte as u16);
             b = b.wrapping_add(a);
-            block_size += 1;
+            block_size = block_size.wrapping_add(1);
         }
         RollingHash {
-            a: a,
-            b: b,
-            block_size: block_size
+            a,
+            b,
+            block_size
         }
     }
 
-    /// Gets the hash as it currently stands
-    pub fn get_hash(&self) -> u32 {
-        return (self.b as u32) << 16 | self.a as u32;
+    fn get_hash(&self) -> u32 {
+        (self.b as u32) << 16 | self.a as u32
     }
 
-    /// Roll the has forward one byte.  This function will remove `old_byte` from its calculation
-    /// and add `new_byte` if it exists.
-    /// To get the hash afterwards, use `get_hash()`.
-    pub fn roll_hash(&mut self, new_byte: Option<u8>, old_byte: u8) {
+    fn roll_hash(&mut self, new_byte: Option<u8>, old_byte: u8) {
         self.a = self.a.wrapping_sub(old_byte as u16);
-        self.b = self.b.wrapping_sub(((old_byte as u16).wrapping_mul(self.block_size as u16)) as u16);
+        self.b = self.b.wrapping_sub((old_byte as u16).wrapping_mul(self.block_size));
         if let Some(new_byte) = new_byte {
             self.a = self.a.wrapping_add(new_byte as u16);
             self.b = self.b.wrapping_add(self.a);
         } else {
-            self.block_size -= 1
+            self.block_size = self.block_size.wrapping_sub(1);
         }
     }
 
-    /// Calculate the hash of a collection of bytes.
-    pub fn hash_buffer(buffer: &[u8]) -> u32 {
+    fn hash_buffer(buffer: &[u8]) -> u32 {
         let mut a:u16 = 0;
         let mut b:u16 = 0;
         for byte in buffer {
@@ -68,6 +61,364 @@ impl RollingHash {
     }
 }
 
+/// The constant librsync adds to every byte in its rollsum
+const ROLLSUM_CHAR_OFFSET: u16 = 31;
+
+impl WeakHash for RollSum {
+    const ALGORITHM: WeakHashAlgorithm = WeakHashAlgorithm::RollSum;
+
+    fn new<'a, I: Iterator<Item=&'a u8>>(initial_data: I) -> RollSum {
+        let mut s1: u16 = 0;
+        let mut s2: u16 = 0;
+        let mut count: u16 = 0;
+        for byte in initial_data {
+            s1 = s1.wrapping_add(*byte as u16 + ROLLSUM_CHAR_OFFSET);
+            s2 = s2.wrapping_add(s1);
+            count = count.wrapping_add(1);
+        }
+        RollSum {
+            s1,
+            s2,
+            count
+        }
+    }
+
+    fn get_hash(&self) -> u32 {
+        (self.s2 as u32) << 16 | self.s1 as u32
+    }
+
+    fn roll_hash(&mut self, new_byte: Option<u8>, old_byte: u8) {
+        let old_byte = old_byte as u16 + ROLLSUM_CHAR_OFFSET;
+        self.s1 = self.s1.wrapping_sub(old_byte);
+        self.s2 = self.s2.wrapping_sub(self.count.wrapping_mul(old_byte));
+        if let Some(new_byte) = new_byte {
+            self.s1 = self.s1.wrapping_add(new_byte as u16 + ROLLSUM_CHAR_OFFSET);
+            self.s2 = self.s2.wrapping_add(self.s1);
+        } else {
+            self.count = self.count.wrapping_sub(1);
+        }
+    }
+}
+
+/// The multiplier librsync uses for its Rabin-Karp hash
+const RABINKARP_MULT: u32 = 0x0810_4225;
+/// The multiplicative inverse of `RABINKARP_MULT` modulo 2^32
+const RABINKARP_INVM: u32 = 0x98f0_09ad;
+/// The value librsync starts each Rabin-Karp hash from
+const RABINKARP_SEED: u32 = 1;
+
+impl WeakHash for RabinKarp {
+    const ALGORITHM: WeakHashAlgorithm = WeakHashAlgorithm::RabinKarp;
+
+    fn new<'a, I: Iterator<Item=&'a u8>>(initial_data: I) -> RabinKarp {
+        let mut hash = RABINKARP_SEED;
+        let mut mult: u32 = 1;
+        for byte in initial_data {
+            hash = hash.wrapping_mul(RABINKARP_MULT).wrapping_add(*byte as u32);
+            mult = mult.wrapping_mul(RABINKARP_MULT);
+        }
+        RabinKarp {
+            hash,
+            mult
+        }
+    }
+
+    fn get_hash(&self) -> u32 {
+        self.hash
+    }
+
+    fn roll_hash(&mut self, new_byte: Option<u8>, old_byte: u8) {
+        // Removing the old byte also has to remove the seed's contribution from the
+        // highest power, which is what the extra `RABINKARP_MULT - 1` is for
+        let old_byte = (old_byte as u32).wrapping_add(RABINKARP_MULT - 1);
+        if let Some(new_byte) = new_byte {
+            self.hash = self.hash.wrapping_mul(RABINKARP_MULT)
+                .wrapping_add(new_byte as u32)
+                .wrapping_sub(self.mult.wrapping_mul(old_byte));
+        } else {
+            self.mult = self.mult.wrapping_mul(RABINKARP_INVM);
+            self.hash = self.hash.wrapping_sub(self.mult.wrapping_mul(old_byte));
+        }
+    }
+}
+
+/// The modulus of Adler-32, which is the largest prime below 2^16
+const ADLER32_MOD: u32 = 65521;
+
+impl WeakHash for Adler32 {
+    const ALGORITHM: WeakHashAlgorithm = WeakHashAlgorithm::Adler32;
+
+    fn new<'a, I: Iterator<Item=&'a u8>>(initial_data: I) -> Adler32 {
+        let mut a: u32 = 1;
+        let mut b: u32 = 0;
+        let mut count: u32 = 0;
+        for byte in initial_data {
+            a = (a + *byte as u32) % ADLER32_MOD;
+            b = (b + a) % ADLER32_MOD;
+            count = count.wrapping_add(1);
+        }
+        Adler32 {
+            a,
+            b,
+            count
+        }
+    }
+
+    fn get_hash(&self) -> u32 {
+        self.b << 16 | self.a
+    }
+
+    fn roll_hash(&mut self, new_byte: Option<u8>, old_byte: u8) {
+        // The old byte was counted once for every byte in the block by `b`, along with the 1 that `a` starts at
+        let old_byte = old_byte as u32;
+        let removed = (self.count % ADLER32_MOD * old_byte + 1) % ADLER32_MOD;
+        self.a = (self.a + ADLER32_MOD - old_byte) % ADLER32_MOD;
+        self.b = (self.b + ADLER32_MOD - removed) % ADLER32_MOD;
+        if let Some(new_byte) = new_byte {
+            self.a = (self.a + new_byte as u32) % ADLER32_MOD;
+            self.b = (self.b + self.a) % ADLER32_MOD;
+        } else {
+            self.count = self.count.wrapping_sub(1);
+        }
+    }
+}
+
+/// Generates the table of random values Buzhash gives each byte, using splitmix64 from a fixed seed.
+/// Hashes are stored with the algorithm that made them, so this table must never change.
+const fn buzhash_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x7264_6966_6275_7a68;
+    let mut index = 0;
+    while index < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[index] = ((z ^ (z >> 31)) >> 32) as u32;
+        index += 1;
+    }
+    table
+}
+
+/// The random value Buzhash gives each byte
+const BUZHASH_TABLE: [u32; 256] = buzhash_table();
+
+impl WeakHash for Buzhash {
+    const ALGORITHM: WeakHashAlgorithm = WeakHashAlgorithm::Buzhash;
+
+    fn new<'a, I: Iterator<Item=&'a u8>>(initial_data: I) -> Buzhash {
+        let mut hash: u32 = 0;
+        let mut count: u32 = 0;
+        for byte in initial_data {
+            hash = hash.rotate_left(1) ^ BUZHASH_TABLE[*byte as usize];
+            count = count.wrapping_add(1);
+        }
+        Buzhash {
+            hash,
+            count
+        }
+    }
+
+    fn get_hash(&self) -> u32 {
+        self.hash
+    }
+
+    fn roll_hash(&mut self, new_byte: Option<u8>, old_byte: u8) {
+        // The oldest byte has been rotated once for every byte after it in the block
+        let old_value = BUZHASH_TABLE[old_byte as usize].rotate_left(self.count.wrapping_sub(1) % 32);
+        self.hash ^= old_value;
+        if let Some(new_byte) = new_byte {
+            self.hash = self.hash.rotate_left(1) ^ BUZHASH_TABLE[new_byte as usize];
+        } else {
+            self.count = self.count.wrapping_sub(1);
+        }
+    }
+}
+
+/// Calculates the strong hashes of blocks, truncated to the length kept in the hashes
+pub(crate) struct StrongHasher {
+    digest: Box<dyn StrongHash + Send>,
+    result: Vec<u8>,
+    len: usize
+}
+
+impl StrongHasher {
+    pub(crate) fn new(algorithm: StrongHashAlgorithm, len: usize) -> StrongHasher {
+        let digest = algorithm.hasher();
+        let result = vec![0; digest.output_len()];
+        StrongHasher {
+            digest,
+            result,
+            len
+        }
+    }
+
+    /// Hashes the data in `front` followed by the data in `back`
+    fn hash(&mut self, front: &[u8], back: &[u8]) -> &[u8] {
+        self.digest.update(front);
+        self.digest.update(back);
+        self.digest.finish_reset(&mut self.result);
+        &self.result[..self.len]
+    }
+
+    /// Gets the result of the most recent call to `hash()`
+    fn last_hash(&self) -> &[u8] {
+        &self.result[..self.len]
+    }
+}
+
+/// Reads from `reader` until `buffer` is full or there is no more data.
+///
+/// Returns the number of bytes read, which is only less than the size of `buffer` at the end of the data
+pub(crate) fn read_block<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read_size = 0;
+    while read_size < buffer.len() {
+        match reader.read(&mut buffer[read_size..]) {
+            Ok(0) => break,
+            Ok(size) => read_size += size,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(read_size)
+}
+
+/// Reads a size from compressed hashes, which is either 64 or 32 bits
+fn read_size<R: Read>(reader: &mut R, wide: bool) -> Result<usize> {
+    let size = if wide {
+        let mut long_buf = [0;8];
+        reader.read_exact(&mut long_buf)?;
+        NetworkEndian::read_u64(&long_buf)
+    } else {
+        let mut int_buf = [0;4];
+        reader.read_exact(&mut int_buf)?;
+        NetworkEndian::read_u32(&int_buf) as u64
+    };
+    if size > usize::MAX as u64 {
+        return Err(Error::Corrupt("size is too large for this platform".to_string()));
+    }
+    Ok(size as usize)
+}
+
+
+/// Finds the difference between new data and a set of hashes by indexing directly into slices of the new data,
+/// rather than reading it through a window.  The data can be given all at once, or a piece at a time as it arrives.
+pub(crate) struct DiffScanner<'a, W: WeakHash> {
+    old_hashes: &'a BlockHashes<W>,
+    /// The hashes of the new data, if they are being recorded
+    new_hashes: Option<BlockHashMap>,
+    diffs: Diff,
+    /// The weak hash of the current window, or `None` if it has to be calculated from scratch
+    weak_hasher: Option<W>,
+    strong_hasher: StrongHasher,
+    last_matching_block_index: Option<usize>,
+    /// The number of bytes of new data scanned so far
+    position: usize,
+    /// Data which has been scanned without matching any block, waiting to be inserted
+    insert_buffer: Vec<u8>,
+    /// Inserts are performed before deletes, so their positions have to include everything deleted before them
+    deleted: usize
+}
+
+impl<'a, W: WeakHash> DiffScanner<'a, W> {
+    /// Starts finding the difference against `old_hashes`, also recording the hashes of the new data if `record_hashes` is set
+    pub(crate) fn new(old_hashes: &'a BlockHashes<W>, record_hashes: bool) -> DiffScanner<'a, W> {
+        DiffScanner {
+            old_hashes,
+            new_hashes: if record_hashes { Some(HashMap::new()) } else { None },
+            diffs: Diff::new(),
+            weak_hasher: None,
+            strong_hasher: StrongHasher::new(old_hashes.strong_hash, old_hashes.strong_len),
+            last_matching_block_index: None,
+            position: 0,
+            insert_buffer: Vec::new(),
+            deleted: 0
+        }
+    }
+
+    /// Scans `data`, the new data which follows everything scanned so far.  If `finished` is set, `data` runs to the
+    /// end of the new data and is scanned completely.  Otherwise, scanning stops while there are still two blocks' worth
+    /// of data left, so that every window and block is complete.
+    ///
+    /// Returns the number of bytes scanned.  These are no longer needed, and the rest of `data` must be passed in again
+    /// at the start of the next call.
+    pub(crate) fn scan(&mut self, data: &[u8], finished: bool) -> usize {
+        let block_size = self.old_hashes.block_size;
+        let frame = |index: usize| &data[index..min(index + block_size, data.len())];
+        let mut index = 0;
+        // Everything from here up to `index` is waiting to be inserted
+        let mut insert_start = 0;
+        while index < data.len() && (finished || index + 2 * block_size <= data.len()) {
+            let weak_hasher = self.weak_hasher.get_or_insert_with(|| W::new(frame(index).iter()));
+            let position = self.position + index;
+            if let Some(other_block_index) = self.old_hashes.check_match(weak_hasher, &mut self.strong_hasher, (frame(index), &[]), self.last_matching_block_index) {
+                self.insert_buffer.extend_from_slice(&data[insert_start..index]);
+                if !self.insert_buffer.is_empty() {
+                    self.diffs.add_insert(position - self.insert_buffer.len() + self.deleted, mem::take(&mut self.insert_buffer));
+                }
+                let next_block_index = self.last_matching_block_index.map_or(0, |last| last + 1);
+                if other_block_index > next_block_index {
+                    let len = block_size * (other_block_index - next_block_index);
+                    self.diffs.add_delete(position, len);
+                    self.deleted += len;
+                }
+                self.last_matching_block_index = Some(other_block_index);
+                // A block of the new data starts either where the match does, or somewhere inside it
+                let end = min(index + block_size, data.len());
+                if let Some(ref mut new_hashes) = self.new_hashes {
+                    if position % block_size == 0 {
+                        let strong_hash = self.strong_hasher.last_hash().to_vec();
+                        new_hashes.entry(weak_hasher.get_hash()).or_insert_with(Vec::new).push((position / block_size, strong_hash));
+                    } else {
+                        let boundry = (position / block_size + 1) * block_size - self.position;
+                        if boundry < end {
+                            let block = frame(boundry);
+                            let strong_hash = self.strong_hasher.hash(block, &[]).to_vec();
+                            new_hashes.entry(W::hash_buffer(block)).or_insert_with(Vec::new).push((position / block_size + 1, strong_hash));
+                        }
+                    }
+                }
+                // Jump past the whole match, rather than rolling through it
+                index = end;
+                insert_start = end;
+                self.weak_hasher = None;
+            } else {
+                if position % block_size == 0 {
+                    if let Some(ref mut new_hashes) = self.new_hashes {
+                        let strong_hash = self.strong_hasher.hash(frame(index), &[]).to_vec();
+                        new_hashes.entry(weak_hasher.get_hash()).or_insert_with(Vec::new).push((position / block_size, strong_hash));
+                    }
+                }
+                weak_hasher.roll_hash(data.get(index + block_size).cloned(), data[index]);
+                index += 1;
+            }
+        }
+        self.insert_buffer.extend_from_slice(&data[insert_start..index]);
+        self.position += index;
+        index
+    }
+
+    /// Finishes the diff once all of the new data has been scanned.  Returns the diff, along with the hashes of the new data,
+    /// which are only filled in if they were being recorded.
+    pub(crate) fn finish(mut self) -> (Diff, BlockHashes<W>) {
+        let old_hashes = self.old_hashes;
+        if !self.insert_buffer.is_empty() {
+            self.diffs.add_insert(self.position - self.insert_buffer.len() + self.deleted, self.insert_buffer);
+        }
+        let next_block_index = self.last_matching_block_index.map_or(0, |last| last + 1);
+        if next_block_index < old_hashes.get_block_count() {
+            self.diffs.add_delete(self.position, old_hashes.file_size - next_block_index * old_hashes.block_size);
+        }
+        (self.diffs, BlockHashes {
+            hashes: self.new_hashes.unwrap_or_default(),
+            block_size: old_hashes.block_size,
+            file_size: self.position,
+            weak_hash: PhantomData,
+            strong_hash: old_hashes.strong_hash,
+            strong_len: old_hashes.strong_len
+        })
+    }
+}
 
 impl BlockHashes {
 
@@ -76,43 +427,217 @@ impl BlockHashes {
     ///
     /// To see the difference after `data_source` has been updated, use `diff_and_update()`
     ///
-    /// This method returns an error when there is a problem reading from `data_source`.
-    pub fn new<R: Read>(mut data_source: R, block_size: usize) -> Result<BlockHashes> {
+    /// This method returns an error when there is a problem reading from `data_source`, or when `block_size` is 0.
+    pub fn new<R: Read>(data_source: R, block_size: usize) -> Result<BlockHashes> {
+        BlockHashes::with_algorithms(data_source, block_size, StrongHashAlgorithm::Md5, 16)
+    }
+
+    /// Create a new BlockHash based on the data in data_source in the same way as `new()`, but hashing the blocks
+    /// on `threads` threads at once.  The data is read in large batches, and each thread hashes a run of
+    /// blocks from the batch, so the hashes are identical to the ones `new()` would give.
+    ///
+    /// This method returns an error when there is a problem reading from `data_source`, or when `block_size` or `threads` is 0.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::BlockHashes;
+    /// use std::io::Cursor;
+    /// let hashes = BlockHashes::new_parallel(Cursor::new("It was the best of times"), 6, 4).unwrap();
+    /// assert_eq!(hashes, BlockHashes::new(Cursor::new("It was the best of times"), 6).unwrap());
+    /// ```
+    pub fn new_parallel<R: Read>(data_source: R, block_size: usize, threads: usize) -> Result<BlockHashes> {
+        BlockHashes::with_algorithms_parallel(data_source, block_size, threads, PARALLEL_BATCH_SIZE, StrongHashAlgorithm::Md5, 16)
+    }
+
+    /// Create a new BlockHash based on the data in data_source, using `strong_hash` to confirm that blocks match
+    /// instead of MD5.  The strong hashes are truncated to `strong_len` bytes, which saves space at the cost of
+    /// a higher chance of two different blocks being mistaken for each other.
+    ///
+    /// Gives an error if `strong_len` is 0 or longer than the algorithm's digest.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::{BlockHashes, StrongHashAlgorithm};
+    /// use std::io::Cursor;
+    /// let hashes = BlockHashes::with_strong_hash(Cursor::new("The initial version"), 8, StrongHashAlgorithm::Blake3, 8).unwrap();
+    /// let diff = hashes.diff_against(Cursor::new("The next version")).unwrap();
+    /// assert_eq!(diff.apply_to_string("The initial version").unwrap(), "The next version");
+    /// ```
+    pub fn with_strong_hash<R: Read>(data_source: R, block_size: usize, strong_hash: StrongHashAlgorithm, strong_len: usize) -> Result<BlockHashes> {
+        BlockHashes::with_hashes(data_source, block_size, strong_hash, strong_len)
+    }
+
+    /// Construct a new block hash for a file that was just created
+    pub fn empty(block_size: usize) -> BlockHashes {
+        BlockHashes {
+            hashes: HashMap::new(),
+            block_size,
+            file_size: 0,
+            weak_hash: PhantomData,
+            strong_hash: StrongHashAlgorithm::Md5,
+            strong_len: 16
+        }
+    }
+
+    /// Expand hashes written by `compress_to()` in earlier versions of this crate, which didn't use
+    /// a container and wrote sizes as 32 bit integers.  Those hashes always used the original rolling
+    /// hash and MD5.
+    ///
+    /// Nothing in that layout identifies it or detects corruption, so this should only be used for data
+    /// known to have been written that way.
+    pub fn expand_from_legacy<R: Read>(reader: &mut R) -> Result<BlockHashes> {
+        let file_size = read_size(reader, false)?;
+        let block_size = read_size(reader, false)?;
+        BlockHashes::expand_hashes_from(reader, file_size, block_size, StrongHashAlgorithm::Md5, 16)
+    }
+}
+
+impl<H: WeakHash> BlockHashes<H> {
+    /// Create a new BlockHash based on the data in data_source, using `H` as the rolling weak hash of each block
+    /// instead of the original sum.  Every diff found against these hashes, including by
+    /// [`diff_and_update()`](#method.diff_and_update), rolls the same hash through the new data.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::{BlockHashes, Buzhash};
+    /// use std::io::Cursor;
+    /// let mut hashes = BlockHashes::<Buzhash>::with_weak_hash(Cursor::new("aaaaaaaabbbbbbbbaaaaaaaa"), 8).unwrap();
+    /// let diff = hashes.diff_and_update(Cursor::new("bbbbbbbbaaaaaaaa")).unwrap();
+    /// assert_eq!(diff.apply_to_string("aaaaaaaabbbbbbbbaaaaaaaa").unwrap(), "bbbbbbbbaaaaaaaa");
+    /// ```
+    pub fn with_weak_hash<R: Read>(data_source: R, block_size: usize) -> Result<BlockHashes<H>> {
+        BlockHashes::with_hashes(data_source, block_size, StrongHashAlgorithm::Md5, 16)
+    }
+
+    /// Create a new BlockHash based on the data in data_source, choosing both the weak and the strong hash.
+    /// See [`with_weak_hash()`](#method.with_weak_hash) and [`with_strong_hash()`](#method.with_strong_hash).
+    pub fn with_hashes<R: Read>(data_source: R, block_size: usize, strong_hash: StrongHashAlgorithm, strong_len: usize) -> Result<BlockHashes<H>> {
+        if strong_len == 0 || strong_len > strong_hash.output_len() {
+            return Err(Error::InvalidParameter(format!("strong hashes must be between 1 and {} bytes", strong_hash.output_len())));
+        }
+        BlockHashes::with_algorithms(data_source, block_size, strong_hash, strong_len)
+    }
+
+    /// Create a new BlockHash based on the data in data_source, using `H` and `strong_hash` to hash each block.
+    /// The strong hashes are truncated to `strong_len` bytes.
+    pub(crate) fn with_algorithms<R: Read>(mut data_source: R, block_size: usize, strong_hash: StrongHashAlgorithm, strong_len: usize) -> Result<BlockHashes<H>> {
+        if block_size == 0 {
+            return Err(Error::InvalidParameter("the block size must be at least 1".to_string()));
+        }
         let mut block = vec![0;block_size];
-        let mut hashes = HashMap::new();
-        let mut block_index = 0;
-        let mut strong_hasher = Md5::new();
-        let mut total_size = 0;
+        let mut hashes = BlockHashes {
+            hashes: HashMap::new(),
+            block_size,
+            file_size: 0,
+            weak_hash: PhantomData,
+            strong_hash,
+            strong_len
+        };
+        let mut strong_hasher = StrongHasher::new(strong_hash, strong_len);
 
-        let mut read_size = try!(data_source.read(&mut block));
+        let mut read_size = read_block(&mut data_source, &mut block)?;
         while read_size > 0 {
-            let weak_hash = RollingHash::hash_buffer(&block[..read_size]);
+            hashes.add_block(&mut strong_hasher, &block[..read_size]);
+            read_size = read_block(&mut data_source, &mut block)?;
+        }
+        Ok(hashes)
+    }
 
-            let mut strong_hash:[u8;16] = [0;16];
-            strong_hasher.reset();
-            strong_hasher.input(&block[..read_size]);
-            strong_hasher.result(&mut strong_hash);
+    /// Hashes `block`, the next block of the file, and adds it to the end of these hashes.
+    /// Every block before it must have been a whole block.
+    pub(crate) fn add_block(&mut self, strong_hasher: &mut StrongHasher, block: &[u8]) {
+        let weak = H::hash_buffer(block);
+        let strong = strong_hasher.hash(block, &[]).to_vec();
+        let block_index = self.get_block_count();
+        self.hashes.entry(weak).or_default().push((block_index, strong));
+        self.file_size += block.len();
+    }
 
-            hashes.entry(weak_hash).or_insert(Vec::new()).push((block_index, strong_hash));
+    /// Create a new BlockHash in the same way as `with_algorithms()`, reading about `batch_size` bytes at a time
+    /// and splitting the blocks in each batch between `threads` threads.
+    fn with_algorithms_parallel<R: Read>(mut data_source: R, block_size: usize, threads: usize, batch_size: usize, strong_hash: StrongHashAlgorithm, strong_len: usize) -> Result<BlockHashes<H>> {
+        if block_size == 0 {
+            return Err(Error::InvalidParameter("the block size must be at least 1".to_string()));
+        }
+        if threads == 0 {
+            return Err(Error::InvalidParameter("at least 1 thread is needed".to_string()));
+        }
+        // Every thread gets at least one block from each batch
+        let mut batch = vec![0; max(batch_size / block_size, threads) * block_size];
+        let mut hashes = HashMap::new();
+        let mut block_index = 0;
+        let mut total_size = 0;
 
-            block_index += 1;
+        loop {
+            let read_size = read_block(&mut data_source, &mut batch)?;
+            if read_size == 0 {
+                break;
+            }
+            let blocks = read_size.div_ceil(block_size);
+            let run_size = blocks.div_ceil(threads) * block_size;
+            let runs = thread::scope(|scope| {
+                let handles: Vec<_> = batch[..read_size].chunks(run_size).map(|run| {
+                    scope.spawn(move || {
+                        let mut strong_hasher = StrongHasher::new(strong_hash, strong_len);
+                        run.chunks(block_size).map(|block| {
+                            let weak = H::hash_buffer(block);
+                            (weak, strong_hasher.hash(block, &[]).to_vec())
+                        }).collect::<Vec<_>>()
+                    })
+                }).collect();
+                handles.into_iter().map(|handle| handle.join().expect("a hashing thread panicked")).collect::<Vec<_>>()
+            });
+            // Blocks are added in order, so that blocks with the same weak hash are listed just as `with_algorithms()` lists them
+            for (weak, strong) in runs.into_iter().flatten() {
+                hashes.entry(weak).or_insert_with(Vec::new).push((block_index, strong));
+                block_index += 1;
+            }
             total_size += read_size;
-            read_size = try!(data_source.read(&mut block));
+            if read_size < batch.len() {
+                break;
+            }
         }
         Ok(BlockHashes {
             hashes,
             block_size,
-            file_size: total_size
+            file_size: total_size,
+            weak_hash: PhantomData,
+            strong_hash,
+            strong_len
         })
     }
 
-    /// Construct a new block hash for a file that was just created
-    pub fn empty(block_size: usize) -> BlockHashes {
-        BlockHashes {
-            hashes: HashMap::new(),
-            block_size: block_size,
-            file_size: 0
-        }
+    /// Gets the size of the blocks these hashes were calculated over
+    #[inline]
+    pub fn get_block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Gets the number of blocks these hashes were calculated over
+    #[inline]
+    pub fn get_block_count(&self) -> usize {
+        self.file_size.div_ceil(self.block_size)
+    }
+
+    /// Gets the algorithm used to calculate the weak hash of each block
+    #[inline]
+    pub fn get_weak_hash(&self) -> WeakHashAlgorithm {
+        H::ALGORITHM
+    }
+
+    /// Gets the algorithm used to calculate the strong hash of each block
+    #[inline]
+    pub fn get_strong_hash(&self) -> StrongHashAlgorithm {
+        self.strong_hash
+    }
+
+    /// Gets the length in bytes the strong hashes are truncated to
+    #[inline]
+    pub fn get_strong_len(&self) -> usize {
+        self.strong_len
     }
 
     /// Compare the data in `new_data` with the hashes computed from either
@@ -137,29 +662,223 @@ impl BlockHashes {
     ///             diff.apply_to_string("It was the best of times").unwrap());
     /// ```
     pub fn diff_and_update<R: Read>(&mut self, new_data: R) -> Result<Diff> {
+        let (diffs, new_hashes) = self.diff_and_signature(new_data)?;
+        *self = new_hashes;
+        Ok(diffs)
+    }
+
+    /// Compare the data in `new_data` with these hashes, without updating them.
+    ///
+    /// Since the hashes are left untouched, the same `BlockHashes` can be used to find
+    /// the difference against any number of candidate files, such as when the hashes
+    /// were received from another machine.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::BlockHashes;
+    /// use std::io::Cursor;
+    /// let hashes = BlockHashes::new(Cursor::new("It was the best of times"), 6).unwrap();
+    /// let first = hashes.diff_against(Cursor::new("It was the worst of times")).unwrap();
+    /// let second = hashes.diff_against(Cursor::new("It was the best of things")).unwrap();
+    /// assert_eq!("It was the worst of times",
+    ///             first.apply_to_string("It was the best of times").unwrap());
+    /// assert_eq!("It was the best of things",
+    ///             second.apply_to_string("It was the best of times").unwrap());
+    /// ```
+    pub fn diff_against<R: Read>(&self, new_data: R) -> Result<Diff> {
+        let (diffs, _) = self.find_diff(new_data, None)?;
+        Ok(diffs)
+    }
+
+    /// Compare `new_data`, which is already in memory, with these hashes in the same way as `diff_and_update()`,
+    /// and then update the hashes to match it.
+    ///
+    /// Since all of the data is available at once, the window is a slice of `new_data` rather than a pair of
+    /// buffers refilled from a reader, and matched blocks are skipped over without rolling the weak hash through them.
+    /// This makes it faster for large files, which `cargo bench --bench slice_diff` measures.  A file on disk can be memory mapped, with a crate such as
+    /// [memmap2](https://crates.io/crates/memmap2), and the map passed in as a slice.
+    ///
+    /// The diff and updated hashes are identical to those given by `diff_and_update()`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::BlockHashes;
+    /// use std::io::Cursor;
+    /// let mut hashes = BlockHashes::new(Cursor::new("It was the best of times"), 6).unwrap();
+    /// let diff = hashes.diff_and_update_slice(b"It was not the best of things").unwrap();
+    /// assert_eq!("It was not the best of things",
+    ///             diff.apply_to_string("It was the best of times").unwrap());
+    /// ```
+    pub fn diff_and_update_slice(&mut self, new_data: &[u8]) -> Result<Diff> {
+        let mut scanner = DiffScanner::new(self, true);
+        scanner.scan(new_data, true);
+        let (diff, new_hashes) = scanner.finish();
+        *self = new_hashes;
+        Ok(diff)
+    }
+
+    /// Compare `new_data`, which is already in memory, with these hashes in the same way as `diff_against()`,
+    /// without updating them.  See [`diff_and_update_slice()`](#method.diff_and_update_slice).
+    pub fn diff_against_slice(&self, new_data: &[u8]) -> Result<Diff> {
+        let mut scanner = DiffScanner::new(self, false);
+        scanner.scan(new_data, true);
+        let (diff, _) = scanner.finish();
+        Ok(diff)
+    }
+
+    /// Compare the data in `new_data` with these hashes, without updating them, and
+    /// also compute the hashes for `new_data`.
+    ///
+    /// The returned `BlockHashes` are identical to those that would be created by calling
+    /// `BlockHashes::new()` on `new_data`, but are computed in the same pass as the diff.
+    pub fn diff_and_signature<R: Read>(&self, new_data: R) -> Result<(Diff, BlockHashes<H>)> {
+        let mut new_hashes = HashMap::new();
+        let (diffs, file_size) = self.find_diff(new_data, Some(&mut new_hashes))?;
+        Ok((diffs, BlockHashes {
+            hashes: new_hashes,
+            block_size: self.block_size,
+            file_size,
+            weak_hash: PhantomData,
+            strong_hash: self.strong_hash,
+            strong_len: self.strong_len
+        }))
+    }
+
+    /// Compare the data in `new_data` with these hashes, without updating them, and produce
+    /// a `Delta` which rebuilds `new_data` from the old version of the file.
+    ///
+    /// Unlike `diff_against()`, blocks are matched wherever they appear in the old version,
+    /// so blocks that have been moved or reordered are copied rather than sent again.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::{BlockHashes, DeltaOp};
+    /// use std::io::Cursor;
+    /// let hashes = BlockHashes::new(Cursor::new("AAAAAAAABBBBBBBB"), 8).unwrap();
+    /// let delta = hashes.delta_against(Cursor::new("BBBBBBBB and AAAAAAAA")).unwrap();
+    /// assert_eq!(delta.ops().cloned().collect::<Vec<_>>(), vec![
+    ///     DeltaOp::Copy{base_offset: 8, len: 8},
+    ///     DeltaOp::Literal(b" and ".to_vec()),
+    ///     DeltaOp::Copy{base_offset: 0, len: 8}
+    /// ]);
+    /// ```
+    pub fn delta_against<R: Read>(&self, new_data: R) -> Result<Delta> {
+        let (delta, _) = self.find_delta(new_data, None)?;
+        Ok(delta)
+    }
+
+    /// Compare the data in `new_data` with the hashes computed from either the most recent
+    /// update or when this `BlockHashes` was created, and produce a `Delta` which rebuilds `new_data`
+    /// from the old version of the file.  The hashes are then updated to match `new_data`.
+    ///
+    /// This is the counterpart to `diff_and_update()` for when the file may have been reorganised.
+    /// A `Diff` can only express blocks which stay in order, so swapping two paragraphs would look
+    /// like one being deleted and inserted again.  The `Delta` copies the paragraph from its old position
+    /// instead, and [`Delta::moves()`](struct.Delta.html#method.moves) reports which blocks were moved.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::BlockHashes;
+    /// use std::io::Cursor;
+    /// let mut hashes = BlockHashes::new(Cursor::new("First para.\nSecond one.\n"), 4).unwrap();
+    /// let delta = hashes.delta_and_update(Cursor::new("Second one.\nFirst para.\n")).unwrap();
+    /// let moves = delta.moves();
+    /// assert_eq!(moves.len(), 1);
+    /// assert_eq!(moves[0].get_base_offset(), 12);
+    /// assert_eq!(moves[0].get_new_offset(), 0);
+    /// assert_eq!(moves[0].get_length(), 12);
+    /// ```
+    pub fn delta_and_update<R: Read>(&mut self, new_data: R) -> Result<Delta> {
+        let mut new_hashes = HashMap::new();
+        let (delta, file_size) = self.find_delta(new_data, Some(&mut new_hashes))?;
+        self.hashes = new_hashes;
+        self.file_size = file_size;
+        Ok(delta)
+    }
+
+    /// Finds a delta which rebuilds the data in `new_data` from the blocks these hashes were created from.
+    /// If `new_hashes` is given, the hashes of every block in `new_data` are recorded into it as well.
+    ///
+    /// Returns the delta, along with the number of bytes read from `new_data`
+    fn find_delta<R: Read>(&self, new_data: R, mut new_hashes: Option<&mut BlockHashMap>) -> Result<(Delta, usize)> {
+        use std::mem;
+        let mut delta = Delta::new();
+        let mut window = Window::new(new_data, self.block_size)?;
+        let mut weak_hasher = H::new(window.frame().0.iter());
+        let mut strong_hasher = StrongHasher::new(self.strong_hash, self.strong_len);
+        let mut literal_buffer = Vec::new();
+        let mut next_block_index = None;
+        let mut current_block_index = 0;
+        while window.frame_size() > 0 {
+            let len = if let Some(block_index) = self.find_block(&weak_hasher, &mut strong_hasher, &window, next_block_index) {
+                delta.add_literal(mem::take(&mut literal_buffer));
+                let (front, back) = window.frame();
+                let len = front.len() + back.len();
+                delta.add_copy(block_index * self.block_size, len);
+                next_block_index = Some(block_index + 1);
+                len
+            } else {
+                next_block_index = None;
+                1
+            };
+            // advance past the entire matched block, or a single byte if there was no match
+            for _ in 0..len {
+                if window.on_boundry() {
+                    if let Some(ref mut new_hashes) = new_hashes {
+                        let (front, back) = window.frame();
+                        let strong_hash = strong_hasher.hash(front, back).to_vec();
+
+                        new_hashes.entry(weak_hasher.get_hash()).or_insert_with(Vec::new).push((current_block_index, strong_hash));
+                    }
+                    current_block_index += 1;
+                }
+                let (tail, head) = window.advance()?;
+                weak_hasher.roll_hash(head, tail.unwrap());
+                if next_block_index.is_none() {
+                    literal_buffer.push(tail.unwrap());
+                }
+            }
+        }
+        delta.add_literal(literal_buffer);
+        Ok((delta, window.get_bytes_read()))
+    }
+
+    /// Finds the difference between the data in `new_data` and these hashes.  If `new_hashes`
+    /// is given, the hashes of every block in `new_data` are recorded into it as well.
+    ///
+    /// Returns the diff, along with the number of bytes read from `new_data`
+    fn find_diff<R: Read>(&self, new_data: R, mut new_hashes: Option<&mut BlockHashMap>) -> Result<(Diff, usize)> {
         use std::mem;
         let mut diffs = Diff::new();
-        let mut window = try!(Window::new(new_data, self.block_size));
-        let mut weak_hasher = RollingHash::new(window.frame().0.iter());
-        let mut strong_hasher = Md5::new();
-        let mut last_matching_block_index = -1;
+        let mut window = Window::new(new_data, self.block_size)?;
+        let mut weak_hasher = H::new(window.frame().0.iter());
+        let mut strong_hasher = StrongHasher::new(self.strong_hash, self.strong_len);
+        let mut last_matching_block_index = None;
         let mut insert_buffer = Vec::new();
-        let mut new_hashes = HashMap::new();
         let mut current_block_index = 0;
+        // Inserts are performed before deletes, so their positions have to include everything deleted before them
+        let mut deleted = 0;
         while window.frame_size() > 0 {
 
-            if let Some(other_block_index) = self.check_match(&weak_hasher, &mut strong_hasher, &mut window, &mut last_matching_block_index) {
+            if let Some(other_block_index) = self.check_match(&weak_hasher, &mut strong_hasher, window.frame(), last_matching_block_index) {
                 //create an insert if the insert buffer has anything in it
-                if insert_buffer.len() > 0 {
+                if !insert_buffer.is_empty() {
                     // XXX with some work here, we could probably track the insert buffer as a piece of the window, which is then
                     // moved into the diff list.
-                    diffs.add_insert(window.get_bytes_read() - insert_buffer.len(), mem::replace(&mut insert_buffer, Vec::new()));
+                    diffs.add_insert(window.get_bytes_read() - insert_buffer.len() + deleted, mem::take(&mut insert_buffer));
                 }
                 //create a delete if the index is more than it should be
-                if other_block_index as i32 > last_matching_block_index + 1 {
-                    diffs.add_delete(window.get_bytes_read(), self.block_size * (other_block_index as i32 - last_matching_block_index - 1) as usize)
+                let next_block_index = last_matching_block_index.map_or(0, |last| last + 1);
+                if other_block_index > next_block_index {
+                    let len = self.block_size * (other_block_index - next_block_index);
+                    diffs.add_delete(window.get_bytes_read(), len);
+                    deleted += len;
                 }
-                last_matching_block_index = other_block_index as i32;
+                last_matching_block_index = Some(other_block_index);
                 //advance forward an entire block's worth
                 for i in 0..self.block_size {
                     if window.on_boundry() {
@@ -167,22 +886,22 @@ impl BlockHashes {
                         if window.frame_size() == 0 {
                             break;
                         }
-                        let mut strong_hash:[u8;16] = [0;16];
-                        // If the boundry happened where we saw a match, we can skip the
-                        // strong hashing, because it was already done during the
-                        // match checking
-                        if i != 0 {
-                            let (front, back) = window.frame();
-                            strong_hasher.reset();
-                            strong_hasher.input(front);
-                            strong_hasher.input(back);
-                        }
-                        strong_hasher.result(&mut strong_hash);
+                        if let Some(ref mut new_hashes) = new_hashes {
+                            // If the boundry happened where we saw a match, we can skip the
+                            // strong hashing, because it was already done during the
+                            // match checking
+                            let strong_hash = if i != 0 {
+                                let (front, back) = window.frame();
+                                strong_hasher.hash(front, back).to_vec()
+                            } else {
+                                strong_hasher.last_hash().to_vec()
+                            };
 
-                        new_hashes.entry(weak_hasher.get_hash()).or_insert(Vec::new()).push((current_block_index, strong_hash));
+                            new_hashes.entry(weak_hasher.get_hash()).or_insert_with(Vec::new).push((current_block_index, strong_hash));
+                        }
                         current_block_index += 1;
                     }
-                    let (tail, head) = try!(window.advance());
+                    let (tail, head) = window.advance()?;
                     if let Some(tail) = tail {
                         weak_hasher.roll_hash(head, tail);
                     } else {
@@ -192,34 +911,30 @@ impl BlockHashes {
             } else {
                 //advance forward one byte
                 if window.on_boundry() {
-                    // XXX There is a slight optimization possible here, where
-                    // when the weak checksum matches, but the strong one doesn't
-                    // we are re-computing the strong checksum here.
-                    let mut strong_hash:[u8;16] = [0;16];
-                    let (front, back) = window.frame();
-                    strong_hasher.reset();
-                    strong_hasher.input(front);
-                    strong_hasher.input(back);
-                    strong_hasher.result(&mut strong_hash);
-
-                    new_hashes.entry(weak_hasher.get_hash()).or_insert(Vec::new()).push((current_block_index, strong_hash));
+                    if let Some(ref mut new_hashes) = new_hashes {
+                        // XXX There is a slight optimization possible here, where
+                        // when the weak checksum matches, but the strong one doesn't
+                        // we are re-computing the strong checksum here.
+                        let (front, back) = window.frame();
+                        let strong_hash = strong_hasher.hash(front, back).to_vec();
+
+                        new_hashes.entry(weak_hasher.get_hash()).or_insert_with(Vec::new).push((current_block_index, strong_hash));
+                    }
                     current_block_index += 1;
                 }
-                let (tail, head) = try!(window.advance());
+                let (tail, head) = window.advance()?;
                 weak_hasher.roll_hash(head, tail.unwrap());
                 insert_buffer.push(tail.unwrap());
             }
         }
-        if insert_buffer.len() > 0 {
-            diffs.add_insert(window.get_bytes_read() - insert_buffer.len(), insert_buffer);
+        if !insert_buffer.is_empty() {
+            diffs.add_insert(window.get_bytes_read() - insert_buffer.len() + deleted, insert_buffer);
         }
-        let old_block_count = (self.file_size + self.block_size - 1) as i32 / self.block_size as i32;
-        if last_matching_block_index + 1 < old_block_count {
-            diffs.add_delete(window.get_bytes_read(), (self.file_size as i32 - (last_matching_block_index + 1) * self.block_size as i32) as usize);
+        let next_block_index = last_matching_block_index.map_or(0, |last| last + 1);
+        if next_block_index < self.get_block_count() {
+            diffs.add_delete(window.get_bytes_read(), self.file_size - next_block_index * self.block_size);
         }
-        self.hashes = new_hashes;
-        self.file_size = window.get_bytes_read();
-        Ok(diffs)
+        Ok((diffs, window.get_bytes_read()))
     }
 
     /// Checks if `data_source` has changed since the last time the hashes were updated.
@@ -228,18 +943,15 @@ impl BlockHashes {
     pub fn verify_unchanged<R: Read>(&self, data_source: &mut R) -> Result<bool> {
         let mut block = vec![0;self.block_size];
         let mut block_index = 0;
-        let mut strong_hasher = Md5::new();
+        let mut strong_hasher = StrongHasher::new(self.strong_hash, self.strong_len);
         let mut total_size = 0;
 
-        let mut read_size = try!(data_source.read(&mut block));
+        let mut read_size = read_block(data_source, &mut block)?;
         while read_size > 0 {
-            let weak_hash = RollingHash::hash_buffer(&block[..read_size]);
+            let weak_hash = H::hash_buffer(&block[..read_size]);
             if let Some(entry) = self.hashes.get(&weak_hash) {
-                let mut strong_hash:[u8;16] = [0;16];
-                strong_hasher.reset();
-                strong_hasher.input(&block[..read_size]);
-                strong_hasher.result(&mut strong_hash);
-                if !entry.contains(&(block_index, strong_hash)) {
+                let strong_hash = strong_hasher.hash(&block[..read_size], &[]);
+                if !entry.iter().any(|&(index, ref hash)| index == block_index && hash[..] == *strong_hash) {
                     return Ok(false);
                 }
             }
@@ -247,102 +959,177 @@ impl BlockHashes {
 
             block_index += 1;
             total_size += read_size;
-            read_size = try!(data_source.read(&mut block));
+            read_size = read_block(data_source, &mut block)?;
         }
         Ok(total_size == self.file_size)
     }
 
     /// Compress these Hashes and write to `writer`.  The output can then be expanded
     /// back into an equivilent Hash collection using `expand_from()`
+    ///
+    /// The hashes are written in a container which identifies them, records the hash algorithms used
+    /// and checks them for corruption.
     pub fn compress_to<W: Write>(&self, writer: &mut W) -> Result<()> {
-
-        let mut int_buf = [0;4];
-        NetworkEndian::write_u32(&mut int_buf, self.file_size as u32);
-        try!(writer.write(&int_buf));
-        NetworkEndian::write_u32(&mut int_buf, self.block_size as u32);
-        try!(writer.write(&int_buf));
-        let block_count = (self.file_size + self.block_size - 1) / self.block_size;
-        let dummy_hash = [0u8;16];
-        let mut sequential_hashes = Vec::with_capacity(block_count);
-        sequential_hashes.resize(block_count, (0, &dummy_hash));
-        for (weak_hash, entry) in self.hashes.iter() {
-            for &(index, ref strong_hash) in entry.iter() {
-                sequential_hashes[index] = (*weak_hash, strong_hash);
+        if let WeakHashAlgorithm::Custom(id) = H::ALGORITHM {
+            if id < 128 {
+                return Err(Error::InvalidParameter(format!("custom weak hashes must have an id from 128 to 255, not {}", id)));
             }
         }
-        for (weak, strong) in sequential_hashes {
+        let mut writer = ChecksumWriter::new(writer, PayloadKind::BlockHashes, (Some(H::ALGORITHM), Some(self.strong_hash)))?;
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u64(&mut long_buf, self.file_size as u64);
+        writer.write_all(&long_buf)?;
+        NetworkEndian::write_u64(&mut long_buf, self.block_size as u64);
+        writer.write_all(&long_buf)?;
+        writer.write_all(&[self.strong_len as u8])?;
+        for (weak, strong) in self.sequential_hashes() {
             NetworkEndian::write_u32(&mut int_buf, weak);
-            try!(writer.write(&int_buf));
-            try!(writer.write(strong));
+            writer.write_all(&int_buf)?;
+            writer.write_all(strong)?;
         }
+        writer.finish()?;
         Ok(())
     }
 
     /// Expand these hashes from previously compressed data in `reader`.  The data in reader
     /// should have been written using `compress_to()`
-    pub fn expand_from<R: Read>(reader: &mut R) -> Result<BlockHashes> {
+    ///
+    /// Gives an error if the data is not a set of hashes, has been corrupted, or was hashed with a different weak hash.
+    /// Hashes written by earlier versions of this crate, before containers were used, can be read with
+    /// [`expand_from_legacy()`](#method.expand_from_legacy).
+    pub fn expand_from<R: Read>(reader: &mut R) -> Result<BlockHashes<H>> {
+        let (mut reader, algorithms) = ChecksumReader::new(reader, PayloadKind::BlockHashes)?;
+        let (weak_hash, strong_hash) = match algorithms {
+            (Some(weak_hash), Some(strong_hash)) => (weak_hash, strong_hash),
+            _ => return Err(Error::Corrupt("no hash algorithm given".to_string()))
+        };
+        check_weak_hash::<H>(weak_hash)?;
+        let file_size = read_size(&mut reader, true)?;
+        let block_size = read_size(&mut reader, true)?;
+        let mut strong_len = [0];
+        reader.read_exact(&mut strong_len)?;
+        let strong_len = strong_len[0] as usize;
+        if strong_len == 0 || strong_len > strong_hash.output_len() {
+            return Err(Error::Corrupt("invalid strong hash length".to_string()));
+        }
+        let hashes = BlockHashes::expand_hashes_from(&mut reader, file_size, block_size, strong_hash, strong_len)?;
+        reader.finish()?;
+        Ok(hashes)
+    }
+
+    /// Reads the hash of each block of a file, once the sizes and algorithms are known
+    fn expand_hashes_from<R: Read>(reader: &mut R, file_size: usize, block_size: usize, strong_hash: StrongHashAlgorithm, strong_len: usize) -> Result<BlockHashes<H>> {
+        if block_size == 0 {
+            return Err(Error::Corrupt("invalid block size".to_string()));
+        }
         let mut int_buf = [0;4];
-        let mut strong_hash = [0u8;16];
-        try!(reader.read(&mut int_buf));
-        let file_size = NetworkEndian::read_u32(&mut int_buf) as usize;
-        try!(reader.read(&mut int_buf));
-        let block_size = NetworkEndian::read_u32(&mut int_buf) as usize;
-        let block_count = (file_size + block_size - 1) / block_size;
-        // Might be an overestimate, but not by more than a few
-        let mut hashes = HashMap::with_capacity(block_count);
+        let mut strong = vec![0; strong_len];
+        let block_count = file_size.div_ceil(block_size);
+        // Might be an overestimate, but not by more than a few.  The sizes haven't been checked against the
+        // amount of data yet, so don't trust them for too much.
+        let mut hashes = HashMap::with_capacity(min(block_count, 1 << 16));
 
         for block_index in 0..block_count {
-            try!(reader.read(&mut int_buf));
-            let weak_hash = NetworkEndian::read_u32(&mut int_buf);
-            try!(reader.read(&mut strong_hash));
-            hashes.entry(weak_hash).or_insert(Vec::new()).push((block_index, strong_hash));
+            reader.read_exact(&mut int_buf)?;
+            let weak = NetworkEndian::read_u32(&int_buf);
+            reader.read_exact(&mut strong)?;
+            hashes.entry(weak).or_insert_with(Vec::new).push((block_index, strong.clone()));
         }
         Ok(BlockHashes {
-            file_size: file_size,
-            block_size: block_size,
-            hashes: hashes
+            file_size,
+            block_size,
+            hashes,
+            weak_hash: PhantomData,
+            strong_hash,
+            strong_len
         })
     }
 
-    /// Checks if the current window frame matches any existing block with an index greater than the previously matched block.
-    ///
-    /// Returns the index of the matching block if it does
-    fn check_match<R: Read>(&self, weak_hasher: &RollingHash, mut strong_hasher: &mut Md5, mut window: &Window<R>, last_matching_block_index: &mut i32) -> Option<usize> {
-        if let Some(other_block_index) = self.hash_match(&weak_hasher, &mut strong_hasher, &mut window) {
-            if other_block_index as i32 > *last_matching_block_index {
-                return Some(other_block_index);
+    /// Gets the weak and strong hash of every block, in the order the blocks appear in the file
+    pub(crate) fn sequential_hashes(&self) -> Vec<(u32, &[u8])> {
+        let mut sequential_hashes = vec![(0, &[][..]); self.get_block_count()];
+        for (weak_hash, entry) in self.hashes.iter() {
+            for &(index, ref strong_hash) in entry.iter() {
+                sequential_hashes[index] = (*weak_hash, &strong_hash[..]);
             }
         }
-        None
+        sequential_hashes
     }
 
-    /// Checks to see if the hash of the current window frame matches an existing hash.
+    /// Checks if `frame`, the data in the current window split into two parts, matches any existing block with an index
+    /// greater than the previously matched block, or any block if none has matched yet.
+    /// If more than one block matches, the earliest one is chosen.
     ///
-    /// If so, returns the index of the matching block
-    fn hash_match<R: Read>(&self, weak_hasher: &RollingHash,  strong_hasher: &mut Md5, window: &Window<R>) -> Option<usize> {
-        let mut new_result = [0;16];
-        if let Some(matches) = self.hashes.get(&weak_hasher.get_hash()) {
-            for &(index, strong_hash) in matches.iter() {
-                strong_hasher.reset();
-                let (front, back) = window.frame();
-                strong_hasher.input(front);
-                strong_hasher.input(back);
-                strong_hasher.result(&mut new_result);
-                if new_result == strong_hash {
-                    return Some(index)
+    /// Returns the index of the matching block if it does
+    fn check_match(&self, weak_hasher: &H, strong_hasher: &mut StrongHasher, (front, back): (&[u8], &[u8]), last_matching_block_index: Option<usize>) -> Option<usize> {
+        let matches = self.hashes.get(&weak_hasher.get_hash())?;
+        let new_result = strong_hasher.hash(front, back);
+        matches.iter()
+            .filter(|&&(index, ref strong_hash)| Some(index) > last_matching_block_index && strong_hash[..] == *new_result)
+            .map(|&(index, _)| index)
+            .min()
+    }
+
+    /// Checks to see if the current window frame matches any existing block, regardless of its position.
+    /// If more than one block matches, `preferred_index` is chosen if it is among them, so that
+    /// runs of sequential blocks can be merged into a single copy.
+    ///
+    /// Returns the index of the matching block if there is one
+    fn find_block<R: Read>(&self, weak_hasher: &H, strong_hasher: &mut StrongHasher, window: &Window<R>, preferred_index: Option<usize>) -> Option<usize> {
+        let matches = self.hashes.get(&weak_hasher.get_hash())?;
+        let (front, back) = window.frame();
+        let new_result = strong_hasher.hash(front, back);
+        let mut found = None;
+        for &(index, ref strong_hash) in matches.iter() {
+            if strong_hash[..] == *new_result {
+                if Some(index) == preferred_index {
+                    return Some(index);
                 }
+                found = found.or(Some(index));
             }
         }
-        return None
+        found
+    }
+}
+
+// These are written out rather than derived, since deriving them would need the weak hash type itself to implement them
+
+impl<H: WeakHash> fmt::Debug for BlockHashes<H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BlockHashes")
+            .field("hashes", &self.hashes)
+            .field("block_size", &self.block_size)
+            .field("file_size", &self.file_size)
+            .field("weak_hash", &H::ALGORITHM)
+            .field("strong_hash", &self.strong_hash)
+            .field("strong_len", &self.strong_len)
+            .finish()
+    }
+}
+
+impl<H: WeakHash> PartialEq for BlockHashes<H> {
+    fn eq(&self, other: &BlockHashes<H>) -> bool {
+        self.hashes == other.hashes && self.block_size == other.block_size && self.file_size == other.file_size &&
+            self.strong_hash == other.strong_hash && self.strong_len == other.strong_len
+    }
+}
+
+/// Checks that hashes recorded as using `weak_hash` are being read with the matching type, `H`
+pub(crate) fn check_weak_hash<H: WeakHash>(weak_hash: WeakHashAlgorithm) -> Result<()> {
+    if weak_hash != H::ALGORITHM {
+        return Err(Error::InvalidParameter(format!("the hashes use the {:?} weak hash, not {:?}", weak_hash, H::ALGORITHM)));
     }
+    Ok(())
 }
 
 #[cfg(test)]
+#[allow(clippy::useless_vec)]
 mod test {
-    use super::super::{BlockHashes, Diff, Insert, Delete};
-    use super::{RollingHash};
+    use super::super::{Error, BlockHashes, RollingHash, RollSum, RabinKarp, Adler32, Buzhash, WeakHash, Delta, DeltaOp, Diff, Insert, Delete, Move, WeakHashAlgorithm, StrongHashAlgorithm};
     use std::io::{Cursor};
     use std::collections::HashMap;
+    use std::marker::PhantomData;
 
     macro_rules! check_diff {
         ($start: tt | $block_size: tt | $new: tt | $(($insert_pos : tt, $insert_value: tt)),* | $(($delete_pos: tt, $delete_len: tt)),*) => {
@@ -364,6 +1151,10 @@ mod test {
                     deletes: vec![$(Delete{position: $delete_pos, len: $delete_len}),*]
                 }, diff);
                 check_hashes(&hashes, $new);
+
+                let mut slice_hashes = BlockHashes::new(Cursor::new($start), $block_size).unwrap();
+                assert_eq!(slice_hashes.diff_and_update_slice($new.as_bytes()).unwrap(), diff);
+                assert_eq!(slice_hashes, hashes);
             }
         };
     }
@@ -409,6 +1200,31 @@ mod test {
 
     }
 
+    fn check_rolling<W: WeakHash>() {
+        let data: Vec<u8> = (0..100u32).map(|i| (i * i * 7 + 13) as u8).collect();
+        let block_size = 16;
+        let mut hash = W::new(data[..block_size].iter());
+        for start in 0..data.len() {
+            let end = if start + block_size < data.len() { start + block_size } else { data.len() };
+            assert_eq!(hash.get_hash(), W::hash_buffer(&data[start..end]));
+            hash.roll_hash(data.get(end).cloned(), data[start]);
+        }
+        assert_eq!(hash.get_hash(), W::hash_buffer(&[]));
+    }
+
+    #[test]
+    fn rolling_hashes() {
+        check_rolling::<RollingHash>();
+        check_rolling::<RollSum>();
+        check_rolling::<RabinKarp>();
+        check_rolling::<Adler32>();
+        check_rolling::<Buzhash>();
+        assert_eq!(Adler32::hash_buffer(b"Wikipedia"), 0x11e6_0398);
+        // The hashes of the first block in the librsync signature tests
+        assert_eq!(RollSum::hash_buffer(b"It was the best "), 0x3f120767);
+        assert_eq!(RabinKarp::hash_buffer(b"It was the best "), 0x706c6304);
+    }
+
     #[test]
     fn hash_blocks_init() {
         let test_string = "It was the best of times, it was the worst of times";
@@ -422,22 +1238,192 @@ mod test {
         // mes      : 42205509  - d2db8a610f8c7c0785d2d92a6e8c450e
         let hashes = BlockHashes::new(Cursor::new(test_string), 8).unwrap();
 
-        let mut expected_hashes:HashMap<u32, Vec<(usize, [u8;16])>> = HashMap::new();
-        expected_hashes.insert(202900156, vec![(0, [0xad, 0x72, 0x1d, 0x63, 0xc3, 0xda, 0xbb, 0x32, 0xcc, 0x90, 0x96, 0x82, 0x40, 0x71, 0xa9, 0x19])]);
-        expected_hashes.insert(211944123, vec![(1, [0x27, 0x12, 0xA2, 0x2D, 0xDA, 0x55, 0x85, 0x75, 0x8A, 0xEB, 0xC4, 0xD2, 0x98, 0x14, 0x2F, 0x8B])]);
-        expected_hashes.insert(225313559, vec![(2, [0x31, 0x60, 0x52, 0x34, 0x54, 0xfa, 0x59, 0xe4, 0xc1, 0x4b, 0xad, 0xf9, 0x43, 0x5d, 0x62, 0x12])]);
-        expected_hashes.insert(169083540, vec![(3, [0x5f, 0xa8, 0xfa, 0x65, 0x9a, 0xdc, 0x38, 0x99, 0x7b, 0xb3, 0x65, 0xf1, 0x76, 0x48, 0xea, 0x8a])]);
-        expected_hashes.insert(197788377, vec![(4, [0x6B, 0xF2, 0x9B, 0x2C, 0xD5, 0x03, 0x3E, 0xFC, 0x07, 0x9C, 0x2E, 0xA1, 0x27, 0xFD, 0x7B, 0x13])]);
-        expected_hashes.insert(217580249, vec![(5, [0x1c, 0x64, 0x81, 0x16, 0x71, 0xe4, 0x3e, 0xa5, 0xf8, 0x2d, 0xa6, 0xff, 0xc4, 0xa5, 0xbb, 0xee])]);
-        expected_hashes.insert(42205509,  vec![(6, [0xd2, 0xdb, 0x8a, 0x61, 0x0f, 0x8c, 0x7c, 0x07, 0x85, 0xd2, 0xd9, 0x2a, 0x6e, 0x8c, 0x45, 0x0e])]);
+        let mut expected_hashes:HashMap<u32, Vec<(usize, Vec<u8>)>> = HashMap::new();
+        expected_hashes.insert(202900156, vec![(0, vec![0xad, 0x72, 0x1d, 0x63, 0xc3, 0xda, 0xbb, 0x32, 0xcc, 0x90, 0x96, 0x82, 0x40, 0x71, 0xa9, 0x19])]);
+        expected_hashes.insert(211944123, vec![(1, vec![0x27, 0x12, 0xA2, 0x2D, 0xDA, 0x55, 0x85, 0x75, 0x8A, 0xEB, 0xC4, 0xD2, 0x98, 0x14, 0x2F, 0x8B])]);
+        expected_hashes.insert(225313559, vec![(2, vec![0x31, 0x60, 0x52, 0x34, 0x54, 0xfa, 0x59, 0xe4, 0xc1, 0x4b, 0xad, 0xf9, 0x43, 0x5d, 0x62, 0x12])]);
+        expected_hashes.insert(169083540, vec![(3, vec![0x5f, 0xa8, 0xfa, 0x65, 0x9a, 0xdc, 0x38, 0x99, 0x7b, 0xb3, 0x65, 0xf1, 0x76, 0x48, 0xea, 0x8a])]);
+        expected_hashes.insert(197788377, vec![(4, vec![0x6B, 0xF2, 0x9B, 0x2C, 0xD5, 0x03, 0x3E, 0xFC, 0x07, 0x9C, 0x2E, 0xA1, 0x27, 0xFD, 0x7B, 0x13])]);
+        expected_hashes.insert(217580249, vec![(5, vec![0x1c, 0x64, 0x81, 0x16, 0x71, 0xe4, 0x3e, 0xa5, 0xf8, 0x2d, 0xa6, 0xff, 0xc4, 0xa5, 0xbb, 0xee])]);
+        expected_hashes.insert(42205509,  vec![(6, vec![0xd2, 0xdb, 0x8a, 0x61, 0x0f, 0x8c, 0x7c, 0x07, 0x85, 0xd2, 0xd9, 0x2a, 0x6e, 0x8c, 0x45, 0x0e])]);
 
         assert_eq!(hashes, BlockHashes {
             hashes: expected_hashes,
             block_size: 8,
-            file_size: 51
+            file_size: 51,
+            weak_hash: PhantomData,
+            strong_hash: StrongHashAlgorithm::Md5,
+            strong_len: 16
         });
+
+        match BlockHashes::new(Cursor::new(test_string), 0) {
+            Err(Error::InvalidParameter(_)) => (),
+            result => panic!("expected an invalid parameter, got {:?}", result)
+        }
+    }
+
+    fn check_weak_hash<W: WeakHash>() {
+        let start = "abababababababababababababababab0000000000000000cdcdcdcdcdcdcdcd";
+        let new = "abababababababab0000000000000000x0000000000000000cdcdcdcdcdcdcdcdabab";
+        let mut hashes = BlockHashes::<W>::with_weak_hash(Cursor::new(start), 8).unwrap();
+        assert_eq!(hashes.get_weak_hash(), W::ALGORITHM);
+        let diff = hashes.diff_and_update(Cursor::new(new)).unwrap();
+        assert_eq!(diff.apply_to_string(start).unwrap(), new);
+        assert_eq!(hashes, BlockHashes::<W>::with_weak_hash(Cursor::new(new), 8).unwrap());
+
+        let mut compressed = Vec::new();
+        hashes.compress_to(&mut compressed).unwrap();
+        assert_eq!(BlockHashes::<W>::expand_from(&mut Cursor::new(compressed)).unwrap(), hashes);
+    }
+
+    #[test]
+    fn weak_hashes() {
+        check_weak_hash::<RollingHash>();
+        check_weak_hash::<RollSum>();
+        check_weak_hash::<RabinKarp>();
+        check_weak_hash::<Adler32>();
+        check_weak_hash::<Buzhash>();
+        check_weak_hash::<ByteSum>();
+    }
+
+    /// A weak hash implemented outside of the crate
+    struct ByteSum(u32);
+
+    impl WeakHash for ByteSum {
+        const ALGORITHM: WeakHashAlgorithm = WeakHashAlgorithm::Custom(200);
+
+        fn new<'a, I: Iterator<Item=&'a u8>>(initial_data: I) -> ByteSum {
+            ByteSum(initial_data.map(|&byte| byte as u32).sum())
+        }
+
+        fn get_hash(&self) -> u32 {
+            self.0
+        }
+
+        fn roll_hash(&mut self, new_byte: Option<u8>, old_byte: u8) {
+            self.0 = self.0 - old_byte as u32 + new_byte.map_or(0, |byte| byte as u32);
+        }
+    }
+
+    /// A custom weak hash using a number reserved for this crate
+    struct BadId;
+
+    impl WeakHash for BadId {
+        const ALGORITHM: WeakHashAlgorithm = WeakHashAlgorithm::Custom(10);
+
+        fn new<'a, I: Iterator<Item=&'a u8>>(_: I) -> BadId {
+            BadId
+        }
+
+        fn get_hash(&self) -> u32 {
+            0
+        }
+
+        fn roll_hash(&mut self, _: Option<u8>, _: u8) {
+        }
+    }
+
+    #[test]
+    fn mismatched_weak_hashes() {
+        let hashes = BlockHashes::<ByteSum>::with_weak_hash(Cursor::new("It was the best of times"), 8).unwrap();
+        assert_eq!(hashes.get_weak_hash(), WeakHashAlgorithm::Custom(200));
+        let mut compressed = Vec::new();
+        hashes.compress_to(&mut compressed).unwrap();
+        match BlockHashes::<RollingHash>::expand_from(&mut Cursor::new(&compressed)) {
+            Err(Error::InvalidParameter(_)) => (),
+            result => panic!("expected an invalid parameter, got {:?}", result)
+        }
+        match BlockHashes::<Buzhash>::expand_from(&mut Cursor::new(&compressed)) {
+            Err(Error::InvalidParameter(_)) => (),
+            result => panic!("expected an invalid parameter, got {:?}", result)
+        }
+
+        let hashes = BlockHashes::<BadId>::with_weak_hash(Cursor::new("It was the best of times"), 8).unwrap();
+        match hashes.compress_to(&mut Vec::new()) {
+            Err(Error::InvalidParameter(_)) => (),
+            result => panic!("expected an invalid parameter, got {:?}", result)
+        }
+    }
+
+    fn check_slice_diff<W: WeakHash>(old: &[u8], new: &[u8], block_size: usize) {
+        let mut hashes = BlockHashes::<W>::with_weak_hash(Cursor::new(old), block_size).unwrap();
+        let diff = hashes.diff_against(Cursor::new(new)).unwrap();
+        assert_eq!(hashes.diff_against_slice(new).unwrap(), diff);
+        assert_eq!(hashes.diff_and_update_slice(new).unwrap(), diff);
+        assert_eq!(hashes, BlockHashes::<W>::with_weak_hash(Cursor::new(new), block_size).unwrap());
+    }
+
+    #[test]
+    fn slice_diffs() {
+        let old: Vec<u8> = (0..2000u32).map(|i| (i * i / 7) as u8).collect();
+        let mut new = old[..300].to_vec();
+        new.extend_from_slice(b"something new");
+        new.extend_from_slice(&old[300..900]);
+        new.extend_from_slice(&old[1000..1500]);
+        new.extend_from_slice(&old[1200..1700]);
+        new.extend_from_slice(b"and the end");
+        for &block_size in [1, 7, 16, 64, 5000].iter() {
+            check_slice_diff::<RollingHash>(&old, &new, block_size);
+            check_slice_diff::<RollSum>(&old, &new, block_size);
+            check_slice_diff::<RabinKarp>(&old, &new, block_size);
+            check_slice_diff::<Adler32>(&old, &new, block_size);
+            check_slice_diff::<Buzhash>(&old, &new, block_size);
+            check_slice_diff::<RollingHash>(&new, &old, block_size);
+            check_slice_diff::<RollingHash>(&old, &[], block_size);
+            check_slice_diff::<RollingHash>(&[], &old, block_size);
+        }
+    }
+
+    #[test]
+    fn parallel_hashes() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * i / 13) as u8).collect();
+        for &(block_size, threads, batch_size) in [(8, 1, 64), (8, 3, 64), (7, 4, 100), (64, 8, 1_000_000), (10_000, 2, 64), (20_000, 3, 64)].iter() {
+            let expected = BlockHashes::new(Cursor::new(&data), block_size).unwrap();
+            assert_eq!(BlockHashes::with_algorithms_parallel(Cursor::new(&data), block_size, threads, batch_size, StrongHashAlgorithm::Md5, 16).unwrap(), expected);
+        }
+        let expected = BlockHashes::<Buzhash>::with_hashes(Cursor::new(&data), 16, StrongHashAlgorithm::Blake3, 8).unwrap();
+        assert_eq!(BlockHashes::<Buzhash>::with_algorithms_parallel(Cursor::new(&data), 16, 4, 160, StrongHashAlgorithm::Blake3, 8).unwrap(), expected);
+        assert_eq!(BlockHashes::new_parallel(Cursor::new(""), 8, 4).unwrap(), BlockHashes::new(Cursor::new(""), 8).unwrap());
+        assert_eq!(BlockHashes::new_parallel(Cursor::new(&data), 8, 4).unwrap(), BlockHashes::new(Cursor::new(&data), 8).unwrap());
+
+        match BlockHashes::new_parallel(Cursor::new(&data), 8, 0) {
+            Err(Error::InvalidParameter(_)) => (),
+            result => panic!("expected an invalid parameter, got {:?}", result)
+        }
     }
 
+    #[test]
+    fn compressing_hashes() {
+        let hashes = BlockHashes::new(Cursor::new("It was the best of times"), 8).unwrap();
+        let mut compressed = Vec::new();
+        hashes.compress_to(&mut compressed).unwrap();
+        assert_eq!(compressed.len(), 8 + 17 + 3 * 20 + 4);
+        assert_eq!(BlockHashes::<RollingHash>::expand_from(&mut Cursor::new(compressed.clone())).unwrap(), hashes);
+        assert!(BlockHashes::<RollingHash>::expand_from(&mut Cursor::new(&compressed[..50])).is_err());
+
+        // The layout used before containers, with 32 bit sizes
+        let block_hashes = &compressed[25..85];
+        let mut narrow = vec![0, 0, 0, 24, 0, 0, 0, 8];
+        narrow.extend_from_slice(block_hashes);
+        assert_eq!(BlockHashes::expand_from_legacy(&mut Cursor::new(narrow.clone())).unwrap(), hashes);
+        assert_eq!(BlockHashes::<RollingHash>::expand_from(&mut Cursor::new(narrow)).unwrap_err().to_string(), "not an rdiff file");
+
+        // Any kind of hashes can be compressed
+        let hashes = BlockHashes::<RabinKarp>::new_librsync(Cursor::new("It was the best of times"), 8, 12).unwrap();
+        let mut compressed = Vec::new();
+        hashes.compress_to(&mut compressed).unwrap();
+        assert_eq!(BlockHashes::expand_from(&mut Cursor::new(compressed)).unwrap(), hashes);
+        for strong_hash in [StrongHashAlgorithm::Md5, StrongHashAlgorithm::Blake3, StrongHashAlgorithm::Sha256].iter() {
+            let hashes = BlockHashes::with_strong_hash(Cursor::new("It was the best of times"), 8, *strong_hash, 6).unwrap();
+            let mut compressed = Vec::new();
+            hashes.compress_to(&mut compressed).unwrap();
+            assert_eq!(compressed.len(), 8 + 17 + 3 * 10 + 4);
+            let expanded = BlockHashes::expand_from(&mut Cursor::new(compressed)).unwrap();
+            assert_eq!(expanded.get_strong_hash(), *strong_hash);
+            assert_eq!(expanded.get_strong_len(), 6);
+            assert_eq!(expanded, hashes);
+        }
+    }
 
     #[test]
     fn empty_hashes() {
@@ -459,6 +1445,24 @@ mod test {
                 );
     }
 
+    #[test]
+    fn repeated_blocks() {
+        // Both blocks have the same hashes, so the second block of the new data has to match
+        // the second block of the old data, even though the first block is found first
+        check_diff!("It was It was " |
+                    7 |
+                    "It was It was " |
+                    |
+
+                );
+        check_diff!("It was It was the best" |
+                    7 |
+                    "It was It was " |
+                    |
+                    (14, 8)
+                );
+    }
+
     #[test]
     fn multiple_overwrites() {
         check_diff!("" |
@@ -572,4 +1576,101 @@ mod test {
                 );
 
     }
+
+    #[test]
+    fn insert_after_delete() {
+        // Inserts are performed before deletes, so an insert after a deleted block has to be
+        // positioned as though the block were still there
+        check_diff!("AAAABBBBCCCC" |
+                    4 |
+                    "AAAACCCCxx" |
+                    (12, "xx") |
+                    (4, 4)
+                );
+        let hashes = BlockHashes::new(Cursor::new("AAAABBBBCCCC"), 4).unwrap();
+        let diff = hashes.diff_against(Cursor::new("AAAACCCCxx")).unwrap();
+        assert_eq!(diff.apply_to_string("AAAABBBBCCCC").unwrap(), "AAAACCCCxx");
+    }
+
+    #[test]
+    fn large_trailing_delete() {
+        // Only the first block has a hash, but the old file is treated as being over 4 GiB long
+        let mut hashes = BlockHashes::new(Cursor::new("AAAA"), 4).unwrap();
+        hashes.file_size = 5_000_000_000;
+        let mut expected = Diff::new();
+        expected.add_insert(4, b"xx".to_vec());
+        expected.add_delete(6, 4_999_999_996);
+        assert_eq!(hashes.diff_against(Cursor::new("AAAAxx")).unwrap(), expected);
+        assert_eq!(hashes.diff_against_slice(b"AAAAxx").unwrap(), expected);
+    }
+
+    #[test]
+    fn diff_without_update() {
+        let original = "Starting data is a long sentence";
+        let hashes = BlockHashes::new(Cursor::new(original), 8).unwrap();
+        let first = hashes.diff_against(Cursor::new("This Starting data is now a long sentence")).unwrap();
+        let second = hashes.diff_against(Cursor::new("Starting a long sentence")).unwrap();
+        assert_eq!(first.apply_to_string(original).unwrap(), "This Starting data is now a long sentence");
+        assert_eq!(second.apply_to_string(original).unwrap(), "Starting a long sentence");
+        check_hashes(&hashes, original);
+
+        let (diff, new_hashes) = hashes.diff_and_signature(Cursor::new("Starting data a long sentence")).unwrap();
+        assert_eq!(diff.apply_to_string(original).unwrap(), "Starting data a long sentence");
+        check_hashes(&hashes, original);
+        check_hashes(&new_hashes, "Starting data a long sentence");
+    }
+
+    fn check_delta(start: &'static str, block_size: usize, new: &'static str, expected: Vec<DeltaOp>) {
+        let hashes = BlockHashes::new(Cursor::new(start), block_size).unwrap();
+        let delta = hashes.delta_against(Cursor::new(new)).unwrap();
+        assert_eq!(delta.ops().cloned().collect::<Vec<_>>(), expected);
+        let mut result = Vec::new();
+        delta.apply(&mut Cursor::new(start), &mut result).unwrap();
+        assert_eq!(String::from_utf8(result).unwrap(), new);
+        check_hashes(&hashes, start);
+    }
+
+    #[test]
+    fn deltas() {
+        check_delta("", 8, "", vec![]);
+        check_delta("", 8, "New Data", vec![DeltaOp::Literal(b"New Data".to_vec())]);
+        check_delta("Same Data", 8, "Same Data", vec![DeltaOp::Copy{base_offset: 0, len: 9}]);
+        check_delta("Starting data is a long sentence", 8, "This Starting data is now a long sentence", vec![
+            DeltaOp::Literal(b"This ".to_vec()),
+            DeltaOp::Copy{base_offset: 0, len: 16},
+            DeltaOp::Literal(b" now".to_vec()),
+            DeltaOp::Copy{base_offset: 16, len: 16},
+        ]);
+        check_delta("Starting data is a long sentence", 8, "Starting data is a long ", vec![DeltaOp::Copy{base_offset: 0, len: 24}]);
+        // Moved and duplicated blocks are copied rather than inserted
+        check_delta("AAAAAAAABBBBBBBBCCCCCCCC", 8, "CCCCCCCCAAAAAAAABBBBBBBBCCCCCCCC", vec![
+            DeltaOp::Copy{base_offset: 16, len: 8},
+            DeltaOp::Copy{base_offset: 0, len: 24},
+        ]);
+        check_delta("One block.Two block.Red block.", 10, "Red block.Two block.One block.", vec![
+            DeltaOp::Copy{base_offset: 20, len: 10},
+            DeltaOp::Copy{base_offset: 10, len: 10},
+            DeltaOp::Copy{base_offset: 0, len: 10},
+        ]);
+        let empty = Delta::new();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn delta_with_update() {
+        let mut hashes = BlockHashes::new(Cursor::new("AAAAAAAABBBBBBBBCCCCCCCC"), 8).unwrap();
+        let delta = hashes.delta_and_update(Cursor::new("CCCCCCCCAAAAAAAAxBBBBBBBB")).unwrap();
+        assert_eq!(delta.ops().cloned().collect::<Vec<_>>(), vec![
+            DeltaOp::Copy{base_offset: 16, len: 8},
+            DeltaOp::Copy{base_offset: 0, len: 8},
+            DeltaOp::Literal(b"x".to_vec()),
+            DeltaOp::Copy{base_offset: 8, len: 8},
+        ]);
+        assert_eq!(delta.moves(), vec![Move::new(16, 0, 8)]);
+        check_hashes(&hashes, "CCCCCCCCAAAAAAAAxBBBBBBBB");
+
+        let delta = hashes.delta_and_update(Cursor::new("AAAAAAAAxBBBBBBBB")).unwrap();
+        assert_eq!(delta.ops().cloned().collect::<Vec<_>>(), vec![DeltaOp::Copy{base_offset: 8, len: 17}]);
+        check_hashes(&hashes, "AAAAAAAAxBBBBBBBB");
+    }
 }
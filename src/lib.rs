@@ -3,6 +3,23 @@
 //! Based on the rsync algorithm.
 //! The `BlockHashes` struct will find the differences between versions of the same file.
 //! It does this through the [`diff_and_update()`](struct.BlockHashes.html#method.diff_and_update) method.
+//! To compare against a set of hashes without replacing them, such as hashes received from another machine,
+//! use [`diff_against()`](struct.BlockHashes.html#method.diff_against) instead.
+//! [`delta_against()`](struct.BlockHashes.html#method.delta_against) instead produces a [`Delta`](struct.Delta.html),
+//! which rebuilds the new version from copies of blocks anywhere in the old version, plus literal data.
+//! Hashes and deltas can also be read and written in the formats used by librsync's `rdiff` tool, starting with
+//! [`BlockHashes::new_librsync()`](struct.BlockHashes.html#method.new_librsync).
+//! [`ChunkHashes`](struct.ChunkHashes.html) splits files into chunks at points chosen by their content instead of at fixed
+//! block boundaries, so that inserting data near the start of a file doesn't change the hashes of everything after it.
+//! Diffs made to the same version of a file at different sites can be brought together with an [`Engine`](struct.Engine.html).
+//! A diff of some text can be shown as a GNU unified diff with [`Diff::to_unified()`](struct.Diff.html#method.to_unified),
+//! and a unified diff made by another tool can be read with [`Diff::from_unified()`](struct.Diff.html#method.from_unified).
+//!
+//! With the `async` feature, hashes can be created and diffs found, applied and compressed using tokio's `AsyncRead`
+//! and `AsyncWrite`, starting with [`BlockHashes::new_async()`](struct.BlockHashes.html#method.new_async).
+//! With the `serde` feature, `Diff`s, their inserts and deletes, and `BlockHashes` can be serialized.  In human readable
+//! formats such as JSON, inserted data which is valid UTF-8 is written as a string.  Diffs and hashes are checked when
+//! they are deserialized, in the same way as when they are expanded.
 //!
 //! # Example
 //!
@@ -18,46 +35,286 @@
 //!
 //! This crate also contains methods relating to finding the differences between two strings, in the [string_diff](string_diff/index.html) module.
 //! These methods can be used to refine the course differences found through the rsync method.
+//! Two diffs made against the same version of a file can be combined with the [merge](merge/index.html) module.
 
 #![deny(missing_docs)]
 extern crate crypto;
 extern crate byteorder;
+extern crate crc32fast;
+extern crate blake3;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "serde")]
+extern crate serde;
 #[macro_use]
 extern crate log;
 
 mod window;
 mod hashing;
+mod delta;
+mod librsync;
+mod atomic;
+mod ops;
+mod engine;
+mod container;
+mod strong_hash;
+mod chunking;
+mod error;
+mod unified;
+#[cfg(feature = "async")]
+mod async_io;
+#[cfg(feature = "serde")]
+mod serialization;
 pub mod string_diff;
+pub mod merge;
 
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{self, Read, Write, Seek, SeekFrom};
 use std::slice::Iter;
 use std::fmt;
-use std::mem;
+use std::cmp::min;
+use std::marker::PhantomData;
 use std::string::FromUtf8Error;
 
 use byteorder::{NetworkEndian, ByteOrder};
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+/// The magic number at the start of every container written by `compress_to()`
+const CONTAINER_MAGIC: [u8; 4] = *b"rdif";
+
+/// The version of the container format written by `compress_to()`
+const CONTAINER_VERSION: u8 = 1;
+
+/// The errors which can occur when creating, reading or applying diffs and hashes
+#[derive(Debug)]
+pub enum Error {
+    /// There was a problem reading or writing data
+    Io(io::Error),
+    /// The data ended before everything that was expected had been read
+    Truncated,
+    /// The data is not in the expected format, or has been damaged
+    Corrupt(String),
+    /// An operation refers to this position, which is past the end of the data it is being applied to
+    PositionOutOfRange(usize),
+    /// Applying a diff to a string gave a result which is not valid UTF-8
+    InvalidUtf8(FromUtf8Error),
+    /// The data was written in this version of the format, which this version of the crate can't read
+    UnsupportedVersion(u8),
+    /// The data uses a feature which this crate doesn't support
+    Unsupported(String),
+    /// A parameter was outside of the range of allowed values
+    InvalidParameter(String),
+    /// A patch's context or removed lines don't match the text it is being applied to, starting at this line
+    /// (counting from 1)
+    ContextMismatch(usize)
+}
+
+/// The result of any operation which can fail with an [`Error`](enum.Error.html)
+pub type Result<T> = std::result::Result<T, Error>;
 
 /// Used for calculating and re-calculating the differences between two versions of the same file
 ///
+/// `H` is the rolling hash used as the weak hash of each block, which is rolled through new data to find
+/// the blocks in it.  Unless another [`WeakHash`](trait.WeakHash.html) is chosen, it is the original sum used by rdiff.
+///
 /// See the [module level documentation](index.html) for examples on how to use this
-#[derive(Debug, PartialEq)]
-pub struct BlockHashes {
-    hashes: HashMap<u32, Vec<(usize, [u8; 16])>>,
+pub struct BlockHashes<H: WeakHash = RollingHash> {
+    hashes: HashMap<u32, Vec<(usize, Vec<u8>)>>,
     block_size: usize,
-    file_size: usize
+    file_size: usize,
+    weak_hash: PhantomData<fn() -> H>,
+    strong_hash: StrongHashAlgorithm,
+    strong_len: usize
+}
+
+/// The rolling checksum used as the weak hash of each block.  The algorithm is recorded
+/// when the hashes are compressed, so that they can only be expanded with the same [`WeakHash`](trait.WeakHash.html).
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum WeakHashAlgorithm {
+    /// The original sum used by rdiff, implemented by [`RollingHash`](struct.RollingHash.html)
+    Rdiff,
+    /// librsync's rollsum, implemented by [`RollSum`](struct.RollSum.html)
+    RollSum,
+    /// librsync's Rabin-Karp polynomial hash, implemented by [`RabinKarp`](struct.RabinKarp.html)
+    RabinKarp,
+    /// Adler-32, implemented by [`Adler32`](struct.Adler32.html)
+    Adler32,
+    /// Buzhash, implemented by [`Buzhash`](struct.Buzhash.html)
+    Buzhash,
+    /// A hash implemented outside of this crate, identified by a number from 128 to 255 chosen by its implementor
+    Custom(u8)
+}
+
+/// A weak, but easy to calculate hash for a block of bytes, which can be rolled forward
+/// through a file one byte at a time.
+///
+/// Which hash works best depends on the data.  The sums are the cheapest to calculate, but collide often
+/// on repetitive data, where the strong hash then has to be calculated far more often.
+///
+/// Other hashes can be used by implementing this trait, and creating the hashes with
+/// [`BlockHashes::with_weak_hash()`](struct.BlockHashes.html#method.with_weak_hash).
+///
+/// # Example
+///
+/// ```
+/// use rdiff::{BlockHashes, WeakHash, WeakHashAlgorithm};
+/// use std::io::Cursor;
+///
+/// /// The sum of the bytes in the block
+/// struct ByteSum(u32);
+///
+/// impl WeakHash for ByteSum {
+///     const ALGORITHM: WeakHashAlgorithm = WeakHashAlgorithm::Custom(200);
+///
+///     fn new<'a, I: Iterator<Item=&'a u8>>(initial_data: I) -> ByteSum {
+///         ByteSum(initial_data.map(|&byte| byte as u32).sum())
+///     }
+///
+///     fn get_hash(&self) -> u32 {
+///         self.0
+///     }
+///
+///     fn roll_hash(&mut self, new_byte: Option<u8>, old_byte: u8) {
+///         self.0 = self.0 - old_byte as u32 + new_byte.map_or(0, |byte| byte as u32);
+///     }
+/// }
+///
+/// let mut hashes = BlockHashes::<ByteSum>::with_weak_hash(Cursor::new("The initial version"), 4).unwrap();
+/// let diff = hashes.diff_and_update(Cursor::new("The next version")).unwrap();
+/// assert_eq!(diff.apply_to_string("The initial version").unwrap(), "The next version");
+/// ```
+pub trait WeakHash {
+    /// The algorithm this type implements, which is recorded alongside the hashes
+    const ALGORITHM: WeakHashAlgorithm;
+
+    /// Creates a new rolling hash over the bytes in `initial_data`.
+    /// It will be assumed that the size of blocks will be the size of the initial data.
+    fn new<'a, I: Iterator<Item=&'a u8>>(initial_data: I) -> Self;
+
+    /// Gets the hash as it currently stands
+    fn get_hash(&self) -> u32;
+
+    /// Roll the has forward one byte.  This function will remove `old_byte` from its calculation
+    /// and add `new_byte` if it exists.
+    /// To get the hash afterwards, use `get_hash()`.
+    fn roll_hash(&mut self, new_byte: Option<u8>, old_byte: u8);
+
+    /// Calculate the hash of a collection of bytes.
+    fn hash_buffer(buffer: &[u8]) -> u32 where Self: Sized {
+        Self::new(buffer.iter()).get_hash()
+    }
+}
+
+/// Implements a weak, but easy to calculate hash for a block of bytes
+///
+/// The hash is comprised of two bytes.  The first is the sum of the bytes
+/// in the block, the second is the sum of the sum of the bytes in the block
+pub struct RollingHash {
+    a: u16,
+    b: u16,
+    block_size: u16
+}
+
+/// librsync's rollsum.  This is the same as `RollingHash`, except that a constant
+/// is added to every byte, so that runs of zeros still change the hash.
+pub struct RollSum {
+    s1: u16,
+    s2: u16,
+    count: u16
+}
+
+/// librsync's Rabin-Karp rolling hash.  This is a polynomial hash modulo 2^32, which
+/// distributes far better than the sums.
+pub struct RabinKarp {
+    hash: u32,
+    mult: u32
+}
+
+/// [Adler-32](https://en.wikipedia.org/wiki/Adler-32).  This is the same kind of sum as `RollingHash`,
+/// but taken modulo a prime, so that every bit of the hash depends on the data.
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+    count: u32
+}
+
+/// [Buzhash](https://en.wikipedia.org/wiki/Rolling_hash#Cyclic_polynomial), which replaces each byte with
+/// a random value and combines them with rotations and xor.  It copes well with data that only uses a
+/// few different byte values, like text.
+pub struct Buzhash {
+    hash: u32,
+    count: u32
+}
+
+/// The cryptographic hash used as the strong hash of each block.  The algorithm is recorded
+/// when the hashes are compressed, so that they can be checked the same way after they are expanded.
+///
+/// Only these algorithms are supported, since every reader of the compressed hashes needs to know
+/// how to calculate them.
+///
+/// See [`BlockHashes::with_strong_hash()`](struct.BlockHashes.html#method.with_strong_hash)
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum StrongHashAlgorithm {
+    /// MD5, as used by rdiff
+    Md5,
+    /// BLAKE2b with a 32 byte digest, as used by librsync
+    Blake2,
+    /// BLAKE3 with a 32 byte digest
+    Blake3,
+    /// SHA-256
+    Sha256
+}
+
+/// A cryptographic hash of a block of data.  Blocks with the same weak hash are only treated as
+/// the same data if their strong hashes match as well.
+pub(crate) trait StrongHash {
+    /// Adds `data` to the data being hashed
+    fn update(&mut self, data: &[u8]);
+
+    /// Writes the digest of all of the data added since the hash was last reset into `out`,
+    /// which must be `output_len()` bytes long, and then resets the hash.
+    fn finish_reset(&mut self, out: &mut [u8]);
+
+    /// Gets the length in bytes of the full digest
+    fn output_len(&self) -> usize;
+}
+
+/// [MD5](https://en.wikipedia.org/wiki/MD5), with a 16 byte digest
+pub(crate) struct Md5Hash {
+    digest: crypto::md5::Md5
+}
+
+/// [BLAKE2b](https://www.blake2.net/), with a 32 byte digest
+pub(crate) struct Blake2Hash {
+    digest: crypto::blake2b::Blake2b
+}
+
+/// [BLAKE3](https://github.com/BLAKE3-team/BLAKE3), with a 32 byte digest
+pub(crate) struct Blake3Hash {
+    hasher: blake3::Hasher
+}
+
+/// [SHA-256](https://en.wikipedia.org/wiki/SHA-2), with a 32 byte digest
+pub(crate) struct Sha256Hash {
+    digest: crypto::sha2::Sha256
 }
 
 /// Represents an operation to insert bytes at a particular position into a file
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Insert {
     position: usize,
+    #[cfg_attr(feature = "serde", serde(with = "serialization::insert_data"))]
     data: Vec<u8>
 }
 
 /// Represents an operation to delete a certain number of bytes at a particular position in a file
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Delete {
     position: usize,
     len: usize
@@ -70,11 +327,171 @@ pub struct Delete {
 /// an earlier part of the file must be stored before an operation that affects a later part.
 /// The diff also assumes that insert operations are performed prior to delete operations.
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(try_from = "serialization::DiffData"))]
 pub struct Diff {
     inserts: Vec<Insert>,
     deletes: Vec<Delete>
 }
 
+/// A single operation used to rebuild a new version of a file from an old version
+#[derive(Debug, PartialEq, Clone)]
+pub enum DeltaOp {
+    /// Copy `len` bytes from the old version of the file, starting at `base_offset`
+    Copy {
+        /// The byte position in the old version of the file to copy from
+        base_offset: usize,
+        /// The number of bytes to copy
+        len: usize
+    },
+    /// Write these bytes, which don't appear in the old version of the file
+    Literal(Vec<u8>)
+}
+
+/// Represents a new version of a file as a series of copies from the old version
+/// and literal data, in the style of librsync's deltas.
+///
+/// Unlike a [`Diff`](struct.Diff.html), copies can refer to any part of the old version,
+/// so blocks that were moved or duplicated don't need to be sent again.
+///
+/// The operations are stored in the order of the new version of the file.
+#[derive(Debug, PartialEq)]
+pub struct Delta {
+    ops: Vec<DeltaOp>
+}
+
+/// A run of bytes which was copied from a different place in the old version of a file than
+/// where it would be expected, if the file had only had data inserted and deleted.
+///
+/// See [`Delta::moves()`](struct.Delta.html#method.moves)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Move {
+    base_offset: usize,
+    new_offset: usize,
+    len: usize
+}
+
+/// Brings together diffs which were made to the same version of a file at the same time, at different sites,
+/// so that every site ends up with the same file.
+///
+/// Each site has its own `Engine`, identified by a site id which must be different from every other site's.
+/// When two sites insert data at the same place, the data from the site with the lower id comes first.
+///
+/// # Example
+///
+/// ```
+/// use rdiff::{BlockHashes, Engine};
+/// use std::io::Cursor;
+/// let base = "The fox jumped";
+/// let hashes = BlockHashes::new(Cursor::new(base), 4).unwrap();
+/// let ours = hashes.diff_against(Cursor::new("The quick fox jumped")).unwrap();
+/// let theirs = hashes.diff_against(Cursor::new("The fox jumped high")).unwrap();
+///
+/// // Each site applies its own diff first, followed by the other site's diff once it's been integrated
+/// let theirs_here = Engine::new(1).integrate_remote(&ours, &theirs, 2);
+/// let ours_there = Engine::new(2).integrate_remote(&theirs, &ours, 1);
+/// let here = theirs_here.apply_to_string(&ours.apply_to_string(base).unwrap()).unwrap();
+/// let there = ours_there.apply_to_string(&theirs.apply_to_string(base).unwrap()).unwrap();
+/// assert_eq!(here, "The quick fox jumped high");
+/// assert_eq!(here, there);
+/// ```
+#[derive(Debug, PartialEq)]
+pub struct Engine {
+    site_id: u32
+}
+
+/// A single step in walking through an old version of a file to produce the new version,
+/// used to transform diffs.
+///
+/// Any data in the old version past the end of the operations is retained.
+#[derive(Debug, PartialEq, Clone)]
+enum Op {
+    /// Keep this many bytes from the old version
+    Retain(usize),
+    /// Write this data, which is not in the old version
+    Insert(Vec<u8>),
+    /// Skip over this many bytes from the old version
+    Delete(usize)
+}
+
+/// The kind of data held in a container
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum PayloadKind {
+    Diff,
+    BlockHashes,
+    ChunkHashes
+}
+
+/// Writes a container around compressed data, keeping a checksum of everything written
+/// so that it can be added to the end.
+struct ChecksumWriter<W: Write> {
+    writer: W,
+    hasher: crc32fast::Hasher
+}
+
+/// Reads the data in a container, keeping a checksum of everything read so that it can be
+/// compared with the one at the end.
+struct ChecksumReader<R: Read> {
+    reader: R,
+    hasher: crc32fast::Hasher
+}
+
+/// The sizes of the chunks [`ChunkHashes`](struct.ChunkHashes.html) splits files into.
+///
+/// Chunks are never shorter than the minimum size, except at the end of the file, or longer than the maximum size.
+/// Chunk boundaries are chosen so that most chunks come out close to the average size.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ChunkSizes {
+    min: usize,
+    avg: usize,
+    max: usize
+}
+
+/// The hashes of the chunks of a file, which has been split at points chosen by its content using
+/// [FastCDC](https://www.usenix.org/conference/atc16/technical-sessions/presentation/xia).
+///
+/// Since the chunk boundaries only depend on the data around them, data inserted into or deleted from
+/// a file only changes the chunks around the change.  This makes these hashes well suited to finding
+/// data which is duplicated between versions, such as for backups.
+///
+/// # Example
+///
+/// ```
+/// use rdiff::{ChunkHashes, ChunkSizes};
+/// use std::io::Cursor;
+/// let old: Vec<u8> = (0..20000u32).map(|i| (i * i / 7) as u8).collect();
+/// let mut new = b"A new header".to_vec();
+/// new.extend_from_slice(&old);
+/// let hashes = ChunkHashes::new(Cursor::new(&old), ChunkSizes::new(256, 1024, 4096).unwrap()).unwrap();
+/// let delta = hashes.delta_against(Cursor::new(&new)).unwrap();
+/// let mut result = Vec::new();
+/// delta.apply(&mut Cursor::new(&old), &mut result).unwrap();
+/// assert_eq!(result, new);
+/// ```
+#[derive(Debug, PartialEq)]
+pub struct ChunkHashes {
+    /// The length and strong hash of every chunk, in file order
+    chunks: Vec<(usize, Vec<u8>)>,
+    /// The position in the file and length of the first chunk with each strong hash
+    positions: HashMap<Vec<u8>, (usize, usize)>,
+    sizes: ChunkSizes,
+    file_size: usize,
+    strong_hash: StrongHashAlgorithm,
+    strong_len: usize
+}
+
+/// Splits the data from a reader into content defined chunks
+struct Chunker<R: Read> {
+    reader: R,
+    buffer: Vec<u8>,
+    sizes: ChunkSizes,
+    /// The mask used to find a boundary before the average chunk size, which has more bits set
+    /// so that boundaries are less likely
+    small_mask: u64,
+    /// The mask used to find a boundary after the average chunk size, which has fewer bits set
+    large_mask: u64,
+    finished: bool
+}
+
 /// A sliding window over a reader.  This monatins an internal buffer read from the file,
 /// which can be read from at any time.
 struct Window<R: Read> {
@@ -125,12 +542,12 @@ impl Diff {
     }
 
     /// Gets an iterator over all insert operations
-    pub fn inserts(&self) -> Iter<Insert> {
+    pub fn inserts(&self) -> Iter<'_, Insert> {
         self.inserts.iter()
     }
 
     /// Gets an iterator over all delete operations
-    pub fn deletes(&self) -> Iter<Delete> {
+    pub fn deletes(&self) -> Iter<'_, Delete> {
         self.deletes.iter()
     }
 
@@ -140,123 +557,262 @@ impl Diff {
     }
 
     /// Applies all of the operations in the diff to the given string.
-    /// Gives an error if the resulting string can't be represented by utf8.
-    ///
-    /// # Panics
-    /// When the operations refer to positions that are not represented by the string.
-    pub fn apply_to_string(&self, string: &str) -> Result<String, FromUtf8Error> {
-        let mut old_bytes = string.bytes();
-        let mut new_bytes = Vec::new();
-        let mut index = 0;
-        for insert in self.inserts() {
-            while index < insert.position {
-                new_bytes.push(old_bytes.next().unwrap().clone());
-                index += 1;
-            }
-            new_bytes.append(&mut insert.data.clone());
-            index += insert.data.len();
-        }
-        while let Some(byte) = old_bytes.next() {
-            new_bytes.push(byte);
-        }
-        let old_bytes = mem::replace(&mut new_bytes, Vec::new());
-        let mut  old_bytes = old_bytes.into_iter();
-        index = 0;
-        for delete in self.deletes() {
-            while index < delete.position {
-                new_bytes.push(old_bytes.next().unwrap());
-                index += 1;
-            }
-            for _ in 0..delete.len {
-                old_bytes.next();
-            }
-        }
-        while let Some(byte) = old_bytes.next() {
-            new_bytes.push(byte);
-        }
-        String::from_utf8(new_bytes)
+    /// Gives an error if the operations refer to positions that are not represented by the string,
+    /// or if the resulting string can't be represented by utf8.
+    pub fn apply_to_string(&self, string: &str) -> Result<String> {
+        let mut new_bytes = Vec::with_capacity(string.len());
+        self.apply_stream(string.as_bytes(), &mut new_bytes)?;
+        Ok(String::from_utf8(new_bytes)?)
     }
 
     /// Apply the operations in this sequence to a file.  This should not be called until after
     /// the sequence has been integrated via [`Engine::integrate_remote`](struct.Engine.html#method.integrate_remote)
     /// The file must have been opened on both read and write mode (see [OpenOptions](https://doc.rust-lang.org/nightly/std/fs/struct.OpenOptions.html)).
-    pub fn apply(&self, file: &mut File) -> io::Result<()> {
-        let mut new_bytes = Vec::new();
-        try!(file.seek(SeekFrom::Start(0)));
-        let mut old_bytes = file.try_clone().unwrap().bytes();
+    ///
+    /// The entire file is read into memory, and rewritten in place.  For large files, use [`apply_stream()`](#method.apply_stream)
+    /// to write the new version somewhere else instead, or [`apply_atomic()`](#method.apply_atomic) to safely replace the file.
+    pub fn apply(&self, file: &mut File) -> Result<()> {
+        let mut old_bytes = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut old_bytes)?;
+        let mut new_bytes = Vec::with_capacity(old_bytes.len());
+        self.apply_stream(&old_bytes[..], &mut new_bytes)?;
+
+        file.seek(SeekFrom::Start(0))?;
+        file.set_len(new_bytes.len() as u64)?;
+        file.write_all(new_bytes.as_slice())?;
+        Ok(())
+    }
+
+    /// Apply the operations in this sequence to the data read from `base`, writing the result to `out`.
+    ///
+    /// The data is copied across in chunks as the operations are performed in file order, so only
+    /// a small, fixed amount of memory is used regardless of the size of `base`.
+    ///
+    /// Gives an error if the operations refer to positions past the end of `base`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::BlockHashes;
+    /// use std::io::Cursor;
+    /// let hashes = BlockHashes::new(Cursor::new("It was the best of times"), 6).unwrap();
+    /// let diff = hashes.diff_against(Cursor::new("It was not the best of things")).unwrap();
+    /// let mut result = Vec::new();
+    /// diff.apply_stream(Cursor::new("It was the best of times"), &mut result).unwrap();
+    /// assert_eq!(result, b"It was not the best of things");
+    /// ```
+    pub fn apply_stream<R: Read, W: Write>(&self, mut base: R, out: W) -> Result<()> {
+        self.check_order()?;
+        let mut out = DeleteFilter::new(out, &self.deletes);
         let mut index = 0;
         for insert in self.inserts.iter() {
-            while index < insert.position {
-                new_bytes.push(try!(old_bytes.next().unwrap()).clone());
-                index += 1;
-            }
-            new_bytes.extend_from_slice(&insert.data[..]);
-            index += insert.data.len();
-        }
-        while let Some(byte) = old_bytes.next() {
-            new_bytes.push(try!(byte));
-        }
-        let old_bytes = mem::replace(&mut new_bytes, Vec::new());
-        let mut old_bytes = old_bytes.into_iter();
-        index = 0;
-        for delete in self.deletes.iter() {
-            while index < delete.position {
-                new_bytes.push(old_bytes.next().unwrap());
-                index += 1;
+            let len = (insert.position - index) as u64;
+            if io::copy(&mut (&mut base).take(len), &mut out)? < len {
+                return Err(Error::PositionOutOfRange(insert.position));
             }
-            for _ in 0..delete.len {
-                old_bytes.next();
-            }
-        }
-        while let Some(byte) = old_bytes.next() {
-            new_bytes.push(byte);
+            out.write_all(&insert.data)?;
+            index = insert.position + insert.data.len();
         }
-
-        try!(file.seek(SeekFrom::Start(0)));
-        try!(file.set_len(new_bytes.len() as u64));
-        file.write_all(new_bytes.as_slice())
+        io::copy(&mut base, &mut out)?;
+        out.finish()
     }
 
     /// Compress this diff and write to `writer`.  The output can then be expanded
     /// back into an equivilent Diff using `expand_from()`
-    pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
-
-        let mut int_buf = [0;4];
-        NetworkEndian::write_u32(&mut int_buf, self.inserts.len() as u32);
-        try!(writer.write(&mut int_buf));
+    ///
+    /// The diff is written in a container which identifies it and checks it for corruption, with
+    /// positions and lengths written as 64 bit integers.
+    pub fn compress_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut writer = ChecksumWriter::new(writer, PayloadKind::Diff, (None, None))?;
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u64(&mut long_buf, self.inserts.len() as u64);
+        writer.write_all(&long_buf)?;
         for insert in self.inserts.iter() {
-            try!(insert.compress_to(writer));
+            insert.compress_to(&mut writer)?;
         }
-        NetworkEndian::write_u32(&mut int_buf, self.deletes.len() as u32);
-        try!(writer.write(&mut int_buf));
+        NetworkEndian::write_u64(&mut long_buf, self.deletes.len() as u64);
+        writer.write_all(&long_buf)?;
         for delete in self.deletes.iter() {
-            try!(delete.compress_to(writer));
+            delete.compress_to(&mut writer)?;
         }
+        writer.finish()?;
         Ok(())
     }
 
     /// Expand this diff from previously compressed data in `reader`.  The data in reader
     /// should have been written using `compress_to()`
-    pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<Diff> {
-        let mut int_buf = [0;4];
+    ///
+    /// Gives an error if the data is not a diff, or has been corrupted.  Diffs written by earlier
+    /// versions of this crate, before containers were used, can be read with `expand_from_legacy()`.
+    pub fn expand_from<R: Read>(reader: &mut R) -> Result<Diff> {
+        let (mut reader, _) = ChecksumReader::new(reader, PayloadKind::Diff)?;
+        let diff = Diff::expand_operations_from(&mut reader, true)?;
+        reader.finish()?;
+        diff.check_order()?;
+        Ok(diff)
+    }
 
+    /// Expand a diff written by `compress_to()` in earlier versions of this crate, which didn't use
+    /// a container and wrote positions and lengths as 32 bit integers.
+    ///
+    /// Nothing in that layout identifies it or detects corruption, so this should only be used for data
+    /// known to have been written that way.
+    pub fn expand_from_legacy<R: Read>(reader: &mut R) -> Result<Diff> {
+        let diff = Diff::expand_operations_from(reader, false)?;
+        diff.check_order()?;
+        Ok(diff)
+    }
+
+    /// Reads the inserts and deletes of a compressed diff, which have either 64 or 32 bit fields
+    fn expand_operations_from<R: Read>(reader: &mut R, wide: bool) -> Result<Diff> {
+        // The counts aren't used to reserve space, since a corrupted count could be enormous
         trace!("Reading insert length");
-        try!(reader.read_exact(&mut int_buf));
-        let insert_len = NetworkEndian::read_u32(&int_buf);
+        let insert_len = read_count(reader, wide)?;
         trace!("Insert length was: {}", insert_len);
-        let inserts = (0..insert_len).map(|_|Insert::expand_from(reader).unwrap()).collect();
+        let mut inserts = Vec::new();
+        for _ in 0..insert_len {
+            inserts.push(if wide {
+                Insert::expand_from(reader)?
+            } else {
+                Insert::expand_narrow_from(reader)?
+            });
+        }
         trace!("Read inserts");
         trace!("Reading delete length");
-        try!(reader.read_exact(&mut int_buf));
-        let delete_len = NetworkEndian::read_u32(&int_buf);
+        let delete_len = read_count(reader, wide)?;
         trace!("Delete length was: {}", delete_len);
-        let deletes = (0..delete_len).map(|_|Delete::expand_from(reader).unwrap()).collect();
+        let mut deletes = Vec::new();
+        for _ in 0..delete_len {
+            deletes.push(if wide {
+                Delete::expand_from(reader)?
+            } else {
+                Delete::expand_narrow_from(reader)?
+            });
+        }
         trace!("Read deletes");
         Ok(Diff {
-            inserts: inserts,
-            deletes: deletes
+            inserts,
+            deletes
         })
     }
+
+    /// Checks that the inserts are in file order without overlapping, and the deletes are in file order,
+    /// which is needed for them to be performed in a single pass
+    fn check_order(&self) -> Result<()> {
+        let mut index = 0;
+        for insert in self.inserts.iter() {
+            if insert.position < index {
+                return Err(Error::Corrupt("inserts are not in file order".to_string()));
+            }
+            index = insert.position.checked_add(insert.data.len()).ok_or(Error::PositionOutOfRange(insert.position))?;
+        }
+        let mut position = 0;
+        // The total length deleted so far, which is needed to find where each delete ends before any were performed
+        let mut shift: usize = 0;
+        for delete in self.deletes.iter() {
+            if delete.position < position {
+                return Err(Error::Corrupt("deletes are not in file order".to_string()));
+            }
+            position = delete.position;
+            shift = shift.checked_add(delete.len)
+                .filter(|&shift| position.checked_add(shift).is_some())
+                .ok_or(Error::PositionOutOfRange(position))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the number of operations in a compressed diff, which is either 64 or 32 bits
+fn read_count<R: Read>(reader: &mut R, wide: bool) -> Result<u64> {
+    if wide {
+        let mut long_buf = [0;8];
+        reader.read_exact(&mut long_buf)?;
+        Ok(NetworkEndian::read_u64(&long_buf))
+    } else {
+        let mut int_buf = [0;4];
+        reader.read_exact(&mut int_buf)?;
+        Ok(NetworkEndian::read_u32(&int_buf) as u64)
+    }
+}
+
+/// Passes data through to a writer, except for the ranges removed by a `Diff`'s delete operations.
+///
+/// The position of each delete is in terms of the data which has been written out so far,
+/// so each delete is performed as soon as that much data has been passed on.
+struct DeleteFilter<'a, W: Write> {
+    writer: W,
+    deletes: Iter<'a, Delete>,
+    next_delete: Option<&'a Delete>,
+    written: usize,
+    skipping: usize
+}
+
+impl<'a, W: Write> DeleteFilter<'a, W> {
+    fn new(writer: W, deletes: &'a [Delete]) -> DeleteFilter<'a, W> {
+        let mut deletes = deletes.iter();
+        let next_delete = deletes.next();
+        DeleteFilter {
+            writer,
+            deletes,
+            next_delete,
+            written: 0,
+            skipping: 0
+        }
+    }
+
+    /// Checks that every delete was performed, and flushes the underlying writer
+    fn finish(&mut self) -> Result<()> {
+        if self.skipping > 0 {
+            // The position doesn't move on while data is being skipped
+            return Err(Error::PositionOutOfRange(self.written));
+        }
+        if let Some(delete) = self.next_delete {
+            return Err(Error::PositionOutOfRange(delete.position));
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> Write for DeleteFilter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut remaining = buf;
+        while !remaining.is_empty() {
+            if self.skipping > 0 {
+                let skip_len = min(self.skipping, remaining.len());
+                self.skipping -= skip_len;
+                remaining = &remaining[skip_len..];
+                continue;
+            }
+            let pass_len = match self.next_delete {
+                Some(delete) if delete.position == self.written => {
+                    self.skipping = delete.len;
+                    self.next_delete = self.deletes.next();
+                    continue;
+                }
+                Some(delete) if delete.position < self.written => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "deletes are not in file order"));
+                }
+                Some(delete) => min(delete.position - self.written, remaining.len()),
+                None => remaining.len()
+            };
+            self.writer.write_all(&remaining[..pass_len])?;
+            self.written += pass_len;
+            remaining = &remaining[pass_len..];
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Default for Diff {
+    fn default() -> Diff {
+        Diff::new()
+    }
 }
 
 impl fmt::Debug for Insert {
@@ -295,31 +851,41 @@ impl Insert {
 
     /// Compress this operation and write to `writer`.  The output can then be expanded
     /// back into an equivilent operation using `expand_from()`
-    pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    pub fn compress_to<W: Write>(&self, writer: &mut W) -> Result<()> {
 
-        let mut int_buf = [0;4];
-        NetworkEndian::write_u32(&mut int_buf, self.position as u32);
-        try!(writer.write(&int_buf));
-        NetworkEndian::write_u32(&mut int_buf, self.data.len() as u32);
-        try!(writer.write(&int_buf));
-        try!(writer.write(&self.data));
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u64(&mut long_buf, self.position as u64);
+        writer.write_all(&long_buf)?;
+        NetworkEndian::write_u64(&mut long_buf, self.data.len() as u64);
+        writer.write_all(&long_buf)?;
+        writer.write_all(&self.data)?;
         Ok(())
     }
 
     /// Expand this operation from previously compressed data in `reader`.  The data in reader
     /// should have been written using `compress_to()`
-    pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<Insert> {
+    pub fn expand_from<R: Read>(reader: &mut R) -> Result<Insert> {
+        let mut long_buf = [0;8];
+        reader.read_exact(&mut long_buf)?;
+        let position = to_usize(NetworkEndian::read_u64(&long_buf))?;
+        reader.read_exact(&mut long_buf)?;
+        let data_len = NetworkEndian::read_u64(&long_buf);
+        Ok(Insert{
+            position,
+            data: read_data(reader, data_len)?
+        })
+    }
+
+    /// Expand this operation from data written by earlier versions of this crate, with 32 bit fields
+    fn expand_narrow_from<R: Read>(reader: &mut R) -> Result<Insert> {
         let mut int_buf = [0;4];
-        try!(reader.read_exact(&mut int_buf));
+        reader.read_exact(&mut int_buf)?;
         let position = NetworkEndian::read_u32(&int_buf);
-        try!(reader.read_exact(&mut int_buf));
-        let data_len = NetworkEndian::read_u32(&int_buf) as usize;
-        let mut data = Vec::with_capacity(data_len);
-        data.resize(data_len, 0);
-        try!(reader.read_exact(&mut data));
+        reader.read_exact(&mut int_buf)?;
+        let data_len = NetworkEndian::read_u32(&int_buf);
         Ok(Insert{
             position: position as usize,
-            data: data
+            data: read_data(reader, data_len as u64)?
         })
     }
 
@@ -349,23 +915,36 @@ impl Delete {
 
     /// Compress this operation and write to `writer`.  The output can then be expanded
     /// back into an equivilent operation using `expand_from()`
-    pub fn compress_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    pub fn compress_to<W: Write>(&self, writer: &mut W) -> Result<()> {
 
-        let mut int_buf = [0;4];
-        NetworkEndian::write_u32(&mut int_buf, self.position as u32);
-        try!(writer.write(&int_buf));
-        NetworkEndian::write_u32(&mut int_buf, self.len as u32);
-        try!(writer.write(&int_buf));
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u64(&mut long_buf, self.position as u64);
+        writer.write_all(&long_buf)?;
+        NetworkEndian::write_u64(&mut long_buf, self.len as u64);
+        writer.write_all(&long_buf)?;
         Ok(())
     }
 
     /// Expand this operation from previously compressed data in `reader`.  The data in reader
     /// should have been written using `compress_to()`
-    pub fn expand_from<R: Read>(reader: &mut R) -> io::Result<Delete> {
+    pub fn expand_from<R: Read>(reader: &mut R) -> Result<Delete> {
+        let mut long_buf = [0;8];
+        reader.read_exact(&mut long_buf)?;
+        let position = NetworkEndian::read_u64(&long_buf);
+        reader.read_exact(&mut long_buf)?;
+        let len = NetworkEndian::read_u64(&long_buf);
+        Ok(Delete{
+            position: to_usize(position)?,
+            len: to_usize(len)?,
+        })
+    }
+
+    /// Expand this operation from data written by earlier versions of this crate, with 32 bit fields
+    fn expand_narrow_from<R: Read>(reader: &mut R) -> Result<Delete> {
         let mut int_buf = [0;4];
-        try!(reader.read_exact(&mut int_buf));
+        reader.read_exact(&mut int_buf)?;
         let position = NetworkEndian::read_u32(&int_buf);
-        try!(reader.read_exact(&mut int_buf));
+        reader.read_exact(&mut int_buf)?;
         let len = NetworkEndian::read_u32(&int_buf);
         Ok(Delete{
             position: position as usize,
@@ -375,9 +954,29 @@ impl Delete {
 
 }
 
+/// Converts a position or length read from compressed data, which may not fit on this platform
+fn to_usize(value: u64) -> Result<usize> {
+    if value > usize::MAX as u64 {
+        return Err(Error::Corrupt("value is too large for this platform".to_string()));
+    }
+    Ok(value as usize)
+}
+
+/// Reads `len` bytes of data from `reader`.  The buffer grows as the data is read,
+/// so a corrupted length can't be used to allocate more memory than there is data.
+fn read_data<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    if reader.take(len).read_to_end(&mut data)? as u64 != len {
+        return Err(Error::Truncated);
+    }
+    Ok(data)
+}
+
 #[cfg(test)]
 mod test {
-    use super::Diff;
+    use super::{Diff, Error};
+    use std::cmp::min;
+    use std::io::{self, Cursor, Read};
 
 
 
@@ -396,4 +995,107 @@ mod test {
         let result = diff.apply_to_string(string).unwrap();
         assert_eq!(result, "Mrs. and Mrs. Dursley, of number forty, Privet Drive, were proud to say that they were perfectly abnormal, thank you very much. They were the last people you'd expect to be involved, because they just didn't hold with much nonsense.".to_string());
     }
+
+    /// Hands out data a few bytes at a time, so that copies are split across many reads
+    struct SmallReads<'a>(&'a [u8]);
+
+    impl<'a> Read for SmallReads<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = min(min(3, buf.len()), self.0.len());
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn applying_diff_as_stream() {
+        let base = b"The quick brown fox jumps over the lazy dog";
+        let mut diff = Diff::new();
+        diff.add_insert(0, b"See ".to_vec());
+        diff.add_insert(14, b"red".to_vec());
+        diff.add_delete(17, 5);
+        diff.add_delete(22, 6);
+        diff.add_delete(35, 4);
+        let mut result = Vec::new();
+        diff.apply_stream(SmallReads(base), &mut result).unwrap();
+        assert_eq!(result, b"See The quick red fox over the lazy".to_vec());
+
+        // Positions past the end of the data
+        let mut diff = Diff::new();
+        diff.add_insert(50, b"!".to_vec());
+        assert!(diff.apply_stream(&base[..], &mut Vec::new()).is_err());
+        let mut diff = Diff::new();
+        diff.add_delete(40, 4);
+        assert!(diff.apply_stream(&base[..], &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn compressing_diff() {
+        let mut diff = Diff::new();
+        diff.add_insert(2, b"ab".to_vec());
+        diff.add_insert(5_000_000_000, b"c".to_vec());
+        diff.add_delete(3, 1);
+        let mut compressed = Vec::new();
+        diff.compress_to(&mut compressed).unwrap();
+        assert_eq!(Diff::expand_from(&mut Cursor::new(compressed.clone())).unwrap(), diff);
+        assert!(Diff::expand_from(&mut Cursor::new(&compressed[..compressed.len() - 1])).is_err());
+
+        // The layout used before containers, with 32 bit positions
+        let mut diff = Diff::new();
+        diff.add_insert(2, b"ab".to_vec());
+        diff.add_delete(3, 1);
+        let narrow = vec![0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, 97, 98, 0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0, 1];
+        assert_eq!(Diff::expand_from_legacy(&mut Cursor::new(narrow.clone())).unwrap(), diff);
+        // It is only read when asked for
+        match Diff::expand_from(&mut Cursor::new(narrow)) {
+            Err(Error::Corrupt(ref message)) if message == "not an rdiff file" => (),
+            result => panic!("expected data which isn't an rdiff file, got {:?}", result)
+        }
+    }
+
+    #[test]
+    fn rejecting_malformed_diffs() {
+        let mut diff = Diff::new();
+        diff.add_insert(10, b"x".to_vec());
+        match diff.apply_to_string("abc") {
+            Err(Error::PositionOutOfRange(10)) => (),
+            result => panic!("expected a position out of range, got {:?}", result)
+        }
+        let mut diff = Diff::new();
+        diff.add_delete(2, 5);
+        match diff.apply_to_string("abc") {
+            Err(Error::PositionOutOfRange(2)) => (),
+            result => panic!("expected a position out of range, got {:?}", result)
+        }
+        let mut diff = Diff::new();
+        diff.add_insert(0, vec![0xff]);
+        match diff.apply_to_string("abc") {
+            Err(Error::InvalidUtf8(_)) => (),
+            result => panic!("expected invalid utf8, got {:?}", result)
+        }
+
+        // An enormous count doesn't allocate anything before finding there is no data
+        match Diff::expand_from_legacy(&mut Cursor::new(vec![0x7f, 0xff, 0xff, 0xff])) {
+            Err(Error::Truncated) => (),
+            result => panic!("expected truncated data, got {:?}", result)
+        }
+        let unordered = vec![0, 0, 0, 2, 0, 0, 0, 5, 0, 0, 0, 1, 97, 0, 0, 0, 1, 0, 0, 0, 1, 98, 0, 0, 0, 0];
+        match Diff::expand_from_legacy(&mut Cursor::new(unordered.clone())) {
+            Err(Error::Corrupt(_)) => (),
+            result => panic!("expected corrupt data, got {:?}", result)
+        }
+
+        // Damaged data gives an error rather than panicking, however it is damaged
+        for index in 0..unordered.len() {
+            for value in [0, 1, 0x7f, 0xff].iter() {
+                let mut damaged = unordered.clone();
+                damaged[index] = *value;
+                if let Ok(diff) = Diff::expand_from_legacy(&mut Cursor::new(damaged)) {
+                    let _ = diff.apply_to_string("abcdefgh");
+                    let _ = diff.invert(b"abcdefgh");
+                }
+            }
+        }
+    }
 }
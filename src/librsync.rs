@@ -0,0 +1,357 @@
+//! Reading and writing the signature and delta formats used by librsync's `rdiff` tool.
+//!
+//! All integers are big endian.  A signature is the magic number, the block length and the
+//! strong hash length, followed by the weak and strong hash of each block in file order.
+//! A delta is the magic number followed by a series of commands, each starting with an opcode byte.
+use super::{Error, Result, BlockHashes, Delta, DeltaOp, WeakHash, WeakHashAlgorithm, StrongHashAlgorithm};
+use crate::hashing::{check_weak_hash, read_block};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use byteorder::{NetworkEndian, ByteOrder};
+
+const MD4_SIG_MAGIC: u32 = 0x7273_0136;
+const BLAKE2_SIG_MAGIC: u32 = 0x7273_0137;
+const RK_MD4_SIG_MAGIC: u32 = 0x7273_0146;
+const RK_BLAKE2_SIG_MAGIC: u32 = 0x7273_0147;
+const DELTA_MAGIC: u32 = 0x7273_0236;
+
+/// Marks the end of a delta
+const OP_END: u8 = 0x00;
+/// Literals of up to this length are written with their length as the opcode
+const OP_LITERAL_MAX_INLINE: u8 = 0x40;
+/// A literal, followed by its length in 1 byte.  The opcodes for 2, 4 and 8 byte lengths follow this one
+const OP_LITERAL_N1: u8 = 0x41;
+/// A copy, followed by its position and length in 1 byte each.  The opcodes for every combination of 1, 2, 4 and 8
+/// byte positions and lengths follow this one
+const OP_COPY_N1_N1: u8 = 0x45;
+/// The last opcode for a copy, with 8 byte positions and lengths
+const OP_COPY_N8_N8: u8 = 0x54;
+
+impl<H: WeakHash> BlockHashes<H> {
+    /// Create a new BlockHash based on the data in data_source, which can be written as a librsync signature
+    /// using [`write_librsync_signature()`](#method.write_librsync_signature).
+    ///
+    /// The weak hash picks the format of the signature: [`RabinKarp`](struct.RabinKarp.html) for the format librsync
+    /// writes by default since version 2.2 (`RS_RK_BLAKE2_SIG_MAGIC`), or [`RollSum`](struct.RollSum.html) for the
+    /// older one (`RS_BLAKE2_SIG_MAGIC`).  Both use BLAKE2 strong hashes, which are truncated to `strong_len` bytes,
+    /// which can be at most 32.
+    ///
+    /// Gives an error if the weak hash is not one that librsync uses.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::{BlockHashes, RabinKarp};
+    /// use std::io::Cursor;
+    /// let hashes = BlockHashes::<RabinKarp>::new_librsync(Cursor::new("The initial version"), 2048, 32).unwrap();
+    /// let mut signature = Vec::new();
+    /// hashes.write_librsync_signature(&mut signature).unwrap();
+    /// assert_eq!(&signature[..4], &[0x72, 0x73, 0x01, 0x47]);
+    /// ```
+    pub fn new_librsync<R: Read>(data_source: R, block_size: usize, strong_len: usize) -> Result<BlockHashes<H>> {
+        signature_magic(H::ALGORITHM, StrongHashAlgorithm::Blake2)?;
+        if strong_len == 0 || strong_len > StrongHashAlgorithm::Blake2.output_len() {
+            return Err(Error::InvalidParameter("librsync strong hashes must be between 1 and 32 bytes".to_string()));
+        }
+        BlockHashes::with_algorithms(data_source, block_size, StrongHashAlgorithm::Blake2, strong_len)
+    }
+
+    /// Write these hashes to `writer` as a librsync signature, as would be created by `rdiff signature`.
+    ///
+    /// Gives an error if these hashes weren't created by [`new_librsync()`](#method.new_librsync),
+    /// or read by [`read_librsync_signature()`](#method.read_librsync_signature).
+    pub fn write_librsync_signature<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let magic = signature_magic(H::ALGORITHM, self.strong_hash)?;
+        let mut int_buf = [0;4];
+        NetworkEndian::write_u32(&mut int_buf, magic);
+        writer.write_all(&int_buf)?;
+        NetworkEndian::write_u32(&mut int_buf, self.block_size as u32);
+        writer.write_all(&int_buf)?;
+        NetworkEndian::write_u32(&mut int_buf, self.strong_len as u32);
+        writer.write_all(&int_buf)?;
+        for (weak, strong) in self.sequential_hashes() {
+            NetworkEndian::write_u32(&mut int_buf, weak);
+            writer.write_all(&int_buf)?;
+            writer.write_all(strong)?;
+        }
+        Ok(())
+    }
+
+    /// Read hashes from a librsync signature in `reader`, as created by `rdiff signature`.  The hashes
+    /// can then be used to create a `Delta` with [`delta_against()`](#method.delta_against).
+    ///
+    /// The weak hash must be the one the signature uses, as described in [`new_librsync()`](#method.new_librsync).
+    /// Signatures using MD4 strong hashes are not supported.
+    ///
+    /// librsync signatures don't record the length of the file, so the last block is assumed to be full.
+    /// This doesn't affect deltas, but a `Diff` found against these hashes may delete past the end of the file.
+    pub fn read_librsync_signature<R: Read>(reader: &mut R) -> Result<BlockHashes<H>> {
+        let mut int_buf = [0;4];
+        reader.read_exact(&mut int_buf)?;
+        let weak_hash = match NetworkEndian::read_u32(&int_buf) {
+            BLAKE2_SIG_MAGIC => WeakHashAlgorithm::RollSum,
+            RK_BLAKE2_SIG_MAGIC => WeakHashAlgorithm::RabinKarp,
+            MD4_SIG_MAGIC | RK_MD4_SIG_MAGIC => return Err(Error::Unsupported("MD4 signatures are not supported".to_string())),
+            _ => return Err(Error::Corrupt("not a librsync signature".to_string()))
+        };
+        check_weak_hash::<H>(weak_hash)?;
+        reader.read_exact(&mut int_buf)?;
+        let block_size = NetworkEndian::read_u32(&int_buf) as usize;
+        reader.read_exact(&mut int_buf)?;
+        let strong_len = NetworkEndian::read_u32(&int_buf) as usize;
+        if block_size == 0 || strong_len == 0 || strong_len > StrongHashAlgorithm::Blake2.output_len() {
+            return Err(Error::Corrupt("invalid librsync signature header".to_string()));
+        }
+
+        let mut hashes = HashMap::new();
+        let mut block = vec![0; 4 + strong_len];
+        let mut block_count = 0;
+        loop {
+            match read_block(reader, &mut block)? {
+                0 => break,
+                size if size < block.len() => return Err(Error::Truncated),
+                _ => {}
+            }
+            let weak = NetworkEndian::read_u32(&block[..4]);
+            hashes.entry(weak).or_insert_with(Vec::new).push((block_count, block[4..].to_vec()));
+            block_count += 1;
+        }
+        Ok(BlockHashes {
+            hashes,
+            block_size,
+            file_size: block_count * block_size,
+            weak_hash: PhantomData,
+            strong_hash: StrongHashAlgorithm::Blake2,
+            strong_len
+        })
+    }
+}
+
+/// Gets the magic number of the librsync signature format which uses the given hashes, or an error if there isn't one
+fn signature_magic(weak_hash: WeakHashAlgorithm, strong_hash: StrongHashAlgorithm) -> Result<u32> {
+    match (weak_hash, strong_hash) {
+        (WeakHashAlgorithm::RollSum, StrongHashAlgorithm::Blake2) => Ok(BLAKE2_SIG_MAGIC),
+        (WeakHashAlgorithm::RabinKarp, StrongHashAlgorithm::Blake2) => Ok(RK_BLAKE2_SIG_MAGIC),
+        _ => Err(Error::InvalidParameter("these hashes are not compatible with librsync".to_string()))
+    }
+}
+
+/// Finds the smallest of the parameter sizes librsync supports which can hold `value`.
+///
+/// Returns the size in bytes, along with its position in the list of sizes, which is used to calculate opcodes
+fn parameter_size(value: u64) -> (usize, u8) {
+    if value <= 0xff {
+        (1, 0)
+    } else if value <= 0xffff {
+        (2, 1)
+    } else if value <= 0xffff_ffff {
+        (4, 2)
+    } else {
+        (8, 3)
+    }
+}
+
+/// Writes `value` to `writer` as a big endian integer `size` bytes long
+fn write_parameter<W: Write>(writer: &mut W, value: u64, size: usize) -> Result<()> {
+    let mut int_buf = [0;8];
+    NetworkEndian::write_u64(&mut int_buf, value);
+    writer.write_all(&int_buf[8 - size..])?;
+    Ok(())
+}
+
+/// Reads a big endian integer `size` bytes long from `reader`
+fn read_parameter<R: Read>(reader: &mut R, size: usize) -> Result<u64> {
+    let mut int_buf = [0;8];
+    reader.read_exact(&mut int_buf[8 - size..])?;
+    Ok(NetworkEndian::read_u64(&int_buf))
+}
+
+impl Delta {
+    /// Write this delta to `writer` in librsync's delta format, as would be created by `rdiff delta`.
+    /// It can then be applied with `rdiff patch`.
+    pub fn write_librsync<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut int_buf = [0;4];
+        NetworkEndian::write_u32(&mut int_buf, DELTA_MAGIC);
+        writer.write_all(&int_buf)?;
+        for op in self.ops() {
+            match *op {
+                DeltaOp::Copy{base_offset, len} => {
+                    let (offset_size, offset_index) = parameter_size(base_offset as u64);
+                    let (len_size, len_index) = parameter_size(len as u64);
+                    writer.write_all(&[OP_COPY_N1_N1 + offset_index * 4 + len_index])?;
+                    write_parameter(writer, base_offset as u64, offset_size)?;
+                    write_parameter(writer, len as u64, len_size)?;
+                }
+                DeltaOp::Literal(ref data) => {
+                    if data.len() <= OP_LITERAL_MAX_INLINE as usize {
+                        writer.write_all(&[data.len() as u8])?;
+                    } else {
+                        let (len_size, len_index) = parameter_size(data.len() as u64);
+                        writer.write_all(&[OP_LITERAL_N1 + len_index])?;
+                        write_parameter(writer, data.len() as u64, len_size)?;
+                    }
+                    writer.write_all(data)?;
+                }
+            }
+        }
+        writer.write_all(&[OP_END])?;
+        Ok(())
+    }
+
+    /// Read a delta in librsync's format from `reader`, as created by `rdiff delta`.
+    pub fn read_librsync<R: Read>(reader: &mut R) -> Result<Delta> {
+        let mut int_buf = [0;4];
+        reader.read_exact(&mut int_buf)?;
+        if NetworkEndian::read_u32(&int_buf) != DELTA_MAGIC {
+            return Err(Error::Corrupt("not a librsync delta".to_string()));
+        }
+        let mut delta = Delta::new();
+        // The length of the new file so far, which has to stay addressable
+        let mut new_len: usize = 0;
+        let too_long = || Error::Corrupt("the delta produces more data than can be addressed".to_string());
+        loop {
+            let mut opcode = [0];
+            reader.read_exact(&mut opcode)?;
+            let opcode = opcode[0];
+            match opcode {
+                OP_END => break,
+                1..=OP_LITERAL_MAX_INLINE => {
+                    let mut data = vec![0; opcode as usize];
+                    reader.read_exact(&mut data)?;
+                    new_len = new_len.checked_add(data.len()).ok_or_else(too_long)?;
+                    delta.add_literal(data);
+                }
+                OP_LITERAL_N1..=0x44 => {
+                    let len = read_parameter(reader, 1 << (opcode - OP_LITERAL_N1))?;
+                    let mut data = Vec::new();
+                    reader.take(len).read_to_end(&mut data)?;
+                    if (data.len() as u64) < len {
+                        return Err(Error::Truncated);
+                    }
+                    new_len = new_len.checked_add(data.len()).ok_or_else(too_long)?;
+                    delta.add_literal(data);
+                }
+                OP_COPY_N1_N1..=OP_COPY_N8_N8 => {
+                    let offset_size = 1 << ((opcode - OP_COPY_N1_N1) / 4);
+                    let len_size = 1 << ((opcode - OP_COPY_N1_N1) % 4);
+                    let base_offset = read_parameter(reader, offset_size)?;
+                    let len = read_parameter(reader, len_size)?;
+                    // The copied data has to be addressable in both the old and the new file
+                    let (base_offset, len) = match (usize::try_from(base_offset), usize::try_from(len)) {
+                        (Ok(base_offset), Ok(len)) if base_offset.checked_add(len).is_some() => (base_offset, len),
+                        _ => return Err(Error::Corrupt(format!("copy of {} bytes from {} is out of range", len, base_offset)))
+                    };
+                    new_len = new_len.checked_add(len).ok_or_else(too_long)?;
+                    delta.add_copy(base_offset, len);
+                }
+                _ => return Err(Error::Corrupt(format!("unknown librsync delta command {:#x}", opcode)))
+            }
+        }
+        Ok(delta)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{BlockHashes, Delta, DeltaOp, Error, RabinKarp, RollSum, RollingHash, WeakHash};
+    use std::io::Cursor;
+
+    #[test]
+    fn signature() {
+        let hashes = BlockHashes::<RabinKarp>::new_librsync(Cursor::new("It was the best of times"), 16, 8).unwrap();
+        let mut signature = Vec::new();
+        hashes.write_librsync_signature(&mut signature).unwrap();
+        assert_eq!(signature, vec![
+            0x72, 0x73, 0x01, 0x47, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x08,
+            0x70, 0x6c, 0x63, 0x04, 0x8c, 0xb1, 0x1c, 0x15, 0xca, 0x91, 0xdc, 0xb4,
+            0x85, 0x4c, 0x4f, 0x04, 0xf7, 0x29, 0xbd, 0x04, 0x06, 0xc5, 0xae, 0x84]);
+        assert_eq!(BlockHashes::<RabinKarp>::read_librsync_signature(&mut Cursor::new(&signature)).unwrap(), BlockHashes {
+            file_size: 32,
+            ..hashes
+        });
+
+        let hashes = BlockHashes::<RollSum>::new_librsync(Cursor::new("It was the best of times"), 16, 8).unwrap();
+        let mut signature = Vec::new();
+        hashes.write_librsync_signature(&mut signature).unwrap();
+        assert_eq!(signature, vec![
+            0x72, 0x73, 0x01, 0x37, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x08,
+            0x3f, 0x12, 0x07, 0x67, 0x8c, 0xb1, 0x1c, 0x15, 0xca, 0x91, 0xdc, 0xb4,
+            0x11, 0xca, 0x04, 0x0f, 0xf7, 0x29, 0xbd, 0x04, 0x06, 0xc5, 0xae, 0x84]);
+
+        assert_eq!(BlockHashes::<RollSum>::read_librsync_signature(&mut Cursor::new(&signature)).unwrap(), BlockHashes {
+            file_size: 32,
+            ..hashes
+        });
+
+        // The signature has to be read with the weak hash it was made with
+        match BlockHashes::<RabinKarp>::read_librsync_signature(&mut Cursor::new(&signature)) {
+            Err(Error::InvalidParameter(_)) => (),
+            result => panic!("expected an invalid parameter, got {:?}", result)
+        }
+        assert!(BlockHashes::new(Cursor::new("It was the best of times"), 16).unwrap().write_librsync_signature(&mut Vec::new()).is_err());
+        assert!(BlockHashes::<RollingHash>::new_librsync(Cursor::new("It was the best of times"), 16, 8).is_err());
+        assert!(BlockHashes::<RollSum>::read_librsync_signature(&mut Cursor::new(&signature[..20])).is_err());
+        assert!(BlockHashes::<RollSum>::read_librsync_signature(&mut Cursor::new(vec![0x72, 0x73, 0x01, 0x36, 0, 0, 8, 0, 0, 0, 0, 8])).is_err());
+    }
+
+    fn check_rolling_matches<H: WeakHash>() {
+        let old = "The quick brown fox jumps over the lazy dog";
+        let new = "A quick brown fox jumps over the lazy dog";
+        let hashes = BlockHashes::<H>::new_librsync(Cursor::new(old), 8, 16).unwrap();
+        let delta = hashes.delta_against(Cursor::new(new)).unwrap();
+        assert_eq!(delta.ops().cloned().collect::<Vec<_>>(), vec![
+            DeltaOp::Literal(b"A quic".to_vec()),
+            DeltaOp::Copy{base_offset: 8, len: 35},
+        ]);
+    }
+
+    #[test]
+    fn rolling_matches_blocks() {
+        check_rolling_matches::<RollSum>();
+        check_rolling_matches::<RabinKarp>();
+    }
+
+    #[test]
+    fn delta() {
+        let mut delta = Delta::new();
+        delta.add_copy(0, 12);
+        delta.add_literal(b"Hello".to_vec());
+        delta.add_copy(0x1234, 0x10000);
+        delta.add_literal(vec![7; 300]);
+        delta.add_copy(0x1_0000_0000, 2);
+        let mut written = Vec::new();
+        delta.write_librsync(&mut written).unwrap();
+        let mut expected = vec![0x72, 0x73, 0x02, 0x36,
+                                0x45, 0x00, 0x0c,
+                                0x05, b'H', b'e', b'l', b'l', b'o',
+                                0x4b, 0x12, 0x34, 0x00, 0x01, 0x00, 0x00,
+                                0x42, 0x01, 0x2c];
+        expected.extend_from_slice(&[7; 300]);
+        expected.extend_from_slice(&[0x51, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00]);
+        assert_eq!(written, expected);
+        assert_eq!(Delta::read_librsync(&mut Cursor::new(&written)).unwrap(), delta);
+
+        assert!(Delta::read_librsync(&mut Cursor::new(&written[..written.len() - 1])).is_err());
+
+        // Copies which run past the end of any file, or which together produce more data than can be addressed
+        let copy = |base_offset: u64, len: u64| {
+            let mut op = vec![0x54];
+            op.extend_from_slice(&base_offset.to_be_bytes());
+            op.extend_from_slice(&len.to_be_bytes());
+            op
+        };
+        for copies in [[copy(0, u64::MAX), copy(u64::MAX, 5)], [copy(0, u64::MAX), copy(0, 1)]].iter() {
+            let mut corrupt = vec![0x72, 0x73, 0x02, 0x36];
+            corrupt.extend(copies.concat());
+            corrupt.push(0);
+            match Delta::read_librsync(&mut Cursor::new(corrupt)) {
+                Err(Error::Corrupt(_)) => (),
+                result => panic!("expected corrupt data, got {:?}", result)
+            }
+        }
+        assert!(Delta::read_librsync(&mut Cursor::new(vec![0x72, 0x73, 0x02, 0x36, 0x55, 0x00])).is_err());
+        assert!(Delta::read_librsync(&mut Cursor::new(vec![0x72, 0x73, 0x01, 0x36, 0x00])).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+//! The `rdiff` command line tool, which creates signatures of files, finds deltas against
+//! those signatures, and patches files with the deltas.
+//!
+//! Any file name can be given as `-` to read from stdin or write to stdout.  Files are written through a temporary
+//! file which only replaces them once the command succeeds, so a failed command leaves its output untouched.
+//! The file written by `patch` can be the same as the base, but the signature and delta can't be written over
+//! the files they are made from.
+//!
+//! Exits with 0 on success, 1 when `diff` finds differences, 2 for invalid arguments,
+//! 3 when a file can't be read or written, and 4 when a signature, delta or base file is invalid.
+extern crate rdiff;
+
+use rdiff::{BlockHashes, Diff};
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::process;
+
+/// The block size used when none is given
+const DEFAULT_BLOCK_SIZE: usize = 2048;
+
+const USAGE: &str = "\
+Usage: rdiff [--block-size <bytes>] <command> <files>...
+
+Commands:
+    signature <base> <sig>          Write the signature of <base> to <sig>
+    delta <sig> <new> <delta>       Write the delta from the file <sig> was made from to <new>
+    patch <base> <delta> <out>      Apply <delta> to <base>, writing the result to <out>
+    diff <old> <new>                Print the inserts and deletes which turn <old> into <new>
+    help                            Print this message
+
+Any file can be given as - to use stdin or stdout.";
+
+/// The ways the tool can fail, which each have their own exit code
+#[derive(Debug)]
+enum CliError {
+    /// The arguments didn't make sense
+    Usage(String),
+    /// A file couldn't be read or written
+    Io(io::Error),
+    /// A signature, delta or base file was invalid
+    Invalid(rdiff::Error)
+}
+
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        match *self {
+            CliError::Usage(_) => 2,
+            CliError::Io(_) => 3,
+            CliError::Invalid(_) => 4
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CliError::Usage(ref message) => write!(fmt, "{}\n\n{}", message, USAGE),
+            CliError::Io(ref e) => write!(fmt, "{}", e),
+            CliError::Invalid(ref e) => write!(fmt, "{}", e)
+        }
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> CliError {
+        CliError::Io(e)
+    }
+}
+
+impl From<rdiff::Error> for CliError {
+    fn from(e: rdiff::Error) -> CliError {
+        match e {
+            rdiff::Error::Io(e) => CliError::Io(e),
+            e => CliError::Invalid(e)
+        }
+    }
+}
+
+/// Opens a file to read from, or stdin for `-`
+fn open_input(name: &str) -> io::Result<Box<dyn Read>> {
+    if name == "-" {
+        Ok(Box::new(BufReader::new(io::stdin())))
+    } else {
+        Ok(Box::new(BufReader::new(File::open(name)?)))
+    }
+}
+
+/// Creates a file to write to, or stdout for `-`
+fn open_output(name: &str) -> io::Result<Box<dyn Write>> {
+    if name == "-" {
+        Ok(Box::new(BufWriter::new(io::stdout())))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(name)?)))
+    }
+}
+
+/// Writes the file `name` with `write`, or stdout for `-`.  The file is written to a temporary file next to it,
+/// which is renamed over it once everything has been written, and removed if anything fails.
+fn write_output<F: FnOnce(&mut dyn Write) -> rdiff::Result<()>>(name: &str, write: F) -> Result<(), CliError> {
+    if name == "-" {
+        let mut out = open_output(name)?;
+        write(&mut out)?;
+        out.flush()?;
+        return Ok(());
+    }
+    let path = Path::new(name);
+    let file_name = path.file_name().ok_or_else(|| CliError::Usage(format!("{} does not name a file", name)))?;
+    let temp_path = path.with_file_name(format!(".{}.rdiff-{}.tmp", file_name.to_string_lossy(), process::id()));
+    let mut temp = BufWriter::new(OpenOptions::new().write(true).create_new(true).open(&temp_path)?);
+    let result = write(&mut temp)
+        .map_err(CliError::from)
+        .and_then(|_| Ok(temp.flush()?))
+        .and_then(|_| Ok(fs::rename(&temp_path, path)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Checks that the output file isn't one of the inputs, which it would replace
+fn check_output(inputs: &[String], output: &str) -> Result<(), CliError> {
+    let output_path = match fs::canonicalize(output) {
+        Ok(path) if output != "-" => path,
+        _ => return Ok(())
+    };
+    if inputs.iter().any(|input| fs::canonicalize(input).is_ok_and(|path| path == output_path)) {
+        return Err(CliError::Usage(format!("{} can't be written over one of the files it is made from", output)));
+    }
+    Ok(())
+}
+
+/// Checks that a command was given the right number of files, and that stdin is read at most once
+fn check_files<'a>(command: &str, files: &'a [String], names: &[&str], inputs: usize) -> Result<&'a [String], CliError> {
+    if files.len() != names.len() {
+        let names = names.iter().map(|name| format!("<{}>", name)).collect::<Vec<_>>().join(" ");
+        return Err(CliError::Usage(format!("{} needs the files {}", command, names)));
+    }
+    if files[..inputs].iter().filter(|name| *name == "-").count() > 1 {
+        return Err(CliError::Usage("only one file can be read from stdin".to_string()));
+    }
+    Ok(files)
+}
+
+/// Runs the tool with the given arguments, not including the program name, and returns the exit code
+fn run(args: &[String], stdout: &mut dyn Write) -> Result<i32, CliError> {
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+    let mut args = args;
+    while let Some(option) = args.first().filter(|arg| arg.starts_with("--")) {
+        match option.as_str() {
+            "--block-size" => {
+                block_size = args.get(1)
+                    .and_then(|size| size.parse().ok())
+                    .filter(|&size| size > 0)
+                    .ok_or_else(|| CliError::Usage("--block-size needs a number of bytes greater than 0".to_string()))?;
+                args = &args[2..];
+            }
+            "--help" => args = &args[..0],
+            _ => return Err(CliError::Usage(format!("unknown option {}", option)))
+        }
+    }
+    let (command, files) = match args.split_first() {
+        Some((command, files)) => (command.as_str(), files),
+        None => {
+            writeln!(stdout, "{}", USAGE)?;
+            return Ok(0);
+        }
+    };
+    match command {
+        "signature" => {
+            let files = check_files(command, files, &["base", "sig"], 1)?;
+            check_output(&files[..1], &files[1])?;
+            let hashes = BlockHashes::new(open_input(&files[0])?, block_size)?;
+            write_output(&files[1], |mut out| hashes.compress_to(&mut out))?;
+            Ok(0)
+        }
+        "delta" => {
+            let files = check_files(command, files, &["sig", "new", "delta"], 2)?;
+            check_output(&files[..2], &files[2])?;
+            let hashes: BlockHashes = BlockHashes::expand_from(&mut open_input(&files[0])?)?;
+            let diff = hashes.diff_against(open_input(&files[1])?)?;
+            write_output(&files[2], |mut out| diff.compress_to(&mut out))?;
+            Ok(0)
+        }
+        "patch" => {
+            let files = check_files(command, files, &["base", "delta", "out"], 2)?;
+            let diff = Diff::expand_from(&mut open_input(&files[1])?)?;
+            // The base is opened before anything is written, and the output file is only replaced once
+            // the whole patch has succeeded, so the output can be the base itself
+            let base = open_input(&files[0])?;
+            if files[2] == "-" {
+                diff.apply_stream(base, open_output(&files[2])?)?;
+            } else {
+                diff.apply_stream_atomic(base, &files[2])?;
+            }
+            Ok(0)
+        }
+        "diff" => {
+            let files = check_files(command, files, &["old", "new"], 2)?;
+            let hashes = BlockHashes::new(open_input(&files[0])?, block_size)?;
+            let diff = hashes.diff_against(open_input(&files[1])?)?;
+            for insert in diff.inserts() {
+                writeln!(stdout, "{:?}", insert)?;
+            }
+            for delete in diff.deletes() {
+                writeln!(stdout, "{:?}", delete)?;
+            }
+            Ok(if diff.is_empty() { 0 } else { 1 })
+        }
+        "help" => {
+            writeln!(stdout, "{}", USAGE)?;
+            Ok(0)
+        }
+        _ => Err(CliError::Usage(format!("unknown command {}", command)))
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let stdout = io::stdout();
+    let code = match run(&args, &mut stdout.lock()) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("rdiff: {}", e);
+            e.exit_code()
+        }
+    };
+    process::exit(code);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run, CliError};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// A temporary directory which is only used by this test run
+    fn temp_dir() -> PathBuf {
+        let mut path = env::temp_dir();
+        path.push(format!("rdiff-cli-test-{}", process::id()));
+        path
+    }
+
+    /// A file in the temporary directory
+    fn temp_file(name: &str) -> String {
+        let mut path = temp_dir();
+        fs::create_dir_all(&path).unwrap();
+        path.push(name);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn run_args(args: &[&str]) -> (Result<i32, CliError>, String) {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let mut stdout = Vec::new();
+        let result = run(&args, &mut stdout);
+        (result, String::from_utf8(stdout).unwrap())
+    }
+
+    #[test]
+    fn signature_delta_patch() {
+        let (base, new, sig, delta, out) = (temp_file("base"), temp_file("new"), temp_file("sig"), temp_file("delta"), temp_file("out"));
+        fs::write(&base, "It was the best of times, it was the worst of times").unwrap();
+        fs::write(&new, "It was the best of times, it was the blurst of times?").unwrap();
+
+        assert_eq!(run_args(&["--block-size", "8", "signature", &base, &sig]).0.unwrap(), 0);
+        assert_eq!(run_args(&["delta", &sig, &new, &delta]).0.unwrap(), 0);
+        assert_eq!(run_args(&["patch", &base, &delta, &out]).0.unwrap(), 0);
+        assert_eq!(fs::read(&out).unwrap(), fs::read(&new).unwrap());
+
+        // Patching a file in place
+        let in_place = temp_file("in_place");
+        fs::copy(&base, &in_place).unwrap();
+        assert_eq!(run_args(&["patch", &in_place, &delta, &in_place]).0.unwrap(), 0);
+        assert_eq!(fs::read(&in_place).unwrap(), fs::read(&new).unwrap());
+
+        let (result, stdout) = run_args(&["--block-size", "8", "diff", &base, &new]);
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(stdout, "Insert(32, ' the blur')\nInsert(57, 'mes?')\nDelete(41, 8)\nDelete(53, 3)\n");
+        let (result, stdout) = run_args(&["diff", &base, &base]);
+        assert_eq!((result.unwrap(), stdout), (0, String::new()));
+
+        // Errors
+        assert_eq!(run_args(&["patch", &base, &sig, &out]).0.unwrap_err().exit_code(), 4);
+        // A failed patch leaves the output as it was
+        let short = temp_file("short");
+        fs::write(&short, "It was").unwrap();
+        assert_eq!(run_args(&["patch", &short, &delta, &out]).0.unwrap_err().exit_code(), 4);
+        assert_eq!(fs::read(&out).unwrap(), fs::read(&new).unwrap());
+        assert_eq!(run_args(&["patch", &short, &delta, &short]).0.unwrap_err().exit_code(), 4);
+        assert_eq!(fs::read(&short).unwrap(), b"It was");
+        assert_eq!(run_args(&["delta", &sig, &temp_file("missing"), &delta]).0.unwrap_err().exit_code(), 3);
+        assert_eq!(run_args(&["delta", &sig, &new]).0.unwrap_err().exit_code(), 2);
+        assert_eq!(run_args(&["diff", "-", "-"]).0.unwrap_err().exit_code(), 2);
+        assert_eq!(run_args(&["--block-size", "0", "diff", &base, &new]).0.unwrap_err().exit_code(), 2);
+        assert_eq!(run_args(&["frobnicate"]).0.unwrap_err().exit_code(), 2);
+        assert!(run_args(&["help"]).1.starts_with("Usage: rdiff"));
+
+        // The signature and delta can't replace the files they are made from, and failures leave the output as it was
+        assert_eq!(run_args(&["signature", &base, &base]).0.unwrap_err().exit_code(), 2);
+        assert_eq!(run_args(&["delta", &sig, &new, &new]).0.unwrap_err().exit_code(), 2);
+        assert_eq!(run_args(&["delta", &sig, &new, &sig]).0.unwrap_err().exit_code(), 2);
+        assert_eq!(fs::read(&base).unwrap(), b"It was the best of times, it was the worst of times");
+        let written_delta = fs::read(&delta).unwrap();
+        assert_eq!(run_args(&["delta", &base, &new, &delta]).0.unwrap_err().exit_code(), 4);
+        assert_eq!(fs::read(&delta).unwrap(), written_delta);
+
+        // Nothing is left behind, including temporary files
+        let mut left = fs::read_dir(temp_dir()).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, vec!["base", "delta", "in_place", "new", "out", "short", "sig"]);
+        fs::remove_dir_all(temp_dir()).unwrap();
+    }
+}
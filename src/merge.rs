@@ -0,0 +1,403 @@
+//! Used for combining two sets of changes made to the same version of a file.
+//!
+//! The primary function of this module is [merge](fn.merge.html).
+//! When the changes overlap, [conflict_markers](fn.conflict_markers.html) can be used to show
+//! them in the style of `diff3`.
+use std::cmp::max;
+use std::ops::Range;
+use super::{Result, Diff, Op};
+use crate::ops::{check_fits, push_op};
+
+/// A region of the original version of a file which was changed in different ways by
+/// both sets of changes being merged.
+#[derive(Debug, PartialEq)]
+pub struct Conflict {
+    base_range: Range<usize>,
+    ours: Vec<u8>,
+    theirs: Vec<u8>
+}
+
+/// The result of merging two diffs: a diff which makes every change that could be merged, along with
+/// the regions that both diffs changed in different ways.
+#[derive(Debug, PartialEq)]
+pub struct MergeResult {
+    diff: Diff,
+    conflicts: Vec<Conflict>
+}
+
+/// A single change to the original version of a file: the data from `start` to `end` is replaced with `data`
+#[derive(Debug)]
+struct Hunk {
+    start: usize,
+    end: usize,
+    data: Vec<u8>
+}
+
+/// A set of hunks from each side which overlap each other, covering `start` to `end` in the original version of the file.
+struct Group {
+    start: usize,
+    end: usize,
+    ours: Range<usize>,
+    theirs: Range<usize>
+}
+
+impl Conflict {
+    /// Gets the range of bytes in the original version of the file which were changed
+    #[inline]
+    pub fn get_base_range(&self) -> Range<usize> {
+        self.base_range.clone()
+    }
+
+    /// Gets what our changes replaced the range with
+    #[inline]
+    pub fn get_ours(&self) -> &[u8] {
+        &self.ours
+    }
+
+    /// Gets what their changes replaced the range with
+    #[inline]
+    pub fn get_theirs(&self) -> &[u8] {
+        &self.theirs
+    }
+}
+
+impl MergeResult {
+    /// Gets the diff which makes every change that didn't conflict.  The regions which conflicted are
+    /// left as they were in the original version of the file.
+    #[inline]
+    pub fn get_diff(&self) -> &Diff {
+        &self.diff
+    }
+
+    /// Gets each region which was changed differently by both diffs, in file order
+    #[inline]
+    pub fn get_conflicts(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+
+    /// Checks whether every change was merged without any conflicts
+    #[inline]
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    /// Consumes the result, giving back the merged diff and the conflicts
+    #[inline]
+    pub fn into_parts(self) -> (Diff, Vec<Conflict>) {
+        (self.diff, self.conflicts)
+    }
+}
+
+/// Merges two diffs, `ours` and `theirs`, which were both made against `base`.
+///
+/// Changes to different parts of `base` are combined into a single diff which makes both sets of changes.
+/// Changes overlap if they affect any of the same bytes, or if they insert data at the same place.
+/// Overlapping changes which have exactly the same result are merged as a single change.
+/// Otherwise, every region changed differently by both diffs is left out of the merged diff, and
+/// returned alongside it as a [`Conflict`](struct.Conflict.html).
+///
+/// Gives an error if either diff refers to positions past the end of `base`, or if its operations
+/// are out of order.
+///
+/// # Example
+///
+/// ```
+/// use rdiff::BlockHashes;
+/// use rdiff::merge::merge;
+/// use std::io::Cursor;
+/// let base = "It was the best of times";
+/// let hashes = BlockHashes::new(Cursor::new(base), 6).unwrap();
+/// let ours = hashes.diff_against(Cursor::new("It was not the best of times")).unwrap();
+/// let theirs = hashes.diff_against(Cursor::new("It was the best of times.")).unwrap();
+/// let merged = merge(base.as_bytes(), &ours, &theirs).unwrap();
+/// assert!(merged.is_clean());
+/// assert_eq!(merged.get_diff().apply_to_string(base).unwrap(), "It was not the best of times.");
+/// ```
+pub fn merge(base: &[u8], ours: &Diff, theirs: &Diff) -> Result<MergeResult> {
+    let ours = find_hunks(base, ours)?;
+    let theirs = find_hunks(base, theirs)?;
+    let mut ops = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut position = 0;
+    for group in find_groups(&ours, &theirs, |hunk| (hunk.start, hunk.end)) {
+        match resolve(base, &group, &ours, &theirs) {
+            Ok(data) => {
+                push_op(&mut ops, Op::Retain(group.start - position));
+                push_op(&mut ops, Op::Delete(group.end - group.start));
+                push_op(&mut ops, Op::Insert(data));
+                position = group.end;
+            }
+            // The conflicting region is kept as it was in the original
+            Err(conflict) => conflicts.push(conflict)
+        }
+    }
+    Ok(MergeResult {
+        diff: Diff::from_ops(&ops),
+        conflicts
+    })
+}
+
+/// Merges two diffs, `ours` and `theirs`, which were both made against the text in `base`, and shows any
+/// conflicts between them with markers in the style of `diff3`:
+///
+/// ```text
+/// <<<<<<< ours_label
+/// our version of the lines
+/// ||||||| base
+/// the original lines
+/// =======
+/// their version of the lines
+/// >>>>>>> theirs_label
+/// ```
+///
+/// Conflicts are widened to cover whole lines, and any changes that fall on those lines are shown as part of
+/// the conflict.  Everything else is merged in the same way as [merge](fn.merge.html).
+///
+/// Gives an error if either diff refers to positions past the end of `base`, or if its operations
+/// are out of order.
+pub fn conflict_markers(base: &[u8], ours: &Diff, theirs: &Diff, ours_label: &str, theirs_label: &str) -> Result<Vec<u8>> {
+    let ours = find_hunks(base, ours)?;
+    let theirs = find_hunks(base, theirs)?;
+    let mut result = Vec::with_capacity(base.len());
+    let mut position = 0;
+    for group in find_groups(&ours, &theirs, |hunk| line_range(base, hunk)) {
+        result.extend_from_slice(&base[position..group.start]);
+        let ours_in_lines = &ours[group.ours.clone()];
+        let theirs_in_lines = &theirs[group.theirs.clone()];
+        let mut merged = Vec::new();
+        let mut line_position = group.start;
+        let mut is_clean = true;
+        for sub_group in find_groups(ours_in_lines, theirs_in_lines, |hunk| (hunk.start, hunk.end)) {
+            merged.extend_from_slice(&base[line_position..sub_group.start]);
+            match resolve(base, &sub_group, ours_in_lines, theirs_in_lines) {
+                Ok(data) => merged.extend(data),
+                Err(_) => {
+                    is_clean = false;
+                    break;
+                }
+            }
+            line_position = sub_group.end;
+        }
+        if is_clean {
+            result.extend(merged);
+            result.extend_from_slice(&base[line_position..group.end]);
+        } else {
+            write_marker(&mut result, "<<<<<<<", ours_label);
+            write_lines(&mut result, &apply_hunks(base, group.start, group.end, ours_in_lines));
+            write_marker(&mut result, "|||||||", "base");
+            write_lines(&mut result, &base[group.start..group.end]);
+            write_marker(&mut result, "=======", "");
+            write_lines(&mut result, &apply_hunks(base, group.start, group.end, theirs_in_lines));
+            write_marker(&mut result, ">>>>>>>", theirs_label);
+        }
+        position = group.end;
+    }
+    result.extend_from_slice(&base[position..]);
+    Ok(result)
+}
+
+/// Finds each change the diff makes to the original version of the file, `base`
+fn find_hunks(base: &[u8], diff: &Diff) -> Result<Vec<Hunk>> {
+    diff.check_order()?;
+    let ops = diff.to_ops();
+    check_fits(&ops, base.len())?;
+    let mut hunks = Vec::new();
+    let mut current: Option<Hunk> = None;
+    let mut position = 0;
+    for op in ops {
+        match op {
+            Op::Retain(len) => {
+                hunks.extend(current.take());
+                position += len;
+            }
+            Op::Insert(mut data) => {
+                current.get_or_insert(Hunk{start: position, end: position, data: Vec::new()}).data.append(&mut data);
+            }
+            Op::Delete(len) => {
+                position += len;
+                current.get_or_insert(Hunk{start: position - len, end: position, data: Vec::new()}).end = position;
+            }
+        }
+    }
+    hunks.extend(current);
+    Ok(hunks)
+}
+
+/// Groups together the hunks from each side which overlap, with `range` giving the range of the file each hunk covers.
+/// Hunks overlap if they cover any of the same bytes, or if they start at the same place.
+fn find_groups<F: Fn(&Hunk) -> (usize, usize)>(ours: &[Hunk], theirs: &[Hunk], range: F) -> Vec<Group> {
+    let mut groups = Vec::new();
+    let mut our_index = 0;
+    let mut their_index = 0;
+    while our_index < ours.len() || their_index < theirs.len() {
+        let (our_start, their_start) = (our_index, their_index);
+        let take_ours = their_index == theirs.len() || (our_index < ours.len() && range(&ours[our_index]).0 <= range(&theirs[their_index]).0);
+        let (start, mut end) = if take_ours {
+            our_index += 1;
+            range(&ours[our_index - 1])
+        } else {
+            their_index += 1;
+            range(&theirs[their_index - 1])
+        };
+        let overlaps = |(hunk_start, _): (usize, usize), end: usize| hunk_start < end || hunk_start == start;
+        loop {
+            if our_index < ours.len() && overlaps(range(&ours[our_index]), end) {
+                end = max(end, range(&ours[our_index]).1);
+                our_index += 1;
+            } else if their_index < theirs.len() && overlaps(range(&theirs[their_index]), end) {
+                end = max(end, range(&theirs[their_index]).1);
+                their_index += 1;
+            } else {
+                break;
+            }
+        }
+        groups.push(Group {
+            start,
+            end,
+            ours: our_start..our_index,
+            theirs: their_start..their_index
+        });
+    }
+    groups
+}
+
+/// Finds what the group's range of the original file should be replaced with, or the conflict if
+/// both sides changed it differently
+fn resolve(base: &[u8], group: &Group, ours: &[Hunk], theirs: &[Hunk]) -> ::std::result::Result<Vec<u8>, Conflict> {
+    let our_data = apply_hunks(base, group.start, group.end, &ours[group.ours.clone()]);
+    if group.theirs.start == group.theirs.end {
+        return Ok(our_data);
+    }
+    let their_data = apply_hunks(base, group.start, group.end, &theirs[group.theirs.clone()]);
+    if group.ours.start == group.ours.end || our_data == their_data {
+        return Ok(their_data);
+    }
+    Err(Conflict {
+        base_range: group.start..group.end,
+        ours: our_data,
+        theirs: their_data
+    })
+}
+
+/// Performs the changes in `hunks` to the original file between `start` and `end`
+fn apply_hunks(base: &[u8], start: usize, end: usize, hunks: &[Hunk]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut position = start;
+    for hunk in hunks {
+        result.extend_from_slice(&base[position..hunk.start]);
+        result.extend_from_slice(&hunk.data);
+        position = hunk.end;
+    }
+    result.extend_from_slice(&base[position..end]);
+    result
+}
+
+/// Widens the range a hunk covers so that it replaces whole lines of the original file with whole lines
+fn line_range(base: &[u8], hunk: &Hunk) -> (usize, usize) {
+    let start = base[..hunk.start].iter().rposition(|&b| b == b'\n').map_or(0, |newline| newline + 1);
+    let mut end = hunk.end;
+    loop {
+        let at_line_end = end == base.len() || end == start || base[end - 1] == b'\n';
+        let ends_line = end > hunk.end || hunk.data.last().or_else(|| base[start..hunk.start].last()).is_none_or(|&b| b == b'\n');
+        if at_line_end && (ends_line || end == base.len()) {
+            return (start, end);
+        }
+        end = base[end..].iter().position(|&b| b == b'\n').map_or(base.len(), |newline| end + newline + 1);
+    }
+}
+
+fn write_marker(result: &mut Vec<u8>, marker: &str, label: &str) {
+    result.extend_from_slice(marker.as_bytes());
+    if !label.is_empty() {
+        result.push(b' ');
+        result.extend_from_slice(label.as_bytes());
+    }
+    result.push(b'\n');
+}
+
+/// Writes out some lines, making sure they end with a newline so the next marker starts on its own line
+fn write_lines(result: &mut Vec<u8>, lines: &[u8]) {
+    result.extend_from_slice(lines);
+    if lines.last().is_some_and(|&b| b != b'\n') {
+        result.push(b'\n');
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{merge, conflict_markers, Conflict};
+    use super::super::{Diff, Error};
+    use crate::string_diff::{find_diff, EditDistance};
+
+    const BASE: &str = "one\ntwo\nthree\nfour\nfive\n";
+
+    #[test]
+    fn merging_separate_changes() {
+        let ours = find_diff(BASE, "one\n2\nthree\nfour\nfive\n", &EditDistance{});
+        let theirs = find_diff(BASE, "one\ntwo\nthree\nfour\n5\nsix\n", &EditDistance{});
+        let merged = merge(BASE.as_bytes(), &ours, &theirs).unwrap();
+        assert!(merged.is_clean());
+        assert_eq!(merged.get_diff().apply_to_string(BASE).unwrap(), "one\n2\nthree\nfour\n5\nsix\n");
+
+        // The same change on both sides is only made once
+        let merged = merge(BASE.as_bytes(), &ours, &ours).unwrap();
+        assert_eq!(merged.get_diff().apply_to_string(BASE).unwrap(), "one\n2\nthree\nfour\nfive\n");
+        assert_eq!(merge(BASE.as_bytes(), &Diff::new(), &Diff::new()).unwrap().into_parts(), (Diff::new(), vec![]));
+    }
+
+    #[test]
+    fn finding_conflicts() {
+        let mut ours = Diff::new();
+        ours.add_insert(4, b"2".to_vec());
+        ours.add_delete(5, 3);
+        let mut theirs = Diff::new();
+        theirs.add_insert(4, b"deux".to_vec());
+        theirs.add_delete(8, 3);
+        theirs.add_insert(18, b"quatre".to_vec());
+        theirs.add_delete(21, 4);
+        assert_eq!(theirs.apply_to_string(BASE).unwrap(), "one\ndeux\nthree\nquatre\nfive\n");
+        let merged = merge(BASE.as_bytes(), &ours, &theirs).unwrap();
+        assert!(!merged.is_clean());
+        assert_eq!(merged.get_conflicts(), &[Conflict {
+            base_range: 4..7,
+            ours: b"2".to_vec(),
+            theirs: b"deux".to_vec()
+        }]);
+        // The changes which didn't conflict are still merged
+        assert_eq!(merged.get_diff().apply_to_string(BASE).unwrap(), "one\ntwo\nthree\nquatre\nfive\n");
+
+        // Inserting at the same place conflicts
+        let mut ours = Diff::new();
+        ours.add_insert(8, b"2.5\n".to_vec());
+        let mut theirs = Diff::new();
+        theirs.add_insert(8, b"2.75\n".to_vec());
+        let merged = merge(BASE.as_bytes(), &ours, &theirs).unwrap();
+        assert_eq!(merged.get_conflicts().len(), 1);
+        assert_eq!(merged.get_diff(), &Diff::new());
+
+        // Changes past the end of the file can't be merged
+        let mut theirs = Diff::new();
+        theirs.add_delete(20, 10);
+        match merge(BASE.as_bytes(), &ours, &theirs) {
+            Err(Error::PositionOutOfRange(30)) => (),
+            result => panic!("expected a position out of range, got {:?}", result)
+        }
+    }
+
+    #[test]
+    fn showing_conflicts() {
+        let ours = find_diff(BASE, "one\ntwo\n3\nfour\nfive\n", &EditDistance{});
+        let theirs = find_diff(BASE, "one\ntwo\ntrois\nfour\ncinq\n", &EditDistance{});
+        let result = conflict_markers(BASE.as_bytes(), &ours, &theirs, "ours", "theirs").unwrap();
+        assert_eq!(String::from_utf8(result).unwrap(), "one\ntwo\n<<<<<<< ours\n3\n||||||| base\nthree\n=======\ntrois\n>>>>>>> theirs\nfour\ncinq\n");
+
+        // Separate changes on the same line are merged normally, and a missing newline at the end is added
+        let ours = find_diff("a b c", "a B c", &EditDistance{});
+        let theirs = find_diff("a b c", "a b C", &EditDistance{});
+        assert_eq!(conflict_markers(b"a b c", &ours, &theirs, "ours", "theirs").unwrap(), b"a B C");
+        let ours = find_diff("a b c", "a b c!", &EditDistance{});
+        let theirs = find_diff("a b c", "a b c?", &EditDistance{});
+        let result = conflict_markers(b"a b c", &ours, &theirs, "ours", "theirs").unwrap();
+        assert_eq!(String::from_utf8(result).unwrap(), "<<<<<<< ours\na b c!\n||||||| base\na b c\n=======\na b c?\n>>>>>>> theirs\n");
+    }
+}
@@ -0,0 +1,335 @@
+use super::{Error, Result, Diff, Op};
+use std::cmp::{min, max};
+use std::collections::VecDeque;
+
+impl Diff {
+    /// Creates the diff which undoes this one.  `base` must be the data that this diff was made against,
+    /// so that any deleted data can be restored.
+    ///
+    /// Applying the inverted diff to the new version of the data gives back `base`.
+    ///
+    /// Gives an error if the diff refers to positions past the end of `base`, or if its operations
+    /// are out of order.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::BlockHashes;
+    /// use std::io::Cursor;
+    /// let hashes = BlockHashes::new(Cursor::new("It was the best of times"), 6).unwrap();
+    /// let diff = hashes.diff_against(Cursor::new("It was not the best of things")).unwrap();
+    /// let undo = diff.invert(b"It was the best of times").unwrap();
+    /// assert_eq!(undo.apply_to_string("It was not the best of things").unwrap(), "It was the best of times");
+    /// ```
+    pub fn invert(&self, base: &[u8]) -> Result<Diff> {
+        self.check_order()?;
+        let ops = self.to_ops();
+        check_fits(&ops, base.len())?;
+        let mut inverse = Vec::new();
+        let mut offset = 0;
+        for op in ops {
+            match op {
+                Op::Retain(len) => {
+                    push_op(&mut inverse, Op::Retain(len));
+                    offset += len;
+                }
+                Op::Insert(data) => push_op(&mut inverse, Op::Delete(data.len())),
+                Op::Delete(len) => {
+                    push_op(&mut inverse, Op::Insert(base[offset..offset + len].to_vec()));
+                    offset += len;
+                }
+            }
+        }
+        Ok(Diff::from_ops(&inverse))
+    }
+
+    /// Combines this diff with `next`, a diff made against the result of this one, into a single diff.
+    ///
+    /// Applying the combined diff gives the same result as applying this diff and then `next`,
+    /// without needing the data in between.
+    ///
+    /// Gives an error if the operations in either diff are out of order.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::BlockHashes;
+    /// use std::io::Cursor;
+    /// let mut hashes = BlockHashes::new(Cursor::new("It was the best of times"), 6).unwrap();
+    /// let first = hashes.diff_and_update(Cursor::new("It was not the best of times")).unwrap();
+    /// let second = hashes.diff_and_update(Cursor::new("It was not the worst of times")).unwrap();
+    /// let diff = first.compose(&second).unwrap();
+    /// assert_eq!(diff.apply_to_string("It was the best of times").unwrap(), "It was not the worst of times");
+    /// ```
+    pub fn compose(&self, next: &Diff) -> Result<Diff> {
+        self.check_order()?;
+        next.check_order()?;
+        let mut first = VecDeque::from(self.to_ops());
+        let mut second = VecDeque::from(next.to_ops());
+        let mut ops = Vec::new();
+        while !first.is_empty() || !second.is_empty() {
+            match (first.front(), second.front()) {
+                // Data inserted by the second diff doesn't depend on the first at all
+                (_, Some(&Op::Insert(_))) => push_op(&mut ops, second.pop_front().unwrap()),
+                // Data deleted by the first diff is never seen by the second
+                (Some(&Op::Delete(_)), _) => push_op(&mut ops, first.pop_front().unwrap()),
+                _ => {
+                    let len = min(front_len(&first), front_len(&second));
+                    match (take_op(&mut first, len), take_op(&mut second, len)) {
+                        (Op::Retain(_), Op::Retain(_)) => push_op(&mut ops, Op::Retain(len)),
+                        (Op::Retain(_), Op::Delete(_)) => push_op(&mut ops, Op::Delete(len)),
+                        (Op::Insert(data), Op::Retain(_)) => push_op(&mut ops, Op::Insert(data)),
+                        (Op::Insert(_), Op::Delete(_)) => (),
+                        _ => unreachable!()
+                    }
+                }
+            }
+        }
+        Ok(Diff::from_ops(&ops))
+    }
+
+    /// Converts this diff into the operations needed to walk from the old version of a file
+    /// to the new one.
+    ///
+    /// Data that is inserted and then deleted again doesn't appear in the operations at all.
+    /// The diff must have passed `check_order()`, or the positions may overflow.
+    pub(crate) fn to_ops(&self) -> Vec<Op> {
+        // The inserts are performed first, so find the ranges of that intermediate version
+        // of the file which the deletes remove
+        let mut deleted = Vec::with_capacity(self.deletes.len());
+        let mut shift = 0;
+        for delete in self.deletes.iter() {
+            deleted.push((delete.position + shift, delete.position + shift + delete.len));
+            shift += delete.len;
+        }
+
+        let mut ops = Vec::new();
+        let mut next_delete = 0;
+        let mut index = 0;
+        for insert in self.inserts.iter() {
+            split_deleted(index, insert.position, &deleted, &mut next_delete, |start, end, is_deleted| {
+                push_op(&mut ops, if is_deleted { Op::Delete(end - start) } else { Op::Retain(end - start) });
+            });
+            let insert_end = insert.position + insert.data.len();
+            split_deleted(insert.position, insert_end, &deleted, &mut next_delete, |start, end, is_deleted| {
+                if !is_deleted {
+                    push_op(&mut ops, Op::Insert(insert.data[start - insert.position..end - insert.position].to_vec()));
+                }
+            });
+            index = insert_end;
+        }
+        let end = deleted.last().map_or(index, |&(_, end)| max(end, index));
+        split_deleted(index, end, &deleted, &mut next_delete, |start, end, is_deleted| {
+            push_op(&mut ops, if is_deleted { Op::Delete(end - start) } else { Op::Retain(end - start) });
+        });
+        if let Some(&Op::Retain(_)) = ops.last() {
+            ops.pop();
+        }
+        ops
+    }
+
+    /// Builds a diff which performs the given operations
+    pub(crate) fn from_ops(ops: &[Op]) -> Diff {
+        let mut diff = Diff::new();
+        // The position in the file after the inserts have been performed
+        let mut index = 0;
+        // The position in the file after the deletes have been performed
+        let mut written = 0;
+        for op in ops {
+            match *op {
+                Op::Retain(len) => {
+                    index += len;
+                    written += len;
+                }
+                Op::Insert(ref data) => {
+                    if !data.is_empty() {
+                        diff.add_insert(index, data.clone());
+                        index += data.len();
+                        written += data.len();
+                    }
+                }
+                Op::Delete(len) => {
+                    if len > 0 {
+                        diff.add_delete(written, len);
+                        index += len;
+                    }
+                }
+            }
+        }
+        diff
+    }
+}
+
+/// Adds an operation to the end of `ops`, merging it with the last operation if they are the same kind
+pub(crate) fn push_op(ops: &mut Vec<Op>, op: Op) {
+    match (ops.last_mut(), op) {
+        (_, Op::Retain(0)) | (_, Op::Delete(0)) => (),
+        (_, Op::Insert(ref data)) if data.is_empty() => (),
+        (Some(&mut Op::Retain(ref mut tail)), Op::Retain(len)) => *tail += len,
+        (Some(&mut Op::Delete(ref mut tail)), Op::Delete(len)) => *tail += len,
+        (Some(&mut Op::Insert(ref mut tail)), Op::Insert(mut data)) => tail.append(&mut data),
+        (_, op) => ops.push(op)
+    }
+}
+
+/// Checks that `ops` don't walk past the end of an original file `base_len` bytes long
+pub(crate) fn check_fits(ops: &[Op], base_len: usize) -> Result<()> {
+    let mut position = 0;
+    for op in ops {
+        if let Op::Retain(len) | Op::Delete(len) = *op {
+            position += len;
+            if position > base_len {
+                return Err(Error::PositionOutOfRange(position));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Gets the number of bytes covered by the first operation in `ops`.  Since everything past the end
+/// of the operations is retained, this has no limit when there are no operations left.
+pub(crate) fn front_len(ops: &VecDeque<Op>) -> usize {
+    match ops.front() {
+        Some(&Op::Retain(len)) | Some(&Op::Delete(len)) => len,
+        Some(Op::Insert(data)) => data.len(),
+        None => usize::MAX
+    }
+}
+
+/// Removes the first `len` bytes worth of operation from `ops`, splitting the first operation if
+/// it is longer than that.  `len` must be no more than `front_len(ops)`.
+pub(crate) fn take_op(ops: &mut VecDeque<Op>, len: usize) -> Op {
+    if len == front_len(ops) {
+        return ops.pop_front().unwrap_or(Op::Retain(len));
+    }
+    match ops.front_mut() {
+        Some(&mut Op::Retain(ref mut remaining)) => {
+            *remaining -= len;
+            Op::Retain(len)
+        }
+        Some(&mut Op::Delete(ref mut remaining)) => {
+            *remaining -= len;
+            Op::Delete(len)
+        }
+        Some(&mut Op::Insert(ref mut data)) => {
+            let rest = data.split_off(len);
+            Op::Insert(::std::mem::replace(data, rest))
+        }
+        None => Op::Retain(len)
+    }
+}
+
+/// Splits the range from `start` to `end` into the parts which are in one of the sorted `deleted` ranges,
+/// and the parts which are not.  `next_delete` is the first deleted range which might overlap the range,
+/// and is moved on past any deleted range which has been passed.
+fn split_deleted<F: FnMut(usize, usize, bool)>(start: usize, end: usize, deleted: &[(usize, usize)], next_delete: &mut usize, mut emit: F) {
+    let mut position = start;
+    while position < end {
+        while *next_delete < deleted.len() && deleted[*next_delete].1 <= position {
+            *next_delete += 1;
+        }
+        let (part_end, is_deleted) = match deleted.get(*next_delete) {
+            Some(&(delete_start, delete_end)) if delete_start <= position => (min(delete_end, end), true),
+            Some(&(delete_start, _)) => (min(delete_start, end), false),
+            None => (end, false)
+        };
+        emit(position, part_end, is_deleted);
+        position = part_end;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Diff, Error, Op};
+
+    fn example_diff() -> Diff {
+        let mut diff = Diff::new();
+        diff.add_insert(4, b"big ".to_vec());
+        diff.add_insert(13, b"ish".to_vec());
+        diff.add_delete(10, 6);
+        diff.add_delete(14, 7);
+        diff
+    }
+
+    #[test]
+    fn converting_to_ops() {
+        // "The brown fox jumped" -> "The big brownish fox jumped" -> "The big br fox"
+        let diff = example_diff();
+        assert_eq!(diff.apply_to_string("The brown fox jumped").unwrap(), "The big br fox");
+        assert_eq!(diff.to_ops(), vec![
+            Op::Retain(4),
+            Op::Insert(b"big ".to_vec()),
+            Op::Retain(2),
+            Op::Delete(3),
+            Op::Retain(4),
+            Op::Delete(7),
+        ]);
+        assert_eq!(Diff::from_ops(&diff.to_ops()).apply_to_string("The brown fox jumped").unwrap(), "The big br fox");
+        assert_eq!(Diff::new().to_ops(), vec![]);
+    }
+
+    #[test]
+    fn inverting() {
+        let base = "The brown fox jumped";
+        let diff = example_diff();
+        let undo = diff.invert(base.as_bytes()).unwrap();
+        assert_eq!(undo.apply_to_string("The big br fox").unwrap(), base);
+        assert_eq!(undo.invert(b"The big br fox").unwrap().apply_to_string(base).unwrap(), "The big br fox");
+        match diff.invert(b"The brown fox") {
+            Err(Error::PositionOutOfRange(20)) => (),
+            result => panic!("expected a position out of range, got {:?}", result)
+        }
+
+        // A delete which runs past the largest position is rejected rather than overflowing
+        let mut diff = Diff::new();
+        diff.add_delete(usize::MAX, 5);
+        match diff.invert(b"abc") {
+            Err(Error::PositionOutOfRange(usize::MAX)) => (),
+            result => panic!("expected a position out of range, got {:?}", result)
+        }
+    }
+
+    #[test]
+    fn composing() {
+        let base = "The brown fox jumped";
+        let first = example_diff();
+        // "The big br fox" -> "The big bad fox!"
+        let mut second = Diff::new();
+        second.add_insert(9, b"ad".to_vec());
+        second.add_insert(16, b"!".to_vec());
+        second.add_delete(11, 1);
+        let composed = first.compose(&second).unwrap();
+        assert_eq!(second.apply_to_string("The big br fox").unwrap(), "The big bad fox!");
+        assert_eq!(composed.apply_to_string(base).unwrap(), "The big bad fox!");
+        assert_eq!(composed.to_ops(), vec![
+            Op::Retain(4),
+            Op::Insert(b"big ".to_vec()),
+            Op::Retain(1),
+            Op::Insert(b"ad".to_vec()),
+            Op::Delete(4),
+            Op::Retain(4),
+            Op::Insert(b"!".to_vec()),
+            Op::Delete(7),
+        ]);
+
+        // Composing with the undo gives back the original
+        assert_eq!(first.compose(&first.invert(base.as_bytes()).unwrap()).unwrap().apply_to_string(base).unwrap(), base);
+        assert_eq!(Diff::new().compose(&first).unwrap().to_ops(), first.to_ops());
+        assert_eq!(first.compose(&Diff::new()).unwrap().to_ops(), first.to_ops());
+
+        // Malformed diffs are rejected rather than overflowing or giving nonsense
+        let mut overflowing = Diff::new();
+        overflowing.add_insert(usize::MAX, b"!".to_vec());
+        match first.compose(&overflowing) {
+            Err(Error::PositionOutOfRange(usize::MAX)) => (),
+            result => panic!("expected a position out of range, got {:?}", result)
+        }
+        let mut unordered = Diff::new();
+        unordered.add_insert(9, b"ad".to_vec());
+        unordered.add_insert(2, b"!".to_vec());
+        match unordered.compose(&second) {
+            Err(Error::Corrupt(_)) => (),
+            result => panic!("expected a corrupt diff, got {:?}", result)
+        }
+    }
+}
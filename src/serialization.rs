@@ -0,0 +1,225 @@
+//! Serialization of diffs and hashes with serde, enabled by the `serde` feature.
+use super::{Error, BlockHashes, Diff, Insert, Delete, WeakHash, WeakHashAlgorithm, StrongHashAlgorithm};
+use crate::hashing::check_weak_hash;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de;
+
+/// The fields of a `Diff`, as they are deserialized before being checked
+#[derive(Deserialize)]
+pub(crate) struct DiffData {
+    inserts: Vec<Insert>,
+    deletes: Vec<Delete>
+}
+
+impl TryFrom<DiffData> for Diff {
+    type Error = Error;
+
+    /// Checks that the operations are in order in the same way as `Diff::expand_from()`, so that deserialized
+    /// diffs can't cause a panic later
+    fn try_from(data: DiffData) -> Result<Diff, Error> {
+        let diff = Diff {
+            inserts: data.inserts,
+            deletes: data.deletes
+        };
+        diff.check_order()?;
+        Ok(diff)
+    }
+}
+
+/// The fields of `BlockHashes` as they are serialized, with the weak hash recorded so that the hashes can only
+/// be deserialized with the same one
+#[derive(Serialize)]
+struct BlockHashesFields<'a> {
+    hashes: &'a HashMap<u32, Vec<(usize, Vec<u8>)>>,
+    block_size: usize,
+    file_size: usize,
+    weak_hash: WeakHashAlgorithm,
+    strong_hash: StrongHashAlgorithm,
+    strong_len: usize
+}
+
+/// The fields of `BlockHashes`, as they are deserialized before being checked
+#[derive(Deserialize)]
+struct BlockHashesData {
+    hashes: HashMap<u32, Vec<(usize, Vec<u8>)>>,
+    block_size: usize,
+    file_size: usize,
+    weak_hash: WeakHashAlgorithm,
+    strong_hash: StrongHashAlgorithm,
+    strong_len: usize
+}
+
+impl<H: WeakHash> Serialize for BlockHashes<H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        BlockHashesFields {
+            hashes: &self.hashes,
+            block_size: self.block_size,
+            file_size: self.file_size,
+            weak_hash: H::ALGORITHM,
+            strong_hash: self.strong_hash,
+            strong_len: self.strong_len
+        }.serialize(serializer)
+    }
+}
+
+impl<'de, H: WeakHash> Deserialize<'de> for BlockHashes<H> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BlockHashes<H>, D::Error> {
+        BlockHashes::try_from(BlockHashesData::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+impl<H: WeakHash> TryFrom<BlockHashesData> for BlockHashes<H> {
+    type Error = Error;
+
+    /// Checks that the hashes are consistent in the same way as `BlockHashes::expand_from()`, so that deserialized
+    /// hashes can't cause a panic later
+    fn try_from(data: BlockHashesData) -> Result<BlockHashes<H>, Error> {
+        check_weak_hash::<H>(data.weak_hash)?;
+        if data.block_size == 0 {
+            return Err(Error::Corrupt("invalid block size".to_string()));
+        }
+        if data.strong_len == 0 || data.strong_len > data.strong_hash.output_len() {
+            return Err(Error::Corrupt("invalid strong hash length".to_string()));
+        }
+        // Every block must be hashed exactly once.  The sizes are checked against the number of hashes first,
+        // since they could give far too many blocks to keep track of.
+        let block_count = data.file_size.div_ceil(data.block_size);
+        let hash_count: usize = data.hashes.values().map(Vec::len).sum();
+        if hash_count != block_count {
+            return Err(Error::Corrupt(format!("there are {} block hashes, but {} blocks", hash_count, block_count)));
+        }
+        let mut hashed = vec![false; block_count];
+        for &(index, ref strong_hash) in data.hashes.values().flatten() {
+            if strong_hash.len() != data.strong_len {
+                return Err(Error::Corrupt("invalid strong hash length".to_string()));
+            }
+            match hashed.get_mut(index) {
+                Some(hashed) if !*hashed => *hashed = true,
+                _ => return Err(Error::Corrupt(format!("block {} is hashed more than once, or is past the end of the file", index)))
+            }
+        }
+        Ok(BlockHashes {
+            hashes: data.hashes,
+            block_size: data.block_size,
+            file_size: data.file_size,
+            weak_hash: PhantomData,
+            strong_hash: data.strong_hash,
+            strong_len: data.strong_len
+        })
+    }
+}
+
+/// Serializes the data of an insert as bytes, except in human readable formats, where it is written
+/// as a string if it is valid UTF-8.
+pub(crate) mod insert_data {
+    use serde::{Serializer, Deserializer};
+    use serde::de::{self, Visitor, SeqAccess};
+    use std::fmt;
+    use std::str;
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match str::from_utf8(data) {
+            Ok(text) if serializer.is_human_readable() => serializer.serialize_str(text),
+            _ => serializer.serialize_bytes(data)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(DataVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(DataVisitor)
+        }
+    }
+
+    /// Accepts inserted data written as a string, as bytes, or as a sequence of bytes
+    struct DataVisitor;
+
+    impl<'de> Visitor<'de> for DataVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a string or bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, text: &str) -> Result<Vec<u8>, E> {
+            Ok(text.as_bytes().to_vec())
+        }
+
+        fn visit_string<E: de::Error>(self, text: String) -> Result<Vec<u8>, E> {
+            Ok(text.into_bytes())
+        }
+
+        fn visit_bytes<E: de::Error>(self, data: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(data.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, data: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(data)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            // Not reserved from the size hint, which could be anything
+            let mut data = Vec::new();
+            while let Some(byte) = seq.next_element()? {
+                data.push(byte);
+            }
+            Ok(data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{BlockHashes, Diff, StrongHashAlgorithm};
+    use std::io::Cursor;
+
+    fn example_diff() -> Diff {
+        let hashes = BlockHashes::new(Cursor::new("It was the best of times"), 6).unwrap();
+        let mut diff = hashes.diff_against(Cursor::new("It was not the best of things")).unwrap();
+        diff.add_insert(40, vec![0xff, 0xfe]);
+        diff
+    }
+
+    #[test]
+    fn serializing_diffs() {
+        let diff = example_diff();
+        let json = serde_json::to_string(&diff).unwrap();
+        assert_eq!(json, concat!(r#"{"inserts":[{"position":6,"data":" not"},{"position":22,"data":" things"},"#,
+                                 r#"{"position":40,"data":[255,254]}],"deletes":[{"position":29,"len":6}]}"#));
+        assert_eq!(serde_json::from_str::<Diff>(&json).unwrap(), diff);
+
+        let packed = rmp_serde::to_vec(&diff).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Diff>(&packed).unwrap(), diff);
+        assert!(serde_json::from_str::<Diff>(r#"{"inserts":[{"position":6,"data":5}],"deletes":[]}"#).is_err());
+
+        // Diffs are checked in the same way as when they are expanded
+        let unordered = r#"{"inserts":[{"position":22,"data":"b"},{"position":6,"data":"a"}],"deletes":[]}"#;
+        assert_eq!(serde_json::from_str::<Diff>(unordered).unwrap_err().to_string(), "inserts are not in file order");
+        let overflowing = format!(r#"{{"inserts":[],"deletes":[{{"position":{},"len":5}}]}}"#, usize::MAX);
+        assert_eq!(serde_json::from_str::<Diff>(&overflowing).unwrap_err().to_string(), format!("position {} is past the end of the data", usize::MAX));
+    }
+
+    #[test]
+    fn serializing_hashes() {
+        let hashes = BlockHashes::with_strong_hash(Cursor::new("It was the best of times"), 6, StrongHashAlgorithm::Blake3, 8).unwrap();
+        let json = serde_json::to_string(&hashes).unwrap();
+        assert_eq!(serde_json::from_str::<BlockHashes>(&json).unwrap(), hashes);
+        let packed = rmp_serde::to_vec(&hashes).unwrap();
+        assert_eq!(rmp_serde::from_slice::<BlockHashes>(&packed).unwrap(), hashes);
+
+        let mut value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        value["file_size"] = 30.into();
+        assert_eq!(serde_json::from_value::<BlockHashes>(value.clone()).unwrap_err().to_string(), "there are 4 block hashes, but 5 blocks");
+        // Too many blocks to keep track of
+        value["file_size"] = u64::MAX.into();
+        value["block_size"] = 1.into();
+        assert_eq!(serde_json::from_value::<BlockHashes>(value.clone()).unwrap_err().to_string(),
+                   format!("there are 4 block hashes, but {} blocks", u64::MAX));
+        value["block_size"] = 0.into();
+        assert_eq!(serde_json::from_value::<BlockHashes>(value).unwrap_err().to_string(), "invalid block size");
+    }
+}
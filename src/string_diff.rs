@@ -1,9 +1,12 @@
 //! Used for finding the minimal set of operations to transform one string into another.
 //!
-//! The primary function of this module is [find diff](fn.find_diff.html).
+//! The primary function of this module is [find diff](fn.find_diff.html).  Larger texts, such as source files,
+//! can be compared line by line with [find line diff](fn.find_line_diff.html).  When every operation has the same
+//! cost, [find myers diff](fn.find_myers_diff.html) is much faster than `find_diff` for strings which are mostly the same.
 use std::mem;
 use std::cmp::max;
-use super::{Diff};
+use super::{Diff, Op};
+use super::ops::push_op;
 
 
 /// Finds the difference on a character by character level between two strings
@@ -43,6 +46,296 @@ pub fn find_diff<S: OperationScore>(old: &str, new: &str, scorer: &S) -> Diff {
     diff
 }
 
+/// Finds the difference between two strings on a line by line level
+///
+/// Each line, including its newline, is treated as a single unit, and the longest sequence of lines common to both
+/// strings is found with the Hirschberg algorithm, which operates in `O(x * y)` time and `O(y)` space
+/// where `x` and `y` are the number of lines that differ.  Every insert and delete in the returned
+/// `Diff` covers whole lines.
+///
+/// # Example
+///
+/// ```
+/// use rdiff::string_diff::find_line_diff;
+/// let old = "fn main() {\n    println!(\"Hello\");\n}\n";
+/// let new = "fn main() {\n    println!(\"Hello, world\");\n}\n";
+/// let diff = find_line_diff(old, new);
+/// // prints (35, '    println!("Hello, world");\n')
+/// for insert in diff.inserts() {
+///     println!("{:?}", insert);
+/// }
+/// // prints (12, 23)
+/// for delete in diff.deletes() {
+///     println!("{:?}", delete);
+/// }
+/// assert_eq!(new, diff.apply_to_string(old).unwrap());
+/// ```
+pub fn find_line_diff(old: &str, new: &str) -> Diff {
+    line_diff::<EditDistance>(old, new, None)
+}
+
+/// Finds the difference between two strings line by line, and then character by character within the changed lines
+///
+/// The lines are aligned as by [find line diff](fn.find_line_diff.html), and each run of changed lines is then
+/// compared using [find diff](fn.find_diff.html) with the given `scorer`, so that only the characters which
+/// changed are inserted or deleted.  Runs of changed lines which are not entirely ASCII are left as whole lines.
+///
+/// # Example
+///
+/// ```
+/// use rdiff::string_diff::{find_refined_line_diff, EditDistance};
+/// let old = "one\ntwo\nthree\n";
+/// let new = "one\ntoo\nthree\n";
+/// let diff = find_refined_line_diff(old, new, &EditDistance{});
+/// // prints (5, 'o')
+/// for insert in diff.inserts() {
+///     println!("{:?}", insert);
+/// }
+/// // prints (6, 1)
+/// for delete in diff.deletes() {
+///     println!("{:?}", delete);
+/// }
+/// assert_eq!(new, diff.apply_to_string(old).unwrap());
+/// ```
+pub fn find_refined_line_diff<S: OperationScore>(old: &str, new: &str, scorer: &S) -> Diff {
+    line_diff(old, new, Some(scorer))
+}
+
+/// Finds the difference on a character by character level between two strings, where every insert and delete costs the same
+///
+/// Uses the Myers algorithm (doi: [10.1007/BF01840446](http://dx.doi.org/10.1007/BF01840446)) in its linear space
+/// form, which operates in `O((x + y) * d)` time and `O(x + y)` space, where `d` is the number of characters
+/// inserted and deleted.  Like `find_diff` with [EditDistance](struct.EditDistance.html) scoring, it finds the
+/// smallest number of characters which need to be inserted and deleted to transform 'old' into 'new', but it is
+/// close to linear time when the two strings are nearly the same.  The operations in the returned `Diff` follow the
+/// same conventions as those returned by `find_diff`.
+///
+/// # Example
+///
+/// ```
+/// use rdiff::string_diff::find_myers_diff;
+/// let diff = find_myers_diff("meadow", "yellowing");
+/// // prints (1, 'y'), (5, 'll') and (9, 'ing')
+/// for insert in diff.inserts() {
+///     println!("{:?}", insert);
+/// }
+/// // prints (0, 1) and (2, 2)
+/// for delete in diff.deletes() {
+///     println!("{:?}", delete);
+/// }
+/// assert_eq!("yellowing", diff.apply_to_string("meadow").unwrap());
+/// ```
+pub fn find_myers_diff(old: &str, new: &str) -> Diff {
+    let (old_chars, new_chars) = (characters(old), characters(new));
+    let mut matches = Vec::new();
+    myers(&old_chars, &new_chars, 0, 0, &mut matches);
+    matched_diff::<EditDistance>(old, new, &old_chars, &new_chars, matches, None)
+}
+
+/// Splits `s` into its characters, each as a string slice
+fn characters(s: &str) -> Vec<&str> {
+    s.char_indices().map(|(index, c)| &s[index..index + c.len_utf8()]).collect()
+}
+
+/// Aligns the lines of 'old' and 'new', and replaces the lines between those that match.  If there is
+/// a `scorer`, the replaced lines are refined character by character.
+fn line_diff<S: OperationScore>(old: &str, new: &str, scorer: Option<&S>) -> Diff {
+    let old_lines = old.split_inclusive('\n').collect::<Vec<_>>();
+    let new_lines = new.split_inclusive('\n').collect::<Vec<_>>();
+    let (old_len, new_len) = (old_lines.len(), new_lines.len());
+    // Lines at the start and end which haven't changed don't need to go through the alignment
+    let prefix = old_lines.iter().zip(new_lines.iter()).take_while(|&(old_line, new_line)| old_line == new_line).count();
+    let suffix = old_lines[prefix..].iter().rev().zip(new_lines[prefix..].iter().rev())
+        .take_while(|&(old_line, new_line)| old_line == new_line).count();
+    let mut matches = (0..prefix).map(|line| (line, line)).collect::<Vec<_>>();
+    common_lines(&old_lines[prefix..old_len - suffix], &new_lines[prefix..new_len - suffix], prefix, prefix, &mut matches);
+    matches.extend((0..suffix).map(|line| (old_len - suffix + line, new_len - suffix + line)));
+    matched_diff(old, new, &old_lines, &new_lines, matches, scorer)
+}
+
+/// Builds the diff which keeps the pieces of 'old' and 'new' given in `matches`, and replaces everything between them.
+/// `old_pieces` and `new_pieces` split 'old' and 'new' into lines or characters, and each match is a pair of indexes
+/// into them.  If there is a `scorer`, the replaced pieces are refined character by character.
+fn matched_diff<S: OperationScore>(old: &str, new: &str, old_pieces: &[&str], new_pieces: &[&str], matches: Vec<(usize, usize)>, scorer: Option<&S>) -> Diff {
+    let mut ops = Vec::new();
+    let (mut old_piece, mut new_piece) = (0, 0);
+    let (mut old_index, mut new_index) = (0, 0);
+    // The end of both strings is treated as a final match, so that the pieces after the last real match are replaced
+    for (old_match, new_match) in matches.into_iter().chain(Some((old_pieces.len(), new_pieces.len()))) {
+        let old_end = old_index + old_pieces[old_piece..old_match].iter().map(|piece| piece.len()).sum::<usize>();
+        let new_end = new_index + new_pieces[new_piece..new_match].iter().map(|piece| piece.len()).sum::<usize>();
+        replace_text(&mut ops, &old[old_index..old_end], &new[new_index..new_end], scorer);
+        let matched_len = old_pieces.get(old_match).map_or(0, |piece| piece.len());
+        push_op(&mut ops, Op::Retain(matched_len));
+        old_index = old_end + matched_len;
+        new_index = new_end + matched_len;
+        old_piece = old_match + 1;
+        new_piece = new_match + 1;
+    }
+    Diff::from_ops(&ops)
+}
+
+/// Adds the operations which turn 'old' into 'new' to `ops`
+fn replace_text<S: OperationScore>(ops: &mut Vec<Op>, old: &str, new: &str, scorer: Option<&S>) {
+    match scorer {
+        // find_diff treats each byte as a character, so only ASCII text can be refined
+        Some(scorer) if !old.is_empty() && !new.is_empty() && old.is_ascii() && new.is_ascii() => {
+            let mut retained = old.len();
+            for op in find_diff(old, new, scorer).to_ops() {
+                if let Op::Retain(len) | Op::Delete(len) = op {
+                    retained -= len;
+                }
+                push_op(ops, op);
+            }
+            // Anything after the last operation is kept
+            push_op(ops, Op::Retain(retained));
+        }
+        _ => {
+            push_op(ops, Op::Delete(old.len()));
+            push_op(ops, Op::Insert(new.as_bytes().to_vec()));
+        }
+    }
+}
+
+/// Finds the longest sequence of lines common to 'old' and 'new' using the Hirschberg algorithm, in the same way
+/// as `hirschberg` does for characters.  Each pair of matching lines is added to `matches` as the index of the
+/// line in 'old' plus `old_offset`, and the index of the line in 'new' plus `new_offset`.
+fn common_lines(old: &[&str], new: &[&str], old_offset: usize, new_offset: usize, matches: &mut Vec<(usize, usize)>) {
+    if old.is_empty() || new.is_empty() {
+        return;
+    }
+    if old.len() == 1 {
+        if let Some(position) = new.iter().position(|line| *line == old[0]) {
+            matches.push((old_offset, new_offset + position));
+        }
+        return;
+    }
+    let old_mid = old.len() / 2;
+    let lengths_l = common_lengths(old[..old_mid].iter(), new.iter());
+    let lengths_r = common_lengths(old[old_mid..].iter().rev(), new.iter().rev());
+    let new_mid = lengths_l.iter()
+                           .zip(lengths_r.iter().rev())
+                           .map(|(l, r)| l + r)
+                           .zip(0..new.len() + 1).max().unwrap().1;
+    common_lines(&old[..old_mid], &new[..new_mid], old_offset, new_offset, matches);
+    common_lines(&old[old_mid..], &new[new_mid..], old_offset + old_mid, new_offset + new_mid, matches);
+}
+
+/// Calculates the length of the longest sequence of lines common to 'old' and each prefix of 'new'.
+/// The result is indexed by the length of the prefix, like the result of `nw_score`.
+fn common_lengths<'a, 'b: 'a, I, J>(old: I, new: J) -> Vec<usize>
+    where I: Iterator<Item = &'a &'b str>, J: Iterator<Item = &'a &'b str> + Clone {
+    let row_len = new.clone().count() + 1;
+    let mut last_row = vec![0; row_len];
+    let mut this_row = vec![0; row_len];
+    for old_line in old {
+        for (new_index, new_line) in new.clone().enumerate() {
+            this_row[new_index + 1] = if old_line == new_line {
+                last_row[new_index] + 1
+            } else {
+                max(last_row[new_index + 1], this_row[new_index])
+            };
+        }
+        mem::swap(&mut last_row, &mut this_row);
+    }
+    last_row
+}
+
+/// Finds the longest sequence of pieces common to 'old' and 'new' using the linear space version of the Myers algorithm.
+/// Each pair of matching pieces is added to `matches` as the index of the piece in 'old' plus `old_offset`, and the
+/// index of the piece in 'new' plus `new_offset`.
+fn myers(old: &[&str], new: &[&str], old_offset: usize, new_offset: usize, matches: &mut Vec<(usize, usize)>) {
+    // Matching pieces at the start and end are always part of the result, and removing them ensures that
+    // the split point found below divides the problem into two smaller ones
+    let prefix = old.iter().zip(new.iter()).take_while(|&(old_piece, new_piece)| old_piece == new_piece).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+        .take_while(|&(old_piece, new_piece)| old_piece == new_piece).count();
+    matches.extend((0..prefix).map(|index| (old_offset + index, new_offset + index)));
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+    if !old_middle.is_empty() && !new_middle.is_empty() {
+        let (old_split, new_split) = middle_snake(old_middle, new_middle);
+        myers(&old_middle[..old_split], &new_middle[..new_split], old_offset + prefix, new_offset + prefix, matches);
+        myers(&old_middle[old_split..], &new_middle[new_split..], old_offset + prefix + old_split, new_offset + prefix + new_split, matches);
+    }
+    matches.extend((0..suffix).map(|index| (old_offset + old.len() - suffix + index, new_offset + new.len() - suffix + index)));
+}
+
+/// Finds a point which the shortest edit script from 'old' to 'new' passes through, about half way along it, by searching
+/// forwards from the start and backwards from the end until the two searches meet.  Gives the number of pieces of 'old'
+/// and 'new' before the point.
+///
+/// The `forward` vector holds, for each diagonal `k`, the furthest index into 'old' reached on that diagonal
+/// by a path with `d` edits, where the index into 'new' is that index minus `k`.  The `backward` vector holds the same
+/// for paths from the end, counting from the ends of the strings.
+fn middle_snake(old: &[&str], new: &[&str]) -> (usize, usize) {
+    let (old_len, new_len) = (old.len() as isize, new.len() as isize);
+    let max_d = (old_len + new_len + 1) / 2;
+    let offset = max_d + 1;
+    let mut forward = vec![-1; 2 * offset as usize + 1];
+    let mut backward = forward.clone();
+    forward[offset as usize + 1] = 0;
+    backward[offset as usize + 1] = 0;
+    let delta = old_len - new_len;
+    // If the difference in lengths is odd, the paths meet while searching forwards, and otherwise while searching backwards
+    let front = delta % 2 != 0;
+    let in_range = |k: isize| k >= -offset && k <= offset;
+    // Diagonals at the edges whose paths have run off the end of 'old' or 'new' aren't searched any further
+    let (mut forward_start, mut forward_end, mut backward_start, mut backward_end) = (0, 0, 0, 0);
+    for d in 0..=max_d {
+        for k in (-d + forward_start..=d - forward_end).step_by(2) {
+            let index = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && forward[index - 1] < forward[index + 1]) {
+                forward[index + 1]
+            } else {
+                forward[index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < old_len && y < new_len && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[index] = x;
+            if x > old_len {
+                forward_end += 2;
+            } else if y > new_len {
+                forward_start += 2;
+            } else if front && in_range(delta - k) {
+                let backward_x = backward[(offset + delta - k) as usize];
+                if backward_x != -1 && x + backward_x >= old_len {
+                    return (x as usize, y as usize);
+                }
+            }
+        }
+        for k in (-d + backward_start..=d - backward_end).step_by(2) {
+            let index = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && backward[index - 1] < backward[index + 1]) {
+                backward[index + 1]
+            } else {
+                backward[index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < old_len && y < new_len && old[(old_len - x - 1) as usize] == new[(new_len - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[index] = x;
+            if x > old_len {
+                backward_end += 2;
+            } else if y > new_len {
+                backward_start += 2;
+            } else if !front && in_range(delta - k) {
+                let forward_x = forward[(offset + delta - k) as usize];
+                if forward_x != -1 && forward_x + x >= old_len {
+                    return (forward_x as usize, (forward_x - delta + k) as usize);
+                }
+            }
+        }
+    }
+    // The two searches always meet by the time `d` reaches `max_d`
+    unreachable!("the forward and backward searches of the Myers algorithm didn't meet")
+}
+
 /// Handles updating the diff and relevant indexes when inserting a string
 /// Needed because the string must be converted to bytes before it can be used in the diff
 macro_rules! do_insert {
@@ -73,6 +366,7 @@ macro_rules! do_delete {
 /// cached so that 'old' and 'new' don't need to be reversed for every recursion of the algorithm.
 /// `diff` is the output of the algorithm and `insert_index` and `delete_index` are simply intermediate state
 /// being passed around.
+#[allow(clippy::too_many_arguments)]
 fn hirschberg<S: OperationScore>(old: &str, new: &str, old_rev: &str, new_rev: &str, scorer: &S, diff: &mut Diff, insert_index: &mut usize, delete_index: &mut usize) {
     trace!("'{}' ({}) '{}' ({})", old, old_rev, new, new_rev);
     // We're going to use these lengths over and over again, we might as well cache them.
@@ -244,7 +538,7 @@ fn nw_score<S: OperationScore>(old: &str, new: &str, scorer: &S) -> Vec<i32> {
 #[cfg(test)]
 mod test {
     extern crate env_logger;
-    use super::{nw_score, find_diff, EditDistance, OperationScore};
+    use super::{nw_score, find_diff, find_line_diff, find_refined_line_diff, find_myers_diff, EditDistance, OperationScore};
     use super::super::{Insert, Delete, Diff};
 
     struct ExampleScores;
@@ -343,4 +637,82 @@ mod test {
             (23, 3), (25, 1), (29, 1),(55, 1), (56, 1), (62, 2), (69, 2), (72, 3), (79, 1)
         );
     }
+
+    #[test]
+    fn line_diffs() {
+        let old = "a\nb\nc\nd\n";
+        assert!(find_line_diff(old, old).is_empty());
+        let diff = find_line_diff(old, "a\nB\nc\nd\ne\n");
+        assert_eq!(diff, Diff {
+            inserts: vec![Insert{position: 4, data: "B\n".bytes().collect()}, Insert{position: 10, data: "e\n".bytes().collect()}],
+            deletes: vec![Delete{position: 2, len: 2}]
+        });
+        for &(old, new) in &[(old, "x\na\nc\ny\nd\n"), (old, ""), ("", old), (old, "a\nb\nc\nd"), ("a\na\nb\na\n", "b\na\na\nb\n"),
+                             ("lines\nwith ünïcödé\n", "lines\nwith unicode\n"), ("a\r\nb\r\n", "a\r\nc\r\n")] {
+            let diff = find_line_diff(old, new);
+            assert_eq!(diff.apply_to_string(old).unwrap(), new);
+            let refined = find_refined_line_diff(old, new, &EditDistance{});
+            assert_eq!(refined.apply_to_string(old).unwrap(), new);
+        }
+        // Only one line changes, even though the old line appears elsewhere
+        let diff = find_line_diff("x\ny\nz\nx\n", "x\ny\nw\nx\n");
+        assert_eq!(diff.deletes().collect::<Vec<_>>(), vec![&Delete{position: 4, len: 2}]);
+    }
+
+    #[test]
+    fn refined_line_diffs() {
+        let old = "Since my baby left me\nI've got a new place to dwell\nDown at the end of 'Lonely Street'\n";
+        let new = "Since my baby left me\nI found a new place to dwell\nDown at the end of 'Lonely Street'\n";
+        let diff = find_refined_line_diff(old, new, &EditDistance{});
+        assert_eq!(diff, Diff {
+            inserts: vec![Insert{position: 27, data: "f".bytes().collect()}, Insert{position: 30, data: "und".bytes().collect()}],
+            deletes: vec![Delete{position: 23, len: 3}, Delete{position: 25, len: 1}, Delete{position: 29, len: 1}]
+        });
+        assert_eq!(diff.apply_to_string(old).unwrap(), new);
+        // Lines which aren't ASCII are replaced whole
+        let diff = find_refined_line_diff("a\ncafé\n", "a\ncafe\n", &EditDistance{});
+        assert_eq!(diff, Diff {
+            inserts: vec![Insert{position: 8, data: "cafe\n".bytes().collect()}],
+            deletes: vec![Delete{position: 2, len: 6}]
+        });
+    }
+
+    /// The number of characters inserted and deleted by a diff
+    fn edit_cost(diff: &Diff) -> usize {
+        diff.inserts().map(|insert| insert.get_data().len()).sum::<usize>() + diff.deletes().map(|delete| delete.get_length()).sum::<usize>()
+    }
+
+    #[test]
+    fn myers_diffs() {
+        for &(old, new) in &[("kitten", "kettle"), ("meadow", "yellowing"), (" I've", " I"), ("", "abc"), ("abc", ""), ("abc", "xyz"),
+                             ("abcabba", "cbabac"), (" I've got a new place", " I found a new place"),
+                             ("Since my baby left me I've got a new place to dwell\nI walk down a lonely street to Heartbreak Hotel.",
+                              "Since my baby left me I found a new place to dwell\nDown at the end of 'Lonely Street' to 'Heartbreak Hotel.'")] {
+            let diff = find_myers_diff(old, new);
+            assert_eq!(diff.apply_to_string(old).unwrap(), new);
+            // The same number of characters are changed as by the Hirschberg algorithm with unit costs
+            assert_eq!(edit_cost(&diff), edit_cost(&find_diff(old, new, &EditDistance{})));
+        }
+        let diff = find_myers_diff("kitten", "kettle");
+        assert_eq!(diff, Diff {
+            inserts: vec![Insert{position: 2, data: "e".bytes().collect()}, Insert{position: 5, data: "l".bytes().collect()}],
+            deletes: vec![Delete{position: 1, len: 1}, Delete{position: 6, len: 1}]
+        });
+        // Characters are never split
+        let diff = find_myers_diff("naïve café", "naive cafés");
+        assert_eq!(diff.apply_to_string("naïve café").unwrap(), "naive cafés");
+        assert_eq!(edit_cost(&diff), 4);
+    }
+
+    #[test]
+    fn large_myers_diffs() {
+        let old = (0..200_000).map(|i| (b'a' + (i * 7 % 26) as u8) as char).collect::<String>();
+        let mut new = old.clone();
+        new.insert_str(100_000, "inserted");
+        new.replace_range(10..20, "replaced");
+        let diff = find_myers_diff(&old, &new);
+        assert_eq!(diff.apply_to_string(&old).unwrap(), new);
+        // Some of the replacement characters may already be in place
+        assert!(edit_cost(&diff) <= 26);
+    }
 }
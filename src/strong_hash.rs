@@ -0,0 +1,150 @@
+use super::{StrongHash, StrongHashAlgorithm, Md5Hash, Blake2Hash, Blake3Hash, Sha256Hash};
+use crypto::md5::Md5;
+use crypto::blake2b::Blake2b;
+use crypto::sha2::Sha256;
+use crypto::digest::Digest;
+
+impl StrongHashAlgorithm {
+    /// Creates a new hash which calculates this algorithm.  The hash can be sent between threads.
+    pub(crate) fn hasher(self) -> Box<dyn StrongHash + Send> {
+        match self {
+            StrongHashAlgorithm::Md5 => Box::new(Md5Hash::new()),
+            StrongHashAlgorithm::Blake2 => Box::new(Blake2Hash::new()),
+            StrongHashAlgorithm::Blake3 => Box::new(Blake3Hash::new()),
+            StrongHashAlgorithm::Sha256 => Box::new(Sha256Hash::new())
+        }
+    }
+
+    /// Gets the length in bytes of this algorithm's digest, which is the longest the strong hashes can be
+    pub fn output_len(self) -> usize {
+        match self {
+            StrongHashAlgorithm::Md5 => 16,
+            StrongHashAlgorithm::Blake2 | StrongHashAlgorithm::Blake3 | StrongHashAlgorithm::Sha256 => 32
+        }
+    }
+}
+
+impl Md5Hash {
+    /// Creates a new, empty hash
+    fn new() -> Md5Hash {
+        Md5Hash {
+            digest: Md5::new()
+        }
+    }
+}
+
+impl StrongHash for Md5Hash {
+    fn update(&mut self, data: &[u8]) {
+        self.digest.input(data);
+    }
+
+    fn finish_reset(&mut self, out: &mut [u8]) {
+        self.digest.result(out);
+        self.digest.reset();
+    }
+
+    fn output_len(&self) -> usize {
+        StrongHashAlgorithm::Md5.output_len()
+    }
+}
+
+impl Blake2Hash {
+    /// Creates a new, empty hash
+    fn new() -> Blake2Hash {
+        Blake2Hash {
+            digest: Blake2b::new(32)
+        }
+    }
+}
+
+impl StrongHash for Blake2Hash {
+    fn update(&mut self, data: &[u8]) {
+        self.digest.input(data);
+    }
+
+    fn finish_reset(&mut self, out: &mut [u8]) {
+        self.digest.result(out);
+        self.digest.reset();
+    }
+
+    fn output_len(&self) -> usize {
+        StrongHashAlgorithm::Blake2.output_len()
+    }
+}
+
+impl Blake3Hash {
+    /// Creates a new, empty hash
+    fn new() -> Blake3Hash {
+        Blake3Hash {
+            hasher: ::blake3::Hasher::new()
+        }
+    }
+}
+
+impl StrongHash for Blake3Hash {
+    fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn finish_reset(&mut self, out: &mut [u8]) {
+        out.copy_from_slice(self.hasher.finalize().as_bytes());
+        self.hasher.reset();
+    }
+
+    fn output_len(&self) -> usize {
+        StrongHashAlgorithm::Blake3.output_len()
+    }
+}
+
+impl Sha256Hash {
+    /// Creates a new, empty hash
+    fn new() -> Sha256Hash {
+        Sha256Hash {
+            digest: Sha256::new()
+        }
+    }
+}
+
+impl StrongHash for Sha256Hash {
+    fn update(&mut self, data: &[u8]) {
+        self.digest.input(data);
+    }
+
+    fn finish_reset(&mut self, out: &mut [u8]) {
+        self.digest.result(out);
+        self.digest.reset();
+    }
+
+    fn output_len(&self) -> usize {
+        StrongHashAlgorithm::Sha256.output_len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::StrongHashAlgorithm;
+
+    fn digest(algorithm: StrongHashAlgorithm, data: &[u8]) -> Vec<u8> {
+        let mut hasher = algorithm.hasher();
+        let mut out = vec![0; hasher.output_len()];
+        // Hashing something first checks that the hash is reset afterwards
+        hasher.update(b"something else");
+        hasher.finish_reset(&mut out);
+        hasher.update(&data[..1]);
+        hasher.update(&data[1..]);
+        hasher.finish_reset(&mut out);
+        out
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn strong_hashes() {
+        assert_eq!(hex(&digest(StrongHashAlgorithm::Md5, b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(hex(&digest(StrongHashAlgorithm::Sha256, b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(&digest(StrongHashAlgorithm::Blake3, b"abc")), "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+        assert_eq!(hex(&digest(StrongHashAlgorithm::Blake2, b"abc")), "bddd813c634239723171ef3fee98579b94964e3bb1cb3e427262c8c068d52319");
+    }
+}
@@ -0,0 +1,431 @@
+//! Conversion between diffs and the unified format written by GNU `diff -u`.
+use super::{Error, Result, Diff, Op};
+use super::ops::push_op;
+use std::cmp::min;
+
+/// Written after a line which has no newline at the end of the file
+const NO_NEWLINE: &str = "\\ No newline at end of file";
+
+/// A run of changed lines.  The old lines from `old_start` to `old_end` are replaced by the new lines
+/// from `new_start` to `new_end`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct LineChange {
+    old_start: usize,
+    old_end: usize,
+    new_start: usize,
+    new_end: usize
+}
+
+/// The lines of a piece of text.  Every line but the last ends with its newline.
+struct Lines<'a> {
+    text: &'a str,
+    starts: Vec<usize>
+}
+
+impl<'a> Lines<'a> {
+    fn new(text: &'a str) -> Lines<'a> {
+        let mut starts = Vec::new();
+        if !text.is_empty() {
+            starts.push(0);
+            starts.extend(text.match_indices('\n').map(|(index, _)| index + 1).filter(|&start| start < text.len()));
+        }
+        Lines {
+            text,
+            starts
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.starts.len()
+    }
+
+    /// Gets the position just past the end of `line`, including its newline
+    fn end(&self, line: usize) -> usize {
+        self.starts.get(line + 1).cloned().unwrap_or(self.text.len())
+    }
+
+    /// Gets the position just past the end of the first `lines` lines
+    fn end_of_lines(&self, lines: usize) -> usize {
+        if lines == 0 { 0 } else { self.end(lines - 1) }
+    }
+
+    fn get(&self, line: usize) -> &'a str {
+        &self.text[self.starts[line]..self.end(line)]
+    }
+
+    /// Finds the line which starts at `position`, if there is one
+    fn starting_at(&self, position: usize) -> Option<usize> {
+        self.starts.binary_search(&position).ok()
+    }
+}
+
+impl Diff {
+    /// Renders this diff as a GNU unified diff, given `base`, the text this diff was made against.
+    /// `old_name` and `new_name` are written in the file headers, and each hunk has up to `context` unchanged lines
+    /// around the changes.  An empty string is given if the diff doesn't change anything.
+    ///
+    /// A line is shown as changed if any part of it was changed by the diff, unless the line it was changed into
+    /// is exactly the same.
+    ///
+    /// Gives an error if the diff refers to positions past the end of `base`, or if the new text isn't valid UTF-8.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::BlockHashes;
+    /// use std::io::Cursor;
+    /// let old = "one\ntwo\nthree\nfour\n";
+    /// let hashes = BlockHashes::new(Cursor::new(old), 4).unwrap();
+    /// let diff = hashes.diff_against(Cursor::new("one\n2\nthree\nfour\n")).unwrap();
+    /// assert_eq!(diff.to_unified(old, "a/numbers", "b/numbers", 1).unwrap(),
+    ///            "--- a/numbers\n+++ b/numbers\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n");
+    /// ```
+    pub fn to_unified(&self, base: &str, old_name: &str, new_name: &str, context: usize) -> Result<String> {
+        let new = self.apply_to_string(base)?;
+        let (old_lines, new_lines) = (Lines::new(base), Lines::new(&new));
+        let changes = self.line_changes(&old_lines, &new_lines);
+        let mut output = String::new();
+        if changes.is_empty() {
+            return Ok(output);
+        }
+        output.push_str(&format!("--- {}\n+++ {}\n", old_name, new_name));
+        let mut remaining = &changes[..];
+        while !remaining.is_empty() {
+            // Changes close enough for their context to touch go in the same hunk
+            let mut hunk_len = 1;
+            while hunk_len < remaining.len() && remaining[hunk_len].old_start - remaining[hunk_len - 1].old_end <= 2 * context {
+                hunk_len += 1;
+            }
+            let (hunk, rest) = remaining.split_at(hunk_len);
+            write_hunk(&mut output, hunk, &old_lines, &new_lines, context);
+            remaining = rest;
+        }
+        Ok(output)
+    }
+
+    /// Reads a unified diff, such as one written by GNU `diff -u` or `git diff`, and gives a diff which makes the same
+    /// changes to `base`, the text the patch was made against.  Any headers before the first hunk are skipped.
+    ///
+    /// Every context line and removed line is checked against `base`, and `Error::ContextMismatch` is given
+    /// if one doesn't match.  Hunks must match at exactly the lines their headers give, so a patch made against a
+    /// different version of the text is never applied.  Gives `Error::Corrupt` if the patch is malformed, and
+    /// `Error::Unsupported` if it changes more than one file.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rdiff::Diff;
+    /// let old = "one\ntwo\nthree\nfour\n";
+    /// let patch = "--- a/numbers\n+++ b/numbers\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n";
+    /// let diff = Diff::from_unified(patch, old).unwrap();
+    /// assert_eq!(diff.apply_to_string(old).unwrap(), "one\n2\nthree\nfour\n");
+    /// ```
+    pub fn from_unified(patch: &str, base: &str) -> Result<Diff> {
+        let base_lines = Lines::new(base);
+        let mut ops = Vec::new();
+        // The next line of the base which hasn't been covered by an operation yet
+        let mut base_line = 0;
+        let mut seen_hunk = false;
+        let mut patch_lines = patch.split_inclusive('\n').peekable();
+        while let Some(line) = patch_lines.next() {
+            if !line.starts_with("@@") {
+                if seen_hunk && (line.starts_with("--- ") || line.starts_with("diff ")) {
+                    return Err(Error::Unsupported("patches which change more than one file are not supported".to_string()));
+                }
+                continue;
+            }
+            seen_hunk = true;
+            let (old_start, mut old_remaining, mut new_remaining) = parse_hunk_header(line)?;
+            // An empty range is numbered by the line before it
+            let start = if old_remaining == 0 { old_start } else { old_start - 1 };
+            if start < base_line {
+                return Err(Error::Corrupt("the hunks of the patch overlap or are out of order".to_string()));
+            }
+            if start > base_lines.len() {
+                return Err(Error::ContextMismatch(start));
+            }
+            push_op(&mut ops, Op::Retain(base_lines.end_of_lines(start) - base_lines.end_of_lines(base_line)));
+            base_line = start;
+
+            while old_remaining > 0 || new_remaining > 0 {
+                let line = patch_lines.next().ok_or_else(|| Error::Corrupt("a hunk of the patch is cut short".to_string()))?;
+                // Some tools strip the space from empty context lines
+                let (kind, mut text) = match line.chars().next() {
+                    Some('\n') => (' ', line),
+                    Some(kind) => (kind, &line[kind.len_utf8()..]),
+                    None => unreachable!()
+                };
+                if patch_lines.peek().is_some_and(|next| next.starts_with('\\')) {
+                    patch_lines.next();
+                    text = text.strip_suffix('\n').unwrap_or(text);
+                }
+                match kind {
+                    ' ' | '-' if old_remaining > 0 && (kind == '-' || new_remaining > 0) => {
+                        if base_line >= base_lines.len() || base_lines.get(base_line) != text {
+                            return Err(Error::ContextMismatch(base_line + 1));
+                        }
+                        if kind == ' ' {
+                            push_op(&mut ops, Op::Retain(text.len()));
+                            new_remaining -= 1;
+                        } else {
+                            push_op(&mut ops, Op::Delete(text.len()));
+                        }
+                        base_line += 1;
+                        old_remaining -= 1;
+                    }
+                    '+' if new_remaining > 0 => {
+                        push_op(&mut ops, Op::Insert(text.as_bytes().to_vec()));
+                        new_remaining -= 1;
+                    }
+                    _ => return Err(Error::Corrupt(format!("unexpected line in a hunk of the patch: {}", line.trim_end())))
+                }
+            }
+        }
+        Ok(Diff::from_ops(&ops))
+    }
+
+    /// Finds the runs of lines changed by this diff.  A line is unchanged if it is copied whole
+    /// from the old text to the new text, and every other line is changed.
+    fn line_changes(&self, old_lines: &Lines, new_lines: &Lines) -> Vec<LineChange> {
+        // Pairs up the old and new lines which are unchanged
+        let mut unchanged = Vec::new();
+        let (mut old_position, mut new_position) = (0, 0);
+        let ops = self.to_ops();
+        // Everything after the last operation is retained
+        let covered: usize = ops.iter().map(|op| match *op {
+            Op::Retain(len) | Op::Delete(len) => len,
+            Op::Insert(_) => 0
+        }).sum();
+        let rest = Op::Retain(old_lines.text.len() - covered);
+        let mut old_line = 0;
+        for op in ops.into_iter().chain(Some(rest)) {
+            match op {
+                Op::Retain(len) => {
+                    while old_line < old_lines.len() && old_lines.starts[old_line] < old_position {
+                        old_line += 1;
+                    }
+                    while old_line < old_lines.len() && old_lines.end(old_line) <= old_position + len {
+                        let start = old_lines.starts[old_line];
+                        let new_start = start - old_position + new_position;
+                        if let Some(new_line) = new_lines.starting_at(new_start) {
+                            if new_lines.end(new_line) - new_start == old_lines.end(old_line) - start {
+                                unchanged.push((old_line, new_line));
+                            }
+                        }
+                        old_line += 1;
+                    }
+                    old_position += len;
+                    new_position += len;
+                }
+                Op::Insert(data) => new_position += data.len(),
+                Op::Delete(len) => old_position += len
+            }
+        }
+
+        let mut changes = Vec::new();
+        let (mut old_next, mut new_next) = (0, 0);
+        unchanged.push((old_lines.len(), new_lines.len()));
+        for (old_line, new_line) in unchanged {
+            let mut change = LineChange {
+                old_start: old_next,
+                old_end: old_line,
+                new_start: new_next,
+                new_end: new_line
+            };
+            // Diffs found from hashes replace whole blocks, which can rewrite lines without changing them
+            while change.old_start < change.old_end && change.new_start < change.new_end && old_lines.get(change.old_start) == new_lines.get(change.new_start) {
+                change.old_start += 1;
+                change.new_start += 1;
+            }
+            while change.old_start < change.old_end && change.new_start < change.new_end && old_lines.get(change.old_end - 1) == new_lines.get(change.new_end - 1) {
+                change.old_end -= 1;
+                change.new_end -= 1;
+            }
+            if change.old_start < change.old_end || change.new_start < change.new_end {
+                changes.push(change);
+            }
+            old_next = old_line + 1;
+            new_next = new_line + 1;
+        }
+        changes
+    }
+}
+
+/// Writes a hunk holding `changes`, with `context` lines of context around them
+fn write_hunk(output: &mut String, changes: &[LineChange], old_lines: &Lines, new_lines: &Lines, context: usize) {
+    let (first, last) = (changes[0], changes[changes.len() - 1]);
+    // Unchanged lines before and after the changes are the same in both versions
+    let before = min(context, first.old_start);
+    let after = min(context, old_lines.len() - last.old_end);
+    let (old_start, new_start) = (first.old_start - before, first.new_start - before);
+    let (old_end, new_end) = (last.old_end + after, last.new_end + after);
+    output.push_str(&format!("@@ -{} +{} @@\n", hunk_range(old_start, old_end), hunk_range(new_start, new_end)));
+
+    let mut old_line = old_start;
+    for change in changes {
+        for line in old_line..change.old_start {
+            write_line(output, ' ', old_lines.get(line));
+        }
+        for line in change.old_start..change.old_end {
+            write_line(output, '-', old_lines.get(line));
+        }
+        for line in change.new_start..change.new_end {
+            write_line(output, '+', new_lines.get(line));
+        }
+        old_line = change.old_end;
+    }
+    for line in old_line..old_end {
+        write_line(output, ' ', old_lines.get(line));
+    }
+}
+
+/// Formats the lines from `start` to `end` as the line number of the first line, followed by the number of lines
+/// if there isn't exactly one.  An empty range is numbered by the line before it.
+fn hunk_range(start: usize, end: usize) -> String {
+    match end - start {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        len => format!("{},{}", start + 1, len)
+    }
+}
+
+/// Reads a hunk header such as `@@ -3,2 +3,4 @@`, giving the first old line and the number of old and new lines
+fn parse_hunk_header(line: &str) -> Result<(usize, usize, usize)> {
+    let invalid = || Error::Corrupt(format!("invalid hunk header: {}", line.trim_end()));
+    let mut fields = line.split(' ');
+    if fields.next() != Some("@@") {
+        return Err(invalid());
+    }
+    let (old, new) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(old), Some(new), Some("@@")) | (Some(old), Some(new), Some("@@\n")) => (old, new),
+        _ => return Err(invalid())
+    };
+    let parse_range = |range: &str, prefix: char| -> Option<(usize, usize)> {
+        let range = range.strip_prefix(prefix)?;
+        match range.split_once(',') {
+            Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+            None => Some((range.parse().ok()?, 1))
+        }
+    };
+    let (old_start, old_len) = parse_range(old, '-').ok_or_else(invalid)?;
+    let (_, new_len) = parse_range(new, '+').ok_or_else(invalid)?;
+    if old_start == 0 && old_len > 0 {
+        return Err(invalid());
+    }
+    Ok((old_start, old_len, new_len))
+}
+
+fn write_line(output: &mut String, prefix: char, line: &str) {
+    output.push(prefix);
+    output.push_str(line);
+    if !line.ends_with('\n') {
+        output.push('\n');
+        output.push_str(NO_NEWLINE);
+        output.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{BlockHashes, Diff, Error};
+    use std::io::Cursor;
+
+    fn unified(old: &str, new: &str, context: usize) -> String {
+        let diff = BlockHashes::new(Cursor::new(old), 4).unwrap().diff_against(Cursor::new(new)).unwrap();
+        assert_eq!(diff.apply_to_string(old).unwrap(), new);
+        diff.to_unified(old, "old", "new", context).unwrap()
+    }
+
+    #[test]
+    fn unified_diffs() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        assert_eq!(unified(old, old, 3), "");
+        assert_eq!(unified(old, "a\nb\nC\nd\ne\nf\ng\nh\ni\nj\n", 1), "--- old\n+++ new\n@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n");
+        assert_eq!(unified(old, "a\nb\nC\nd\ne\nf\ng\nh\ni\nj\n", 0), "--- old\n+++ new\n@@ -3 +3 @@\n-c\n+C\n");
+        // Changes with overlapping context share a hunk, and the others get their own
+        assert_eq!(unified(old, "a\nb\nc\nx\nd\ne\nf\ng\ni\nj\n", 1),
+                   "--- old\n+++ new\n@@ -3,2 +3,3 @@\n c\n+x\n d\n@@ -7,3 +8,2 @@\n g\n-h\n i\n");
+        assert_eq!(unified(old, "a\nb\nc\nx\nd\ne\nf\ng\ni\nj\n", 2),
+                   "--- old\n+++ new\n@@ -2,9 +2,9 @@\n b\n c\n+x\n d\n e\n f\n g\n-h\n i\n j\n");
+        // Files which start or end empty
+        assert_eq!(unified("", "a\n", 3), "--- old\n+++ new\n@@ -0,0 +1 @@\n+a\n");
+        assert_eq!(unified("a\nb\n", "", 3), "--- old\n+++ new\n@@ -1,2 +0,0 @@\n-a\n-b\n");
+    }
+
+    #[test]
+    fn missing_newlines() {
+        assert_eq!(unified("a\nb\nc", "a\nb\nc\n", 1), "--- old\n+++ new\n@@ -2,2 +2,2 @@\n b\n-c\n\\ No newline at end of file\n+c\n");
+        assert_eq!(unified("a\nb\nc\n", "a\nb\nc\nd", 1), "--- old\n+++ new\n@@ -3 +3,2 @@\n c\n+d\n\\ No newline at end of file\n");
+        assert_eq!(unified("a\nb\nc", "a\nB\nc", 1),
+                   "--- old\n+++ new\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n\\ No newline at end of file\n");
+    }
+
+    #[test]
+    fn invalid_unified_diffs() {
+        let mut diff = Diff::new();
+        diff.add_delete(10, 2);
+        match diff.to_unified("short", "old", "new", 3) {
+            Err(Error::PositionOutOfRange(_)) => (),
+            result => panic!("expected a position out of range, got {:?}", result)
+        }
+        let mut diff = Diff::new();
+        diff.add_insert(1, vec![0xff]);
+        match diff.to_unified("short", "old", "new", 3) {
+            Err(Error::InvalidUtf8(_)) => (),
+            result => panic!("expected invalid UTF-8, got {:?}", result)
+        }
+    }
+
+    fn patched(patch: &str, base: &str) -> String {
+        Diff::from_unified(patch, base).unwrap().apply_to_string(base).unwrap()
+    }
+
+    #[test]
+    fn parsing_unified_diffs() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        for &new in &["a\nb\nC\nd\ne\nf\ng\nh\ni\nj\n", "a\nb\nc\nx\nd\ne\nf\ng\ni\nj\n", "", "x\n", old, "a\nb\nc\nd\ne\nf\ng\nh\ni\nj"] {
+            for context in 0..4 {
+                assert_eq!(patched(&unified(old, new, context), old), new);
+            }
+        }
+        assert_eq!(patched(&unified("", "a\n", 3), ""), "a\n");
+        assert_eq!(patched(&unified("a\nb\nc", "a\nB\nc", 1), "a\nb\nc"), "a\nB\nc");
+        assert_eq!(patched(&unified("a\nb\nc", "a\nb\nc\n", 1), "a\nb\nc"), "a\nb\nc\n");
+
+        // Headers written by git, section names after the hunk header, and empty context lines without their space
+        let patch = "diff --git a/f b/f\nindex 1234567..89abcde 100644\n--- a/f\n+++ b/f\n@@ -2,4 +2,3 @@ fn main() {\n b\n\n-c\n d\n";
+        assert_eq!(patched(patch, "a\nb\n\nc\nd\ne\n"), "a\nb\n\nd\ne\n");
+        // Insertions after a line, and at the start
+        assert_eq!(patched("@@ -1,0 +2 @@\n+x\n@@ -3,0 +5 @@\n+y\n", "a\nb\nc\n"), "a\nx\nb\nc\ny\n");
+        assert_eq!(patched("@@ -0,0 +1 @@\n+x\n", "a\n"), "x\na\n");
+        assert!(Diff::from_unified("", "a\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_patches() {
+        let base = "a\nb\nc\n";
+        match Diff::from_unified("@@ -1,2 +1,2 @@\n a\n-x\n+y\n", base) {
+            Err(Error::ContextMismatch(2)) => (),
+            result => panic!("expected a mismatch at line 2, got {:?}", result)
+        }
+        match Diff::from_unified("@@ -3,2 +3,2 @@\n c\n-d\n+y\n", base) {
+            Err(Error::ContextMismatch(4)) => (),
+            result => panic!("expected a mismatch at line 4, got {:?}", result)
+        }
+        match Diff::from_unified("@@ -3 +3 @@\n-c\n\\ No newline at end of file\n", base) {
+            Err(Error::ContextMismatch(3)) => (),
+            result => panic!("expected a mismatch at line 3, got {:?}", result)
+        }
+        for patch in &["@@ -1,2 +1,2 @@\n a\n-b\n", "@@ -1,2 +1 @@\n a\n b\n", "@@ -1 +1 @@\n*a\n", "@@ -x +1 @@\n", "@@ -0 +1 @@\n",
+                       "@@ -2 +2 @@\n b\n@@ -1 +1 @@\n a\n", "@@ -1 +1\n a\n"] {
+            match Diff::from_unified(patch, base) {
+                Err(Error::Corrupt(_)) => (),
+                result => panic!("expected {:?} to be corrupt, got {:?}", patch, result)
+            }
+        }
+        match Diff::from_unified("--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n+x\n--- a/g\n+++ b/g\n@@ -1 +1 @@\n-a\n+y\n", base) {
+            Err(Error::Unsupported(_)) => (),
+            result => panic!("expected an unsupported patch, got {:?}", result)
+        }
+    }
+}
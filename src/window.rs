@@ -1,18 +1,18 @@
 use std::io::{Read, Result};
 use std::mem;
 use std::cmp::min;
-use ::Window;
+use crate::Window;
 
 
 impl<R:Read> Window<R> {
     pub fn new(mut reader:R, block_size: usize) -> Result<Window<R>> {
         let mut front = vec!(0;block_size);
         let mut back = vec!(0;block_size);
-        let size = try!(reader.read(front.as_mut_slice()));
+        let size = reader.read(front.as_mut_slice())?;
         unsafe {
             front.set_len(size);
         }
-        let size = try!(reader.read(back.as_mut_slice()));
+        let size = reader.read(back.as_mut_slice())?;
         unsafe {
             back.set_len(size);
         }
@@ -27,15 +27,15 @@ impl<R:Read> Window<R> {
     }
 
     pub fn advance(&mut self) -> Result<(Option<u8>, Option<u8>)> {
-        if self.front.len() == 0 {
+        if self.front.is_empty() {
             return Ok((None, None));
         }
 
         if self.offset >= self.front.len() {
-            if self.back.len() == 0 {
+            if self.back.is_empty() {
                 return Ok((None, None));
             }
-            try!(self.load_next_block());
+            self.load_next_block()?;
         }
         let tail = self.front[self.offset];
         let head = self.get_head();
@@ -49,13 +49,13 @@ impl<R:Read> Window<R> {
         if head_index >= self.back.len() {
             return None;
         }
-        return Some(self.back[head_index]);
+        Some(self.back[head_index])
     }
 
     fn load_next_block(&mut self) -> Result<()> {
         // We've gone past the end of the front half
         self.front = mem::replace(&mut self.back, vec!(0;self.block_size));
-        let size = try!(self.reader.read(self.back.as_mut_slice()));
+        let size = self.reader.read(self.back.as_mut_slice())?;
         unsafe{
             self.back.set_len(size);
         }
@@ -63,7 +63,7 @@ impl<R:Read> Window<R> {
         Ok(())
     }
 
-    pub fn frame<'a>(&'a self) -> (&'a [u8], &'a [u8]) {
+    pub fn frame(&self) -> (&[u8], &[u8]) {
         let front_offset = min(self.offset, self.front.len());
         let back_offset = min(self.offset, self.back.len());
         (&self.front[front_offset..], &self.back[..back_offset])